@@ -1,144 +1,3175 @@
-// Helper functions using KCL CLI
+//! Helper functions using KCL CLI.
+//!
+//! Besides backing the plugin commands, this module is the library API of the crate:
+//! `run_kcl`, `format_kcl` and `validate_kcl` take builder-style options
+//! (`RunOptions`, `FormatOptions`, `ValidateOptions`) and wrap the KCL CLI.
 use anyhow::Result;
-use std::process::Command;
+use regex::Regex;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Child, ChildStdout, Command, Output, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use tempfile::NamedTempFile;
+use walkdir::WalkDir;
 
-/// Run a KCL file using the KCL CLI.
+/// Default `kcl` binary: the `KCL_BIN` environment variable, or `"kcl"` (looked up in PATH).
+///
+/// Lets installs outside PATH, or a pinned KCL version, be used without passing the
+/// binary around explicitly.
+pub fn kcl_binary() -> String {
+    std::env::var("KCL_BIN")
+        .ok()
+        .filter(|bin| !bin.is_empty())
+        .unwrap_or_else(|| "kcl".to_string())
+}
+
+/// Where to get the KCL CLI, shown when the binary cannot be found.
+pub const KCL_INSTALL_URL: &str = "https://www.kcl-lang.io/docs/user_docs/getting-started/install";
+
+/// Turn an error spawning a KCL CLI subcommand into a readable message.
+///
+/// A missing binary gets a dedicated message with an install hint instead of the
+/// bare "No such file or directory".
+fn exec_error(bin: &str, subcommand: &str, e: std::io::Error) -> anyhow::Error {
+    if e.kind() == std::io::ErrorKind::NotFound {
+        KclNotFoundError {
+            bin: bin.to_string(),
+        }
+        .into()
+    } else {
+        anyhow::anyhow!("Error executing kcl {}: {}", subcommand, e)
+    }
+}
+
+/// Error of a KCL CLI binary that cannot be found, with an install hint.
+///
+/// Returned inside the `anyhow::Error` of every helper running KCL, use `downcast_ref` to detect it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KclNotFoundError {
+    /// Name or path of the missing binary.
+    pub bin: String,
+}
+
+impl std::fmt::Display for KclNotFoundError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "❌ KCL CLI not found ('{}'). Install it and make sure it is on PATH, or set KCL_BIN to its path. See {}",
+            self.bin, KCL_INSTALL_URL
+        )
+    }
+}
+
+impl std::error::Error for KclNotFoundError {}
+
+/// Build a `Command` for the KCL CLI binary.
+///
+/// The binary is located with `resolve_kcl_binary`, so PATH is only searched once per name.
 ///
 /// # Arguments
-/// * `file` - Path to the KCL file to execute.
-/// * `format` - Output format (e.g., "yaml" or "json").
-/// * `output` - Optional output file path.
-/// * `defines` - List of variable definitions (e.g., ["foo=bar"]).
+/// * `bin` - Name or path of the `kcl` binary (e.g., "kcl" or "/opt/kcl/bin/kcl").
+pub fn kcl_command(bin: &str) -> Command {
+    Command::new(resolve_kcl_binary(bin))
+}
+
+/// Locate the `kcl` binary `bin` names, caching the result for the life of the process.
 ///
-/// # Returns
-/// * `Ok(String)` with the output or output file path on success.
-/// * `Err(anyhow::Error)` if the KCL command fails.
-pub(crate) fn run_kcl_command(
-    file: &str,
-    format: &str,
-    output: &Option<String>,
-    defines: &[String],
-) -> Result<String> {
-    let mut cmd = Command::new("kcl");
-    cmd.arg("run").arg(file).arg("--format").arg(format);
+/// A bare name (e.g. `kcl`) is searched in the directories of PATH, with `.exe` appended on
+/// Windows. A path (e.g. from `KCL_BIN`) is used as is, apart from the `.exe` suffix. If
+/// nothing is found, the name is returned unchanged so spawning it reports the usual
+/// not-found error.
+pub fn resolve_kcl_binary(bin: &str) -> std::path::PathBuf {
+    static RESOLVED: OnceLock<Mutex<HashMap<String, std::path::PathBuf>>> = OnceLock::new();
+    let mut resolved = RESOLVED
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    resolved
+        .entry(bin.to_string())
+        .or_insert_with(|| {
+            let name = exe_name(bin, cfg!(windows));
+            let path = std::path::Path::new(&name);
+            if path.components().count() > 1 {
+                return path.to_path_buf();
+            }
+            std::env::var_os("PATH")
+                .and_then(|paths| {
+                    std::env::split_paths(&paths)
+                        .map(|dir| dir.join(&name))
+                        .find(|candidate| candidate.is_file())
+                })
+                .unwrap_or_else(|| path.to_path_buf())
+        })
+        .clone()
+}
+
+/// Name of the executable for `bin`: with `windows`, `.exe` is appended unless it has an extension.
+pub fn exe_name(bin: &str, windows: bool) -> String {
+    if windows && std::path::Path::new(bin).extension().is_none() {
+        format!("{}.exe", bin)
+    } else {
+        bin.to_string()
+    }
+}
+
+/// Version details of the KCL CLI reported by `kcl version`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct KclVersionInfo {
+    /// Version number, e.g. `"0.10.0"`.
+    pub version: String,
+    /// Commit the CLI was built from, if reported.
+    pub git_commit: Option<String>,
+    /// Platform the CLI was built for (e.g. `"linux-amd64"`), if reported.
+    pub platform: Option<String>,
+}
 
-    // Add defined variables
-    for define in defines {
-        cmd.arg("-D").arg(define);
+/// Get the version details of the KCL CLI with `kcl version`.
+///
+/// # Returns
+/// * `Ok(Some(KclVersionInfo))` with the parsed version details.
+/// * `Ok(None)` if the KCL CLI is not installed.
+/// * `Err(anyhow::Error)` if it fails or prints no recognizable version.
+pub fn kcl_version_info(bin: &str) -> Result<Option<KclVersionInfo>> {
+    let output = match kcl_command(bin).arg("version").output() {
+        Ok(output) => output,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(exec_error(bin, "version", e)),
+    };
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "kcl version failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
     }
+    let text = String::from_utf8_lossy(&output.stdout);
+    parse_kcl_version_info(&text)
+        .map(Some)
+        .ok_or_else(|| anyhow::anyhow!("Unrecognized kcl version output: {}", text.trim()))
+}
 
-    // Add output file if specified
-    if let Some(output_file) = output {
-        cmd.arg("-o").arg(output_file);
+/// Parse the output of `kcl version`.
+///
+/// Both the `key: value` layout (`version`, `gitCommit`, `platform` lines) and the
+/// single word layout (`0.10.0-linux-amd64`) are understood. In the latter, a hex
+/// suffix of at least 7 digits is taken as the commit and the rest as the platform.
+pub fn parse_kcl_version_info(text: &str) -> Option<KclVersionInfo> {
+    let mut info = KclVersionInfo::default();
+    for line in text.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if value.is_empty() {
+            continue;
+        }
+        match key
+            .trim()
+            .to_ascii_lowercase()
+            .replace(['_', '-', ' '], "")
+            .as_str()
+        {
+            "version" => {
+                let parsed = split_version(value);
+                info.version = parsed.version;
+                info.git_commit = info.git_commit.or(parsed.git_commit);
+                info.platform = info.platform.or(parsed.platform);
+            }
+            "gitcommit" | "commit" => info.git_commit = Some(value.to_string()),
+            "platform" => info.platform = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    if info.version.is_empty() {
+        let word = text.split_whitespace().find(|word| is_version_word(word))?;
+        info = split_version(word);
     }
+    Some(info)
+}
 
-    let output_res = cmd
-        .output()
-        .map_err(|e| anyhow::anyhow!("Error executing kcl: {}", e))?;
+/// Whether `word` looks like a version number, e.g. `0.10.0` or `v0.9.8`.
+fn is_version_word(word: &str) -> bool {
+    let word = word.trim_start_matches('v');
+    word.starts_with(|c: char| c.is_ascii_digit()) && word.contains('.')
+}
 
-    if output_res.status.success() {
-        if let Some(output_file) = output {
-            Ok(format!("✅ {}", output_file))
+/// Split a version word like `v0.10.0-e07ed7a-linux-amd64` into its parts.
+fn split_version(word: &str) -> KclVersionInfo {
+    let mut parts = word.trim_start_matches('v').split('-');
+    let mut info = KclVersionInfo {
+        version: parts.next().unwrap_or_default().to_string(),
+        ..KclVersionInfo::default()
+    };
+    let mut platform = Vec::new();
+    for part in parts {
+        if part.len() >= 7 && part.chars().all(|c| c.is_ascii_hexdigit()) {
+            info.git_commit = Some(part.to_string());
+        } else if part.contains('.') {
+            info.version = format!("{}-{}", info.version, part);
         } else {
-            Ok(format!(
-                "✅ {}",
-                String::from_utf8_lossy(&output_res.stdout)
-            ))
+            platform.push(part);
         }
-    } else {
-        Err(anyhow::anyhow!(
-            "❌: {}",
-            String::from_utf8_lossy(&output_res.stderr)
-        ))
     }
+    if !platform.is_empty() {
+        info.platform = Some(platform.join("-"));
+    }
+    info
 }
 
-/// Format a KCL file using the KCL CLI.
+/// Run a command to completion, killing it if it runs longer than `timeout`.
+///
+/// Without a timeout this is the same as `Command::output()`. With one, the child is
+/// spawned with piped stdout/stderr (drained on background threads so a chatty child
+/// cannot block on a full pipe) and polled until it exits or the timeout elapses.
+///
+/// # Returns
+/// * `Ok(Some(Output))` if the command finished in time.
+/// * `Ok(None)` if the timeout elapsed and the child was killed.
+/// * `Err(std::io::Error)` if the command could not be spawned or waited on.
+pub fn output_with_timeout(
+    cmd: &mut Command,
+    timeout: Option<Duration>,
+) -> std::io::Result<Option<Output>> {
+    output_with_interrupt(cmd, timeout, None)
+}
+
+/// Like `output_with_timeout`, but also kill the child once `interrupt` is set.
+///
+/// # Returns
+/// * `Ok(Some(Output))` if the command finished in time.
+/// * `Ok(None)` if the timeout elapsed or the interrupt was set, and the child was killed.
+/// * `Err(std::io::Error)` if the command could not be spawned or waited on.
+pub fn output_with_interrupt(
+    cmd: &mut Command,
+    timeout: Option<Duration>,
+    interrupt: Option<&AtomicBool>,
+) -> std::io::Result<Option<Output>> {
+    if timeout.is_none() && interrupt.is_none() {
+        return cmd.output().map(Some);
+    }
+
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(Output {
+                status,
+                stdout: stdout.join().unwrap_or_default(),
+                stderr: stderr.join().unwrap_or_default(),
+            }));
+        }
+        let timed_out = timeout.is_some_and(|timeout| start.elapsed() >= timeout);
+        if timed_out || interrupt.is_some_and(|i| i.load(Ordering::Relaxed)) {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Read a child pipe to the end on a background thread.
+fn drain<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
+
+/// Executes the KCL CLI commands built by the helpers.
+///
+/// The `_with` variants of the helpers (e.g. `run_kcl_command_with`) take a runner, so
+/// their success, failure and parsing paths can be driven with canned output instead
+/// of a real `kcl` binary.
+pub trait KclRunner: Sync {
+    /// Run `cmd` to completion, see `output_with_interrupt`.
+    ///
+    /// # Returns
+    /// * `Ok(Some(Output))` if the command finished.
+    /// * `Ok(None)` if it was killed after `timeout` or once `interrupt` was set.
+    /// * `Err(std::io::Error)` if it could not be run.
+    fn output(
+        &self,
+        cmd: &mut Command,
+        timeout: Option<Duration>,
+        interrupt: Option<&AtomicBool>,
+    ) -> std::io::Result<Option<Output>>;
+}
+
+/// `KclRunner` spawning the KCL CLI, used by the helpers without a `_with` suffix.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CliRunner;
+
+impl KclRunner for CliRunner {
+    fn output(
+        &self,
+        cmd: &mut Command,
+        timeout: Option<Duration>,
+        interrupt: Option<&AtomicBool>,
+    ) -> std::io::Result<Option<Output>> {
+        output_with_interrupt(cmd, timeout, interrupt)
+    }
+}
+
+/// Run a KCL file using the KCL CLI.
 ///
 /// # Arguments
-/// * `file` - Path to the KCL file to format.
+/// * `opts` - What to run and how, see `RunOptions`. With `validate_output` set, the
+///   rendered output is parsed to check it is well-formed in its format.
 ///
 /// # Returns
-/// * `Ok(String)` with a success message if formatting succeeds.
-/// * `Err(anyhow::Error)` if formatting fails.
-pub(crate) fn format_kcl_file(file: &str) -> Result<String> {
-    let output = Command::new("kcl")
-        .arg("fmt")
-        .arg(file)
-        .output()
-        .map_err(|e| anyhow::anyhow!("Error executing kcl fmt: {}", e))?;
+/// * `Ok(RunOutput)` with the output or output file path, and KCL's warnings, on success.
+/// * `Err(anyhow::Error)` if the KCL command fails, times out or the output does not parse,
+///   or a `NotUtf8Error` if the output is not valid UTF-8.
+pub fn run_kcl_command(opts: &RunOptions) -> Result<RunOutput> {
+    run_kcl_command_with(&CliRunner, opts)
+}
 
-    if !output.status.success() {
+/// Like `run_kcl_command`, running KCL through `runner`.
+pub fn run_kcl_command_with(runner: &dyn KclRunner, opts: &RunOptions) -> Result<RunOutput> {
+    let Some(output_file) = &opts.output else {
+        let rendered = render_kcl_with(runner, opts)?;
+        return Ok(RunOutput {
+            text: format!("✅ {}", rendered.text),
+            warnings: rendered.warnings,
+        });
+    };
+
+    let output_res = kcl_run(runner, opts)?;
+    if opts.validate_output {
+        let rendered = std::fs::read_to_string(output_file)
+            .map_err(|e| anyhow::anyhow!("Error reading {}: {}", output_file, e))?;
+        parse_output(&rendered, &opts.format)?;
+    }
+    Ok(RunOutput {
+        text: format!("✅ {}", output_file),
+        warnings: warnings(&output_res),
+    })
+}
+
+/// Text produced by a successful `run_kcl_command` or `render_kcl`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunOutput {
+    /// Rendered output (or, for `run_kcl_command`, the ✅ message).
+    pub text: String,
+    /// Warnings KCL printed to stderr, e.g. deprecations, or `None` if it printed nothing.
+    pub warnings: Option<String>,
+}
+
+/// Replace the ✅/❌/⚠ decorations of messages with plain `OK`/`FAIL`/`WARN` words,
+/// for terminals and logs without emoji support.
+pub fn plain_text(text: &str) -> String {
+    text.replace('✅', "OK")
+        .replace('❌', "FAIL")
+        .replace("⚠", "WARN")
+}
+
+/// ANSI escape sequences: CSI (colors, cursor moves), OSC (e.g. hyperlinks) and two-byte escapes.
+const ANSI_PATTERN: &str = r"\x1b(?:\[[0-?]*[ -/]*[@-~]|\][^\x07\x1b]*(?:\x07|\x1b\\)|[@-Z\\-_])";
+
+/// Remove the ANSI escape sequences (e.g. colors) KCL may print from `text`.
+pub fn strip_ansi(text: &str) -> String {
+    if !text.contains('\x1b') {
+        return text.to_string();
+    }
+    static ANSI: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(ANSI_PATTERN).expect("valid ANSI regex"));
+    ANSI.replace_all(text, "").into_owned()
+}
+
+/// Remove the ANSI escape sequences from raw process output, keeping other bytes as is.
+fn strip_ansi_bytes(bytes: Vec<u8>) -> Vec<u8> {
+    if !bytes.contains(&0x1b) {
+        return bytes;
+    }
+    static ANSI: LazyLock<regex::bytes::Regex> =
+        LazyLock::new(|| regex::bytes::Regex::new(ANSI_PATTERN).expect("valid ANSI regex"));
+    ANSI.replace_all(&bytes, &b""[..]).into_owned()
+}
+
+/// Strip the ANSI escape sequences from both streams of a process output.
+fn strip_output_ansi(output: Output) -> Output {
+    Output {
+        stdout: strip_ansi_bytes(output.stdout),
+        stderr: strip_ansi_bytes(output.stderr),
+        ..output
+    }
+}
+
+/// Non-empty stderr of a successful KCL process.
+fn warnings(output: &Output) -> Option<String> {
+    let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+    (!stderr.is_empty()).then_some(stderr)
+}
+
+/// Run KCL and return the rendered output as is, without writing any file.
+///
+/// Takes the same options as `run_kcl_command`, the output file is ignored.
+///
+/// # Returns
+/// * `Ok(RunOutput)` with the text printed by `kcl run` and its warnings.
+/// * `Err(anyhow::Error)` if the KCL command fails, times out or the output does not parse.
+pub fn render_kcl(opts: &RunOptions) -> Result<RunOutput> {
+    render_kcl_with(&CliRunner, opts)
+}
+
+/// Like `render_kcl`, running KCL through `runner`.
+fn render_kcl_with(runner: &dyn KclRunner, opts: &RunOptions) -> Result<RunOutput> {
+    let opts = RunOptions {
+        output: None,
+        ..opts.clone()
+    };
+    let output_res = kcl_run(runner, &opts)?;
+    let rendered = utf8_output(output_res.stdout.clone())?;
+    if opts.validate_output {
+        parse_output(&rendered, &opts.format)?;
+    }
+    Ok(RunOutput {
+        text: rendered,
+        warnings: warnings(&output_res),
+    })
+}
+
+/// Output formats of `kcl run`. KCL renders `toml` and `ndjson` as JSON, which is then converted.
+pub const OUTPUT_FORMATS: &[&str] = &["yaml", "json", "toml", "ndjson"];
+
+/// Convert JSON output rendered by KCL into newline-delimited JSON, one list item per line.
+///
+/// # Returns
+/// * `Ok(String)` with each item of the top-level list on its own line.
+/// * `Err(anyhow::Error)` if the output is not a JSON list.
+pub fn json_to_ndjson(output: &str) -> Result<String> {
+    let doc: serde_json::Value = serde_json::from_str(output)
+        .map_err(|e| anyhow::anyhow!("❌ KCL produced malformed json output: {}", e))?;
+    let serde_json::Value::Array(items) = doc else {
         return Err(anyhow::anyhow!(
-            "KCL format failed: {}",
-            String::from_utf8_lossy(&output.stderr)
+            "❌ ndjson output needs a list at the top level, got {}",
+            json_type(&doc)
         ));
-    }
+    };
+    items
+        .iter()
+        .map(|item| Ok(format!("{}\n", serde_json::to_string(item)?)))
+        .collect()
+}
 
-    Ok(format!("✅ File formatted: {}", file))
+/// Name of the type of a JSON value, for error messages.
+fn json_type(value: &serde_json::Value) -> &'static str {
+    match value {
+        serde_json::Value::Null => "null",
+        serde_json::Value::Bool(_) => "a boolean",
+        serde_json::Value::Number(_) => "a number",
+        serde_json::Value::String(_) => "a string",
+        serde_json::Value::Array(_) => "a list",
+        serde_json::Value::Object(_) => "a record",
+    }
 }
 
-/// Validate all KCL files in a directory using the KCL CLI.
+/// Convert JSON output rendered by KCL into TOML.
 ///
-/// # Arguments
-/// * `dir` - Path to the directory to search for KCL files.
+/// TOML has no null, so `None` values are dropped, and the top level must be a record.
 ///
 /// # Returns
-/// * `Ok(String)` with a summary of validation results if all files are valid or no files found.
-/// * `Err(anyhow::Error)` if validation fails for any file or if the find command fails.
-pub(crate) fn validate_kcl_project(dir: &str) -> Result<String> {
-    // Find KCL files in directory
-    let find_output = Command::new("find")
-        .arg(dir)
-        .arg("-name")
-        .arg("*.k")
-        .arg("-type")
-        .arg("f")
-        .output()
-        .map_err(|e| anyhow::anyhow!("Error finding KCL files: {}", e))?;
+/// * `Ok(String)` with the TOML document.
+/// * `Err(anyhow::Error)` if the output is not a JSON record.
+pub fn json_to_toml(output: &str) -> Result<String> {
+    let doc: serde_json::Value = serde_json::from_str(output)
+        .map_err(|e| anyhow::anyhow!("❌ KCL produced malformed json output: {}", e))?;
+    if !doc.is_object() {
+        return Err(anyhow::anyhow!(
+            "❌ toml output needs a record at the top level"
+        ));
+    }
+    toml::to_string(&drop_nulls(doc))
+        .map_err(|e| anyhow::anyhow!("❌ Cannot render the output as toml: {}", e))
+}
+
+/// Remove the null values of a JSON document, recursively.
+fn drop_nulls(value: serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(map) => map
+            .into_iter()
+            .filter(|(_, value)| !value.is_null())
+            .map(|(key, value)| (key, drop_nulls(value)))
+            .collect(),
+        serde_json::Value::Array(items) => items
+            .into_iter()
+            .filter(|value| !value.is_null())
+            .map(drop_nulls)
+            .collect(),
+        other => other,
+    }
+}
+
+/// Error of a `kcl run` killed after running past its timeout.
+///
+/// Returned inside the `anyhow::Error` of the run functions, use `downcast_ref` to detect it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeoutError {
+    /// KCL files that were running.
+    pub files: Vec<String>,
+    /// Limit that elapsed.
+    pub timeout: Duration,
+}
+
+impl std::fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "❌ kcl run timed out on {} after {:?}",
+            self.files.join(" "),
+            self.timeout
+        )
+    }
+}
+
+impl std::error::Error for TimeoutError {}
+
+/// Error of a `kcl run` whose output is not valid UTF-8, holding the raw output.
+///
+/// Returned inside the `anyhow::Error` of the run functions instead of a lossily
+/// decoded output, use `downcast` to get the bytes back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NotUtf8Error {
+    /// Output as KCL printed it.
+    pub bytes: Vec<u8>,
+    /// Length of the valid UTF-8 prefix of `bytes`.
+    pub valid_up_to: usize,
+}
+
+impl std::fmt::Display for NotUtf8Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "❌ KCL output is not valid UTF-8 (invalid byte at offset {} of {})",
+            self.valid_up_to,
+            self.bytes.len()
+        )
+    }
+}
+
+impl std::error::Error for NotUtf8Error {}
+
+/// Decode the output of KCL, failing with a `NotUtf8Error` rather than replacing invalid bytes.
+fn utf8_output(bytes: Vec<u8>) -> Result<String> {
+    String::from_utf8(bytes).map_err(|e| {
+        anyhow::Error::new(NotUtf8Error {
+            valid_up_to: e.utf8_error().valid_up_to(),
+            bytes: e.into_bytes(),
+        })
+    })
+}
+
+/// Invoke `kcl run`, failing with KCL's stderr if it does not succeed.
+///
+/// With the `toml` format, the JSON KCL rendered (to stdout or the output file) is
+/// converted to TOML in place. ANSI escape sequences are stripped unless `opts.color`.
+fn kcl_run(runner: &dyn KclRunner, opts: &RunOptions) -> Result<Output> {
+    let output_res = kcl_exec(runner, opts)?;
+    if !output_res.status.success() {
+        return Err(anyhow::anyhow!(
+            "❌: {}",
+            String::from_utf8_lossy(&output_res.stderr)
+        ));
+    }
+    convert_output(opts, output_res)
+}
+
+/// Invoke `kcl run` and return its output whatever its exit status.
+///
+/// Fails only if the options are not supported, KCL cannot be started or it times out.
+fn kcl_exec(runner: &dyn KclRunner, opts: &RunOptions) -> Result<Output> {
+    check_run_options(opts)?;
+    let mut cmd = opts.command();
+    let output_res = runner
+        .output(&mut cmd, opts.timeout, None)
+        .map_err(|e| exec_error(&opts.bin, "run", e))?
+        .ok_or_else(|| {
+            anyhow::Error::new(TimeoutError {
+                files: opts.files.clone(),
+                timeout: opts.timeout.unwrap_or_default(),
+            })
+        })?;
+    Ok(if opts.color {
+        output_res
+    } else {
+        strip_output_ansi(output_res)
+    })
+}
+
+/// Convert the output of a successful `kcl run` to TOML or NDJSON if that is the requested format.
+fn convert_output(opts: &RunOptions, output_res: Output) -> Result<Output> {
+    let convert: fn(&str) -> Result<String> = match opts.format.as_str() {
+        "toml" => json_to_toml,
+        "ndjson" => json_to_ndjson,
+        _ => return Ok(output_res),
+    };
+    match &opts.output {
+        Some(output_file) => {
+            let rendered = std::fs::read_to_string(output_file)
+                .map_err(|e| anyhow::anyhow!("Error reading {}: {}", output_file, e))?;
+            std::fs::write(output_file, convert(&rendered)?)
+                .map_err(|e| anyhow::anyhow!("Error writing {}: {}", output_file, e))?;
+            Ok(output_res)
+        }
+        None => {
+            let rendered = convert(&String::from_utf8_lossy(&output_res.stdout))?;
+            Ok(Output {
+                stdout: rendered.into_bytes(),
+                ..output_res
+            })
+        }
+    }
+}
 
-    let files = String::from_utf8_lossy(&find_output.stdout);
-    let kcl_files: Vec<&str> = files.lines().filter(|line| !line.is_empty()).collect();
+/// Fail early on options `kcl run` cannot be invoked with.
+fn check_run_options(opts: &RunOptions) -> Result<()> {
+    if opts.files.is_empty() {
+        return Err(anyhow::anyhow!("❌ No KCL files to run"));
+    }
+    if !OUTPUT_FORMATS.contains(&opts.format.as_str()) {
+        return Err(anyhow::anyhow!(
+            "❌ Unsupported format '{}', expected one of: {}",
+            opts.format,
+            OUTPUT_FORMATS.join(", ")
+        ));
+    }
+    Ok(())
+}
 
-    if kcl_files.is_empty() {
-        return Ok(format!("No KCL files found in {}", dir));
+/// Start `kcl run` and read its output line by line as KCL prints it.
+///
+/// Unlike `render_kcl`, the output is never held in memory as a whole. The output
+/// file, timeout and output validation are not supported here, and neither are the
+/// `toml` and `ndjson` formats since they need the whole output to be converted.
+///
+/// # Returns
+/// * `Ok(KclLines)` iterating over the output lines once KCL started.
+/// * `Err(anyhow::Error)` if the options are not supported or KCL cannot be started.
+pub fn stream_kcl(opts: &RunOptions) -> Result<KclLines> {
+    check_run_options(opts)?;
+    if opts.format == "toml" || opts.format == "ndjson" {
+        return Err(anyhow::anyhow!(
+            "❌ The {} format cannot be streamed",
+            opts.format
+        ));
     }
+    let opts = RunOptions {
+        output: None,
+        ..opts.clone()
+    };
+    let mut child = opts
+        .command()
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| exec_error(&opts.bin, "run", e))?;
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| anyhow::anyhow!("❌ KCL output is not available"))?;
+    let stderr = drain(child.stderr.take());
+    Ok(KclLines {
+        lines: BufReader::new(stdout).lines(),
+        child,
+        stderr: Some(stderr),
+        color: opts.color,
+        done: false,
+    })
+}
 
-    let mut results = Vec::new();
-    let mut all_valid = true;
+/// Output lines of a running `kcl run`, see `stream_kcl`.
+///
+/// Once the output ends, yields a last error with KCL's stderr if it failed. Dropping
+/// it before the end kills KCL.
+pub struct KclLines {
+    lines: std::io::Lines<BufReader<ChildStdout>>,
+    child: Child,
+    stderr: Option<thread::JoinHandle<Vec<u8>>>,
+    color: bool,
+    done: bool,
+}
 
-    for file in &kcl_files {
-        let output = Command::new("kcl")
-            .arg("run")
-            .arg(file)
-            .arg("--format")
-            .arg("yaml")
-            .output();
+impl Iterator for KclLines {
+    type Item = Result<String>;
 
-        match output {
-            Ok(output) if output.status.success() => {
-                results.push(format!("✅ {}", file));
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.lines.next() {
+            Some(Ok(line)) if self.color => Some(Ok(line)),
+            Some(Ok(line)) => Some(Ok(strip_ansi(&line))),
+            Some(Err(e)) => {
+                self.done = true;
+                let _ = self.child.kill();
+                let _ = self.child.wait();
+                Some(Err(anyhow::anyhow!("❌ Error reading KCL output: {}", e)))
             }
-            Ok(output) => {
-                results.push(format!(
-                    "❌ {}: {}",
-                    file,
-                    String::from_utf8_lossy(&output.stderr)
-                ));
-                all_valid = false;
+            None => {
+                self.done = true;
+                let status = self.child.wait();
+                let stderr = self
+                    .stderr
+                    .take()
+                    .map(|h| h.join().unwrap_or_default())
+                    .unwrap_or_default();
+                match status {
+                    Ok(status) if status.success() => None,
+                    Ok(_) if self.color => Some(Err(anyhow::anyhow!(
+                        "❌: {}",
+                        String::from_utf8_lossy(&stderr)
+                    ))),
+                    Ok(_) => Some(Err(anyhow::anyhow!(
+                        "❌: {}",
+                        String::from_utf8_lossy(&strip_ansi_bytes(stderr))
+                    ))),
+                    Err(e) => Some(Err(e.into())),
+                }
             }
-            Err(e) => {
-                results.push(format!("❌ {}: Execution error: {}", file, e));
-                all_valid = false;
+        }
+    }
+}
+
+impl Drop for KclLines {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+/// Write `content` to `path` unless the file already holds exactly the same bytes.
+///
+/// Skipping identical writes keeps the file's mtime, so file watchers and
+/// incremental tools are not triggered by no-op runs.
+///
+/// # Returns
+/// * `Ok(true)` if the file was written, `Ok(false)` if it was left untouched.
+pub fn write_if_changed(path: &str, content: &str) -> Result<bool> {
+    if let Ok(existing) = std::fs::read(path)
+        && existing == content.as_bytes()
+    {
+        return Ok(false);
+    }
+    std::fs::write(path, content).map_err(|e| anyhow::anyhow!("Error writing {}: {}", path, e))?;
+    Ok(true)
+}
+
+/// Location and message of an error reported by KCL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KclDiagnostic {
+    /// File the error points at.
+    pub file: String,
+    /// 1-based line number.
+    pub line: usize,
+    /// 1-based column number.
+    pub column: usize,
+    /// Readable message, e.g. `"CompileError: expected expression"`.
+    pub message: String,
+    /// KCL error code, e.g. `"E2L23"`, if the error title had one.
+    pub code: Option<String>,
+}
+
+impl std::fmt::Display for KclDiagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}",
+            self.file, self.line, self.column, self.message
+        )
+    }
+}
+
+/// Parse the `file:line:col` diagnostics out of KCL's error output.
+///
+/// Understands the current format (`error[E2L23]: ...` followed by ` --> file:line:col`)
+/// and the older one (`KCL Syntax Error[E1001] : ...` followed by `---> File file:line:col`).
+/// The message is the error title, followed by the text under the `^` marker if any.
+///
+/// # Returns
+/// One diagnostic per location found, in order (empty if there is none).
+pub fn parse_diagnostics(stderr: &str) -> Vec<KclDiagnostic> {
+    static TITLE: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"^\s*(?:❌:\s*)?(?:error|KCL [\w ]*?Error)\[(\w+)\]\s*:\s*(.+)$")
+            .expect("valid title regex")
+    });
+    static LOCATION: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"^\s*(?:-->|---> File)\s*(.+?):(\d+):(\d+)\s*$").expect("valid location regex")
+    });
+    static MARKER: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"^\s*\d*\s*\|\s*\^+\s*(.*)$").expect("valid marker regex"));
+
+    let mut diagnostics: Vec<KclDiagnostic> = Vec::new();
+    let mut current_title = String::new();
+    let mut current_code = None;
+    let mut has_marker = false;
+    for line in stderr.lines() {
+        if let Some(caps) = TITLE.captures(line) {
+            current_code = Some(caps[1].to_string());
+            current_title = caps[2].trim().to_string();
+        } else if let Some(caps) = LOCATION.captures(line) {
+            diagnostics.push(KclDiagnostic {
+                file: caps[1].to_string(),
+                line: caps[2].parse().unwrap_or_default(),
+                column: caps[3].parse().unwrap_or_default(),
+                message: current_title.clone(),
+                code: current_code.clone(),
+            });
+            has_marker = false;
+        } else if let Some(caps) = MARKER.captures(line)
+            && !has_marker
+            && let Some(last) = diagnostics.last_mut()
+        {
+            let detail = caps[1].trim();
+            if !detail.is_empty() {
+                last.message = if last.message.is_empty() {
+                    detail.to_string()
+                } else {
+                    format!("{}: {}", last.message, detail)
+                };
             }
+            has_marker = true;
         }
     }
+    diagnostics
+}
+
+/// Run a hook shell command (`sh -c` on Unix, `cmd /C` on Windows).
+///
+/// Hooks run in the same working directory and environment as the KCL invocation,
+/// so they can prepare inputs for it or clean up after it.
+///
+/// # Arguments
+/// * `hook` - Shell command line to run.
+/// * `cwd` - Working directory KCL runs in (see `RunOptions::in_package_root`).
+/// * `env` - Environment variables set for KCL, on top of the inherited ones.
+///
+/// # Returns
+/// * `Ok(())` if the hook exits successfully.
+/// * `Err(anyhow::Error)` with the hook's stderr if it cannot be executed or fails.
+pub fn run_hook(hook: &str, cwd: Option<&str>, env: &[(String, String)]) -> Result<()> {
+    let mut cmd = if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C").arg(hook);
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(hook);
+        cmd
+    };
+    if let Some(cwd) = cwd {
+        cmd.current_dir(cwd);
+    }
+    cmd.envs(env.iter().map(|(key, value)| (key, value)));
+
+    let output = cmd
+        .output()
+        .map_err(|e| anyhow::anyhow!("Error executing hook '{}': {}", hook, e))?;
 
-    let summary = if all_valid {
-        format!("✅ All {} files are valid", kcl_files.len())
+    if output.status.success() {
+        Ok(())
     } else {
-        format!("❌ Errors found in some files")
+        Err(anyhow::anyhow!(
+            "Hook '{}' failed ({}): {}",
+            hook,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+/// Expand a leading `~` and `$VAR`/`${VAR}` references in a path.
+///
+/// Variables are read with `lookup`, so callers can take them from the shell's
+/// environment rather than the plugin's. `~` expands to `HOME` (`USERPROFILE` on
+/// Windows); `~user` forms are left as they are.
+///
+/// # Returns
+/// * `Ok(String)` with the expanded path.
+/// * `Err(anyhow::Error)` naming the first variable that is not defined.
+pub fn expand_path(path: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String> {
+    let home_var = if cfg!(windows) { "USERPROFILE" } else { "HOME" };
+    let (home, rest) = match path.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with(['/', std::path::MAIN_SEPARATOR]) => {
+            let home = lookup(home_var).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Cannot expand ~ in '{}': ${} is not defined",
+                    path,
+                    home_var
+                )
+            })?;
+            (home, rest)
+        }
+        _ => (String::new(), path),
     };
+    static VARIABLE: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"\$(?:\{(\w+)\}|(\w+))").expect("valid variable regex"));
+    let mut missing = None;
+    let expanded = VARIABLE.replace_all(rest, |caps: &regex::Captures| {
+        let name = caps
+            .get(1)
+            .or_else(|| caps.get(2))
+            .map_or("", |m| m.as_str());
+        lookup(name).unwrap_or_else(|| {
+            missing.get_or_insert_with(|| name.to_string());
+            String::new()
+        })
+    });
+    if let Some(name) = missing {
+        return Err(anyhow::anyhow!(
+            "Cannot expand '{}': ${} is not defined",
+            path,
+            name
+        ));
+    }
+    Ok(format!("{}{}", home, expanded))
+}
+
+/// Write KCL source code to a temporary `.k` file.
+///
+/// The file is deleted when the returned handle is dropped.
+///
+/// # Arguments
+/// * `source` - KCL source code.
+///
+/// # Returns
+/// * `Ok(NamedTempFile)` holding the source.
+/// * `Err(anyhow::Error)` if the file cannot be created or written.
+pub fn write_temp_kcl(source: &str) -> Result<NamedTempFile> {
+    let mut file = tempfile::Builder::new()
+        .prefix("kcl-run-")
+        .suffix(".k")
+        .tempfile()
+        .map_err(|e| anyhow::anyhow!("Error creating temp KCL file: {}", e))?;
+    std::io::Write::write_all(&mut file, source.as_bytes())
+        .map_err(|e| anyhow::anyhow!("Error writing temp KCL file: {}", e))?;
+    Ok(file)
+}
+
+/// Evaluate a KCL expression using the KCL CLI.
+///
+/// The expression is assigned to a `result` variable in a temporary `.k` file,
+/// which is run with JSON output.
+///
+/// # Arguments
+/// * `bin` - Name or path of the `kcl` binary.
+/// * `expression` - KCL expression, e.g. `"1 + 2"`.
+///
+/// # Returns
+/// * `Ok(serde_json::Value)` with the value of the expression.
+/// * `Err(anyhow::Error)` if KCL fails to evaluate it.
+pub fn eval_kcl(bin: &str, expression: &str) -> Result<serde_json::Value> {
+    let source = write_temp_kcl(&format!("result = {}\n", expression))?;
+    let rendered = render_kcl(
+        &RunOptions::new(source.path().to_string_lossy())
+            .bin(bin)
+            .format("json"),
+    )?;
+    let mut documents = parse_output(&rendered.text, "json")?;
+    Ok(documents
+        .pop()
+        .and_then(|mut document| document.get_mut("result").map(serde_json::Value::take))
+        .unwrap_or(serde_json::Value::Null))
+}
+
+/// A single difference found by `json_diff`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonChange {
+    /// Dotted path of the key or list index, e.g. `spec.ports.0`.
+    pub path: String,
+    /// `added`, `removed` or `changed`.
+    pub change: &'static str,
+    /// Value on the old side, `None` for added keys.
+    pub old: Option<serde_json::Value>,
+    /// Value on the new side, `None` for removed keys.
+    pub new: Option<serde_json::Value>,
+}
+
+/// Compare two JSON values key by key.
+///
+/// Objects are compared per key and lists per index, so a change deep inside a config
+/// is reported at its own path rather than as a change of the whole document. Keys are
+/// reported in the order of `old`, followed by the keys only found in `new`.
+pub fn json_diff(old: &serde_json::Value, new: &serde_json::Value) -> Vec<JsonChange> {
+    let mut changes = Vec::new();
+    diff_at("", old, new, &mut changes);
+    changes
+}
+
+fn diff_at(
+    path: &str,
+    old: &serde_json::Value,
+    new: &serde_json::Value,
+    changes: &mut Vec<JsonChange>,
+) {
+    use serde_json::Value;
 
-    Ok(format!("{}\n\n{}", summary, results.join("\n")))
+    let child = |key: &str| {
+        if path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", path, key)
+        }
+    };
+    let change = |path: String, change, old: Option<&Value>, new: Option<&Value>| JsonChange {
+        path,
+        change,
+        old: old.cloned(),
+        new: new.cloned(),
+    };
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            for (key, old_value) in old {
+                match new.get(key) {
+                    Some(new_value) => diff_at(&child(key), old_value, new_value, changes),
+                    None => changes.push(change(child(key), "removed", Some(old_value), None)),
+                }
+            }
+            for (key, new_value) in new.iter().filter(|(key, _)| !old.contains_key(*key)) {
+                changes.push(change(child(key), "added", None, Some(new_value)));
+            }
+        }
+        (Value::Array(old), Value::Array(new)) => {
+            for index in 0..old.len().max(new.len()) {
+                let path = child(&index.to_string());
+                match (old.get(index), new.get(index)) {
+                    (Some(old_value), Some(new_value)) => {
+                        diff_at(&path, old_value, new_value, changes)
+                    }
+                    (Some(old_value), None) => {
+                        changes.push(change(path, "removed", Some(old_value), None))
+                    }
+                    (None, new_value) => changes.push(change(path, "added", None, new_value)),
+                }
+            }
+        }
+        (old, new) if old != new => {
+            changes.push(change(path.to_string(), "changed", Some(old), Some(new)))
+        }
+        _ => {}
+    }
+}
+
+/// Evaluate two KCL runs as JSON and compare their results with `json_diff`.
+///
+/// The `format` of both options is forced to JSON, so any output format set on them is
+/// ignored.
+pub fn diff_kcl(old: &RunOptions, new: &RunOptions) -> Result<Vec<JsonChange>> {
+    let evaluate = |opts: &RunOptions| -> Result<serde_json::Value> {
+        let rendered = render_kcl(&opts.clone().format("json"))?;
+        Ok(parse_output(&rendered.text, "json")?
+            .pop()
+            .unwrap_or(serde_json::Value::Null))
+    };
+    Ok(json_diff(&evaluate(old)?, &evaluate(new)?))
+}
+
+/// Write a `<file>.sha256` sidecar holding the SHA-256 digest of `file`.
+///
+/// The sidecar uses the `sha256sum` format (`<hash>  <filename>`) with the bare
+/// file name, so it can be checked with `sha256sum -c` next to the file.
+///
+/// # Returns
+/// The path of the sidecar file written.
+pub fn write_checksum_file(file: &str) -> Result<String> {
+    let contents =
+        std::fs::read(file).map_err(|e| anyhow::anyhow!("Error reading {}: {}", file, e))?;
+    let digest = Sha256::digest(&contents);
+    let name = std::path::Path::new(file)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| file.to_string());
+    let sidecar = format!("{}.sha256", file);
+    std::fs::write(&sidecar, format!("{:x}  {}\n", digest, name))
+        .map_err(|e| anyhow::anyhow!("Error writing {}: {}", sidecar, e))?;
+    Ok(sidecar)
+}
+
+/// Run several KCL files one after another using the KCL CLI.
+///
+/// Each file of `opts.files` is run on its own with the other options, see
+/// `run_kcl_command`. A failing file does not stop the remaining ones from running.
+///
+/// # Returns
+/// One `(file, result)` pair per input file, in the same order.
+pub fn run_kcl_files(opts: &RunOptions) -> Vec<(String, Result<RunOutput>)> {
+    opts.files
+        .iter()
+        .map(|file| {
+            let single = RunOptions {
+                files: vec![file.clone()],
+                output: None,
+                ..opts.clone()
+            };
+            (file.clone(), run_kcl_command(&single))
+        })
+        .collect()
+}
+
+/// Rewrite `key=value` defines so their values use the KCL literal syntax of a hinted type.
+///
+/// Hints map a define key to one of `int`, `float`, `bool` or `string`. Numbers are
+/// passed unquoted, booleans as `True`/`False`, and strings are quoted so that a value
+/// like `3` stays a string. Defines without a hint are passed through unchanged.
+///
+/// # Arguments
+/// * `defines` - List of variable definitions (e.g., ["replicas=3"]).
+/// * `hints` - Pairs of define key and type name (e.g., [("replicas", "int")]).
+///
+/// # Returns
+/// * `Ok(Vec<String>)` with the rewritten defines, in the same order.
+/// * `Err(anyhow::Error)` if a hint names an unknown type or a value does not match its type.
+pub fn apply_type_hints(defines: &[String], hints: &[(String, String)]) -> Result<Vec<String>> {
+    defines
+        .iter()
+        .map(|define| {
+            let Some((key, value)) = define.split_once('=') else {
+                return Ok(define.clone());
+            };
+            let Some((_, ty)) = hints.iter().find(|(hint_key, _)| hint_key == key) else {
+                return Ok(define.clone());
+            };
+            let literal = match ty.as_str() {
+                "int" => value
+                    .trim()
+                    .parse::<i64>()
+                    .map(|n| n.to_string())
+                    .map_err(|_| {
+                        anyhow::anyhow!("Define '{}' value '{}' is not an int", key, value)
+                    })?,
+                "float" => value
+                    .trim()
+                    .parse::<f64>()
+                    .map(|n| n.to_string())
+                    .map_err(|_| {
+                        anyhow::anyhow!("Define '{}' value '{}' is not a float", key, value)
+                    })?,
+                "bool" => match value.trim() {
+                    "true" | "True" => "True".to_string(),
+                    "false" | "False" => "False".to_string(),
+                    _ => {
+                        return Err(anyhow::anyhow!(
+                            "Define '{}' value '{}' is not a bool",
+                            key,
+                            value
+                        ));
+                    }
+                },
+                "string" | "str" => serde_json::to_string(value)?,
+                other => {
+                    return Err(anyhow::anyhow!(
+                        "Unknown type '{}' for define '{}' (expected int, float, bool or string)",
+                        other,
+                        key
+                    ));
+                }
+            };
+            Ok(format!("{}={}", key, literal))
+        })
+        .collect()
+}
+
+/// Split `KEY=VALUE` entries into environment variable pairs.
+///
+/// Only the first `=` separates the key, so values may contain `=` themselves.
+///
+/// # Returns
+/// * `Ok(Vec<(String, String)>)` with the pairs, in the same order.
+/// * `Err(anyhow::Error)` if an entry has no `=` or an empty key.
+pub fn parse_env_vars(entries: &[String]) -> Result<Vec<(String, String)>> {
+    entries
+        .iter()
+        .map(|entry| match entry.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => {
+                Ok((key.trim().to_string(), value.to_string()))
+            }
+            _ => Err(anyhow::anyhow!(
+                "Invalid environment variable '{}' (expected KEY=VALUE)",
+                entry
+            )),
+        })
+        .collect()
+}
+
+/// Read `key=value` entries from an overrides file, one per line.
+///
+/// Blank lines and lines starting with `#` are skipped, and entries are trimmed. A `#`
+/// after an entry is part of its value.
+///
+/// # Returns
+/// * `Ok(Vec<String>)` with the entries, in file order.
+/// * `Err(anyhow::Error)` if the file cannot be read or a line is not `key=value`,
+///   naming the line number.
+pub fn read_overrides_file(path: &str) -> Result<Vec<String>> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Error reading {}: {}", path, e))?;
+    let mut entries = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once('=') {
+            Some((key, _)) if !key.trim().is_empty() => entries.push(line.to_string()),
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "❌ {}:{}: expected key=value, got '{}'",
+                    path,
+                    index + 1,
+                    line
+                ));
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Parse rendered KCL output in the given format.
+///
+/// YAML output may hold several `---` separated documents, and NDJSON output one document
+/// per line, each one is parsed and returned in order. JSON and TOML output always yield a
+/// single document.
+///
+/// # Arguments
+/// * `output` - Text rendered by `kcl run`.
+/// * `format` - Format of the text ("yaml", "json", "toml" or "ndjson").
+///
+/// # Returns
+/// * `Ok(Vec<serde_json::Value>)` with the parsed documents.
+/// * `Err(anyhow::Error)` if the text is not well-formed in `format`.
+pub fn parse_output(output: &str, format: &str) -> Result<Vec<serde_json::Value>> {
+    match format {
+        "json" => serde_json::from_str(output)
+            .map(|doc| vec![doc])
+            .map_err(|e| anyhow::anyhow!("❌ KCL produced malformed json output: {}", e)),
+        "toml" => toml::from_str(output)
+            .map(|doc| vec![doc])
+            .map_err(|e| anyhow::anyhow!("❌ KCL produced malformed toml output: {}", e)),
+        "ndjson" => output
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                serde_json::from_str(line)
+                    .map_err(|e| anyhow::anyhow!("❌ KCL produced malformed ndjson output: {}", e))
+            })
+            .collect(),
+        _ => serde_yaml::Deserializer::from_str(output)
+            .map(|doc| {
+                serde_json::Value::deserialize(doc)
+                    .map_err(|e| anyhow::anyhow!("❌ KCL produced malformed yaml output: {}", e))
+            })
+            .collect(),
+    }
+}
+
+/// Format a KCL file using the KCL CLI.
+///
+/// # Arguments
+/// * `bin` - Name or path of the `kcl` binary.
+/// * `file` - Path to the KCL file to format.
+///
+/// # Returns
+/// * `Ok(String)` with a success message if formatting succeeds.
+/// * `Err(anyhow::Error)` if formatting fails.
+pub fn format_kcl_file(bin: &str, file: &str) -> Result<String> {
+    format_kcl_file_with(&CliRunner, bin, file)
+}
+
+/// Like `format_kcl_file`, running KCL through `runner`.
+pub fn format_kcl_file_with(runner: &dyn KclRunner, bin: &str, file: &str) -> Result<String> {
+    let mut cmd = kcl_command(bin);
+    cmd.arg("fmt").arg(file);
+    let output = runner
+        .output(&mut cmd, None, None)
+        .map_err(|e| exec_error(bin, "fmt", e))?
+        .ok_or_else(|| anyhow::anyhow!("kcl fmt was killed formatting {}", file))?;
+
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "KCL format failed: {}",
+            strip_ansi(&String::from_utf8_lossy(&output.stderr))
+        ));
+    }
+
+    Ok(format!("✅ File formatted: {}", file))
+}
+
+/// Format KCL source code with `kcl fmt`, through a temporary `.k` file.
+///
+/// # Returns
+/// * `Ok(String)` with the formatted source.
+/// * `Err(anyhow::Error)` if the temporary file cannot be written or `kcl fmt` fails.
+pub fn format_kcl_source(bin: &str, source: &str) -> Result<String> {
+    let copy = write_temp_kcl(source)?;
+    format_kcl_file(bin, &copy.path().to_string_lossy())?;
+    std::fs::read_to_string(copy.path())
+        .map_err(|e| anyhow::anyhow!("Error reading formatted source: {}", e))
+}
+
+/// Check which KCL files are not formatted, without modifying them.
+///
+/// Each file is printed formatted with `kcl fmt --std_output` and compared with
+/// its current content.
+///
+/// # Arguments
+/// * `bin` - Name or path of the `kcl` binary.
+/// * `path` - KCL file, or directory whose `*.k` files are checked.
+/// * `recursive` - Also check the files in subdirectories of a directory.
+///
+/// # Returns
+/// * `Ok(Vec<String>)` with the files that would change (empty if all are formatted).
+/// * `Err(anyhow::Error)` if a file cannot be read or `kcl fmt` fails.
+pub fn check_kcl_format(bin: &str, path: &str, recursive: bool) -> Result<Vec<String>> {
+    let mut unformatted = Vec::new();
+    for file in kcl_files(path, recursive)? {
+        let current = std::fs::read_to_string(&file)
+            .map_err(|e| anyhow::anyhow!("Error reading {}: {}", file, e))?;
+        let output = kcl_command(bin)
+            .arg("fmt")
+            .arg(&file)
+            .arg("--std_output")
+            .output()
+            .map_err(|e| exec_error(bin, "fmt", e))?;
+        if !output.status.success() {
+            return Err(anyhow::anyhow!(
+                "KCL format failed: {}",
+                strip_ansi(&String::from_utf8_lossy(&output.stderr))
+            ));
+        }
+        if strip_ansi(&String::from_utf8_lossy(&output.stdout)) != current {
+            unformatted.push(file);
+        }
+    }
+    Ok(unformatted)
+}
+
+/// Unified diff of the changes `kcl fmt` would make, without modifying any file.
+///
+/// Each file is copied to a temporary `.k` file which is formatted in its place.
+///
+/// # Arguments
+/// * `bin` - Name or path of the `kcl` binary.
+/// * `path` - KCL file, or directory whose `*.k` files are diffed.
+/// * `recursive` - Also diff the files in subdirectories of a directory.
+///
+/// # Returns
+/// * `Ok(String)` with the diffs of every file that would change (empty if all are formatted).
+/// * `Err(anyhow::Error)` if a file cannot be read or `kcl fmt` fails.
+pub fn format_kcl_diff(bin: &str, path: &str, recursive: bool) -> Result<String> {
+    Ok(format_kcl_diffs(bin, path, recursive)?
+        .into_iter()
+        .map(|(_, diff)| diff)
+        .collect())
+}
+
+/// Like `format_kcl_diff`, but with one `(file, diff)` pair per file that would change.
+pub fn format_kcl_diffs(bin: &str, path: &str, recursive: bool) -> Result<Vec<(String, String)>> {
+    let mut diffs = Vec::new();
+    for file in kcl_files(path, recursive)? {
+        let current = std::fs::read_to_string(&file)
+            .map_err(|e| anyhow::anyhow!("Error reading {}: {}", file, e))?;
+        let formatted = format_kcl_source(bin, &current)?;
+        if formatted != current {
+            let diff = similar::TextDiff::from_lines(&current, &formatted)
+                .unified_diff()
+                .header(&file, &file)
+                .to_string();
+            diffs.push((file, diff));
+        }
+    }
+    Ok(diffs)
+}
+
+/// List the `*.k` files of a directory, or the file itself if `path` is not a directory.
+///
+/// # Arguments
+/// * `path` - KCL file or directory.
+/// * `recursive` - Walk subdirectories too, instead of only the top level.
+///
+/// # Returns
+/// * `Ok(Vec<String>)` with the files found, sorted by path.
+/// * `Err(anyhow::Error)` if the directory cannot be walked.
+pub fn kcl_files(path: &str, recursive: bool) -> Result<Vec<String>> {
+    if !std::path::Path::new(path).is_dir() {
+        return Ok(vec![path.to_string()]);
+    }
+    let mut walker = WalkDir::new(path).sort_by_file_name();
+    if !recursive {
+        walker = walker.max_depth(1);
+    }
+    let mut files = Vec::new();
+    for entry in walker {
+        let entry = entry.map_err(|e| anyhow::anyhow!("Error walking {}: {}", path, e))?;
+        if entry.file_type().is_file() && entry.path().extension().is_some_and(|ext| ext == "k") {
+            files.push(entry.path().to_string_lossy().into_owned());
+        }
+    }
+    Ok(files)
+}
+
+/// Modes accepted by `kcl import --mode`.
+pub const IMPORT_MODES: &[&str] = &[
+    "json",
+    "yaml",
+    "jsonschema",
+    "protobuf",
+    "openapi",
+    "crd",
+    "auto",
+];
+
+/// Convert a JSON/YAML/OpenAPI/CRD file into KCL using `kcl import`.
+///
+/// # Arguments
+/// * `bin` - Name or path of the `kcl` binary.
+/// * `file` - Path to the file to import.
+/// * `mode` - Import mode, one of `IMPORT_MODES`.
+/// * `output_dir` - Directory to generate the KCL files in (defaults to the current directory).
+///
+/// # Returns
+/// * `Ok(Vec<String>)` with the paths of the `.k` files created or updated by the import.
+/// * `Err(anyhow::Error)` if the mode is unknown or the KCL command fails.
+pub fn import_kcl(
+    bin: &str,
+    file: &str,
+    mode: &str,
+    output_dir: Option<&str>,
+) -> Result<Vec<String>> {
+    if !IMPORT_MODES.contains(&mode) {
+        return Err(anyhow::anyhow!(
+            "Unknown import mode '{}' (expected one of: {})",
+            mode,
+            IMPORT_MODES.join(", ")
+        ));
+    }
+    let dir = output_dir.unwrap_or(".");
+    let before = modified_times(&find_files(dir, &["*.k"], None)?);
+
+    let mut cmd = kcl_command(bin);
+    cmd.arg("import").arg("--mode").arg(mode);
+    if let Some(output_dir) = output_dir {
+        cmd.arg("--output").arg(output_dir);
+    }
+    cmd.arg(file);
+
+    let output = cmd.output().map_err(|e| exec_error(bin, "import", e))?;
+
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "❌: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    let after = modified_times(&find_files(dir, &["*.k"], None)?);
+    let mut generated: Vec<String> = after
+        .into_iter()
+        .filter(|(path, modified)| before.get(path) != Some(modified))
+        .map(|(path, _)| path)
+        .collect();
+    generated.sort();
+    Ok(generated)
+}
+
+/// Convert a file into KCL with `kcl import` and return the generated source.
+///
+/// The import runs into a temporary directory, so no file is left behind. When KCL
+/// generates several files, their sources are joined in path order.
+///
+/// # Returns
+/// * `Ok(String)` with the generated KCL source.
+/// * `Err(anyhow::Error)` if the mode is unknown, the KCL command fails or generates nothing.
+pub fn import_kcl_source(bin: &str, file: &str, mode: &str) -> Result<String> {
+    let dir = tempfile::tempdir()?;
+    let generated = import_kcl(bin, file, mode, Some(&dir.path().to_string_lossy()))?;
+    if generated.is_empty() {
+        return Err(anyhow::anyhow!(
+            "❌ kcl import generated no KCL from {}",
+            file
+        ));
+    }
+    let sources = generated
+        .iter()
+        .map(|path| {
+            std::fs::read_to_string(path)
+                .map_err(|e| anyhow::anyhow!("Error reading {}: {}", path, e))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(sources.join("\n"))
+}
+
+/// Targets supported by `export_kcl`.
+pub const EXPORT_TARGETS: &[&str] = &["openapi"];
+
+/// Export the schemas of a KCL package to another format with `kcl doc generate`.
+///
+/// KCL generates the spec into a temporary directory, so no file is left behind.
+///
+/// # Arguments
+/// * `bin` - Name or path of the `kcl` binary.
+/// * `package` - Root directory of the KCL package, or a KCL file of it (its directory is used).
+/// * `target` - One of `EXPORT_TARGETS`.
+///
+/// # Returns
+/// * `Ok(String)` with the generated spec (JSON for `openapi`).
+/// * `Err(anyhow::Error)` if the target is unknown, the KCL command fails or generates nothing.
+pub fn export_kcl(bin: &str, package: &str, target: &str) -> Result<String> {
+    if !EXPORT_TARGETS.contains(&target) {
+        return Err(anyhow::anyhow!(
+            "Unknown export target '{}' (expected one of: {})",
+            target,
+            EXPORT_TARGETS.join(", ")
+        ));
+    }
+    let path = std::path::Path::new(package);
+    let package = match path.is_file() {
+        true => path.parent().unwrap_or(std::path::Path::new(".")),
+        false => path,
+    };
+    let dir = tempfile::tempdir()?;
+    let output = kcl_command(bin)
+        .args(["doc", "generate", "--format", target, "--file-path"])
+        .arg(package)
+        .arg("--target")
+        .arg(dir.path())
+        .output()
+        .map_err(|e| exec_error(bin, "doc", e))?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "❌: {}",
+            strip_ansi(&String::from_utf8_lossy(&output.stderr))
+        ));
+    }
+
+    let mut generated = find_files(&dir.path().to_string_lossy(), &["*.json"], None)?;
+    generated.sort();
+    let Some(spec) = generated.first() else {
+        return Err(anyhow::anyhow!(
+            "❌ kcl doc generated no {} spec from {}",
+            target,
+            package.display()
+        ));
+    };
+    std::fs::read_to_string(spec).map_err(|e| anyhow::anyhow!("Error reading {}: {}", spec, e))
+}
+
+/// Actions of `kcl mod` supported by `kcl_mod`.
+pub const MOD_ACTIONS: &[&str] = &["add", "update", "metadata"];
+
+/// Manage the dependencies of a KCL package with `kcl mod`.
+///
+/// Runs `kcl mod add <package>`, `kcl mod update` or `kcl mod metadata` in `dir`, then
+/// reads the package's `kcl.mod` to report its dependencies as they are afterwards.
+///
+/// # Arguments
+/// * `bin` - Name or path of the `kcl` binary.
+/// * `action` - One of `MOD_ACTIONS`.
+/// * `package` - Package to add (e.g. `k8s:1.28`), required by `add` only.
+/// * `dir` - Directory of the KCL package (holding `kcl.mod`).
+///
+/// # Returns
+/// * `Ok(serde_json::Value)` with the `package` and `dependencies` tables of `kcl.mod`.
+/// * `Err(anyhow::Error)` if the action is unknown, the KCL command fails or `kcl.mod` cannot be read.
+pub fn kcl_mod(
+    bin: &str,
+    action: &str,
+    package: Option<&str>,
+    dir: &str,
+) -> Result<serde_json::Value> {
+    if !MOD_ACTIONS.contains(&action) {
+        return Err(anyhow::anyhow!(
+            "Unknown mod action '{}' (expected one of: {})",
+            action,
+            MOD_ACTIONS.join(", ")
+        ));
+    }
+    let mut cmd = kcl_command(bin);
+    cmd.arg("mod").arg(action).current_dir(dir);
+    match (action, package) {
+        ("add", Some(package)) => {
+            cmd.arg(package);
+        }
+        ("add", None) => return Err(anyhow::anyhow!("kcl mod add needs a package to add")),
+        _ => {}
+    }
+
+    let output = cmd.output().map_err(|e| exec_error(bin, "mod", e))?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "❌: {}",
+            strip_ansi(&String::from_utf8_lossy(&output.stderr))
+        ));
+    }
+    read_kcl_mod(dir)
+}
+
+/// Directory of the KCL package `path` belongs to, if it holds a `kcl.mod`.
+///
+/// That is `path` itself for a directory, or the directory of a file.
+pub fn kcl_package_dir(path: &std::path::Path) -> Option<std::path::PathBuf> {
+    let dir = match path.is_dir() {
+        true => path,
+        false => path.parent()?,
+    };
+    dir.join("kcl.mod").is_file().then(|| dir.to_path_buf())
+}
+
+/// Root of the KCL package `path` is part of: the nearest directory holding `kcl.mod`,
+/// starting at `path` (or the directory of a file) and walking up its parents.
+pub fn find_package_root(path: &std::path::Path) -> Option<std::path::PathBuf> {
+    let dir = match path.is_dir() {
+        true => path,
+        false => path.parent()?,
+    };
+    dir.ancestors()
+        .find(|dir| dir.join("kcl.mod").is_file())
+        .map(std::path::Path::to_path_buf)
+}
+
+/// Read the `package` and `dependencies` tables of the `kcl.mod` file in `dir`.
+///
+/// A dependency is either a version string or a table (e.g. `{ git = "...", tag = "v0.1.0" }`).
+/// A missing `[dependencies]` table gives an empty one.
+///
+/// # Returns
+/// * `Ok(serde_json::Value)` with `package` and `dependencies` objects.
+/// * `Err(anyhow::Error)` if `kcl.mod` cannot be read or is not valid TOML.
+pub fn read_kcl_mod(dir: &str) -> Result<serde_json::Value> {
+    let path = std::path::Path::new(dir).join("kcl.mod");
+    let text = std::fs::read_to_string(&path)
+        .map_err(|e| anyhow::anyhow!("Error reading {}: {}", path.display(), e))?;
+    let mut manifest: serde_json::Map<String, serde_json::Value> =
+        toml::from_str(&text).map_err(|e| anyhow::anyhow!("Invalid {}: {}", path.display(), e))?;
+    let mut table = |key: &str| {
+        manifest
+            .remove(key)
+            .unwrap_or_else(|| serde_json::Value::Object(serde_json::Map::new()))
+    };
+    Ok(serde_json::json!({
+        "package": table("package"),
+        "dependencies": table("dependencies"),
+    }))
+}
+
+/// Map each file to its last modification time, skipping files that cannot be read.
+fn modified_times(files: &[String]) -> HashMap<String, SystemTime> {
+    files
+        .iter()
+        .filter_map(|file| {
+            let modified = std::fs::metadata(file).and_then(|m| m.modified()).ok()?;
+            Some((file.clone(), modified))
+        })
+        .collect()
+}
+
+/// Result of a single test case reported by `kcl test`.
+#[derive(Debug)]
+pub struct KclTestCase {
+    /// Name of the test function.
+    pub name: String,
+    /// Whether the test passed.
+    pub passed: bool,
+    /// Duration reported by KCL (e.g., "2ms"), if any.
+    pub duration: Option<String>,
+}
+
+/// Run the KCL tests (`*_test.k` files) in a directory using the KCL CLI.
+///
+/// # Arguments
+/// * `bin` - Name or path of the `kcl` binary.
+/// * `dir` - Path to the directory (package) holding the tests.
+/// * `run` - Optional regex passed through as `--run` to select which tests run.
+///
+/// # Returns
+/// * `Ok(Vec<KclTestCase>)` with one entry per executed test, passing or failing.
+/// * `Err(anyhow::Error)` if the KCL command fails without reporting any test results.
+pub fn run_kcl_tests(bin: &str, dir: &str, run: Option<&str>) -> Result<Vec<KclTestCase>> {
+    let mut cmd = kcl_command(bin);
+    cmd.arg("test").arg(dir);
+    if let Some(run) = run {
+        cmd.arg("--run").arg(run);
+    }
+
+    let output = cmd.output().map_err(|e| exec_error(bin, "test", e))?;
+
+    let cases = parse_test_output(&String::from_utf8_lossy(&output.stdout));
+    if cases.is_empty() && !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "❌: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Ok(cases)
+}
+
+/// Parse the per-test lines printed by `kcl test`.
+///
+/// Each test is reported as `<name>: PASS (<duration>)` or `<name>: FAIL (<duration>)`;
+/// any other line (failure details, separators, totals) is ignored.
+pub fn parse_test_output(text: &str) -> Vec<KclTestCase> {
+    text.lines()
+        .filter_map(|line| {
+            let (name, rest) = line.trim().split_once(": ")?;
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                return None;
+            }
+            let passed = match rest.split_whitespace().next()? {
+                "PASS" => true,
+                "FAIL" => false,
+                _ => return None,
+            };
+            let duration = rest
+                .split_once('(')
+                .and_then(|(_, d)| d.strip_suffix(')'))
+                .map(|d| d.to_string());
+            Some(KclTestCase {
+                name: name.to_string(),
+                passed,
+                duration,
+            })
+        })
+        .collect()
+}
+
+/// Find files in a directory whose names match any of the given patterns.
+///
+/// Subdirectories are walked too. Entries that cannot be read (e.g. a missing `dir`)
+/// are skipped, so they give no files rather than an error.
+///
+/// # Arguments
+/// * `dir` - Path to the directory to search.
+/// * `patterns` - File name globs (e.g., `["*.k"]`).
+/// * `skip_dirs` - If set, subdirectories hidden or named in it are not walked, see `is_skipped_dir`.
+///
+/// # Returns
+/// * `Ok(Vec<String>)` with the paths of the matching files, sorted by path.
+/// * `Err(anyhow::Error)` if a pattern is invalid.
+fn find_files(dir: &str, patterns: &[&str], skip_dirs: Option<&[String]>) -> Result<Vec<String>> {
+    let patterns = patterns
+        .iter()
+        .map(|pattern| {
+            glob::Pattern::new(pattern)
+                .map_err(|e| anyhow::anyhow!("Invalid pattern '{}': {}", pattern, e))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(WalkDir::new(dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.depth() == 0
+                || !entry.file_type().is_dir()
+                || !skip_dirs
+                    .is_some_and(|skip| is_skipped_dir(&entry.file_name().to_string_lossy(), skip))
+        })
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .filter(|entry| {
+            let name = entry.file_name().to_string_lossy();
+            patterns.iter().any(|pattern| pattern.matches(&name))
+        })
+        .map(|entry| entry.path().to_string_lossy().into_owned())
+        .collect())
+}
+
+/// Build the `kcl vet` command checking a data file against a KCL schema file.
+///
+/// When no format is given, YAML data files (`.yaml`/`.yml`) are passed with
+/// `--format yaml` and anything else is vetted as JSON, which is the `kcl vet` default.
+fn vet_command(
+    bin: &str,
+    data_file: &str,
+    schema_file: &str,
+    schema_name: Option<&str>,
+    format: Option<&str>,
+) -> Command {
+    let mut cmd = kcl_command(bin);
+    cmd.arg("vet").arg(data_file).arg(schema_file);
+    if let Some(schema_name) = schema_name {
+        cmd.arg("--schema").arg(schema_name);
+    }
+    match format {
+        Some(format) => {
+            cmd.arg("--format").arg(format);
+        }
+        None if data_file.ends_with(".yaml") || data_file.ends_with(".yml") => {
+            cmd.arg("--format").arg("yaml");
+        }
+        None => {}
+    }
+    cmd
+}
+
+/// Outcome of vetting a data file against a KCL schema.
+#[derive(Debug, Default)]
+pub struct VetResult {
+    /// True if the data conforms to the schema.
+    pub valid: bool,
+    /// Errors reported by KCL, one per diagnostic.
+    pub errors: Vec<String>,
+}
+
+/// Validate a JSON/YAML data file against a KCL schema using `kcl vet`.
+///
+/// # Arguments
+/// * `bin` - Name or path of the `kcl` binary.
+/// * `data_file` - Path to the data file to check.
+/// * `schema_file` - Path to the KCL file defining the schema.
+/// * `schema_name` - Optional schema to validate against (passed as `--schema`).
+/// * `format` - Optional data format ("json" or "yaml"), detected from the extension otherwise.
+///
+/// # Returns
+/// * `Ok(VetResult)` with `valid` set and the KCL errors if the data does not conform.
+/// * `Err(anyhow::Error)` if the KCL command cannot be executed.
+pub fn vet_kcl_data(
+    bin: &str,
+    data_file: &str,
+    schema_file: &str,
+    schema_name: Option<&str>,
+    format: Option<&str>,
+) -> Result<VetResult> {
+    let output = vet_command(bin, data_file, schema_file, schema_name, format)
+        .output()
+        .map_err(|e| exec_error(bin, "vet", e))?;
+
+    if output.status.success() {
+        return Ok(VetResult {
+            valid: true,
+            errors: Vec::new(),
+        });
+    }
+    let stderr = strip_ansi(&String::from_utf8_lossy(&output.stderr));
+    let mut errors: Vec<String> = parse_diagnostics(&stderr)
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    if errors.is_empty() && !stderr.trim().is_empty() {
+        errors.push(stderr.trim().to_string());
+    }
+    Ok(VetResult {
+        valid: false,
+        errors,
+    })
+}
+
+/// Validation outcome for a single file.
+#[derive(Debug)]
+pub struct FileValidation {
+    /// Path of the validated file.
+    pub file: String,
+    /// Error reported by KCL, or `None` if the file is valid.
+    pub error: Option<String>,
+    /// True if the error matched an allowlisted pattern and counts as a pass.
+    pub ignored: bool,
+}
+
+impl FileValidation {
+    /// Returns true if KCL reported no error for the file.
+    pub fn is_valid(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// Returns true if the file failed with an error that is not ignored.
+    pub fn is_invalid(&self) -> bool {
+        self.error.is_some() && !self.ignored
+    }
+
+    /// Status of the file: `"valid"`, `"ignored"` or `"invalid"`.
+    pub fn status(&self) -> &'static str {
+        if self.is_valid() {
+            "valid"
+        } else if self.ignored {
+            "ignored"
+        } else {
+            "invalid"
+        }
+    }
+}
+
+/// Mark failures whose KCL error matches `pattern` as ignored.
+///
+/// Ignored files keep their error message but count as a pass, so known-benign
+/// failures (e.g. library files that error when run directly) can be allowlisted.
+pub fn ignore_errors_matching(results: &mut [FileValidation], pattern: &Regex) {
+    for result in results.iter_mut() {
+        if let Some(error) = &result.error
+            && pattern.is_match(error)
+        {
+            result.ignored = true;
+        }
+    }
+}
+
+/// Validate all KCL files in a directory using the KCL CLI.
+///
+/// Without a schema, every `*.k` file is checked with `kcl lint`, which compiles the
+/// whole file (including schemas it never instantiates) without executing it.
+/// With a schema, every JSON/YAML data file is checked against it with `kcl vet`.
+///
+/// # Arguments
+/// * `bin` - Name or path of the `kcl` binary.
+/// * `dir` - Path to the directory to search for files.
+/// * `schema` - Optional KCL schema file to vet data files against.
+/// * `timeout` - Optional per-file limit after which the KCL process is killed.
+/// * `jobs` - Number of files checked concurrently (`0` uses the number of CPUs).
+/// * `interrupt` - Optional flag that stops launching KCL processes and kills the running ones once set.
+///
+/// # Returns
+/// * `Ok(Vec<FileValidation>)` with one result per file found, sorted by path (empty if none were found).
+///   When interrupted, only the files checked before the interrupt are returned.
+/// * `Err(anyhow::Error)` if the directory cannot be searched or the KCL CLI is not installed.
+pub fn validate_kcl_project(
+    bin: &str,
+    dir: &str,
+    schema: Option<&str>,
+    timeout: Option<Duration>,
+    jobs: usize,
+    interrupt: Option<&AtomicBool>,
+) -> Result<Vec<FileValidation>> {
+    validate_kcl_project_with(&CliRunner, bin, dir, schema, timeout, jobs, interrupt)
+}
+
+/// Like `validate_kcl_project`, running KCL through `runner`.
+pub fn validate_kcl_project_with(
+    runner: &dyn KclRunner,
+    bin: &str,
+    dir: &str,
+    schema: Option<&str>,
+    timeout: Option<Duration>,
+    jobs: usize,
+    interrupt: Option<&AtomicBool>,
+) -> Result<Vec<FileValidation>> {
+    let skip_dirs: Vec<String> = SKIPPED_DIRS.iter().map(|dir| dir.to_string()).collect();
+    let files = select_files(dir, schema, &[], &[], Some(&skip_dirs))?;
+    let checker = FileChecker {
+        runner,
+        bin,
+        check: FileCheck::new(schema, true),
+        timeout,
+        cwd: None,
+        ignore: None,
+    };
+    validate_files(&checker, files, jobs, interrupt, false, None)
+}
+
+/// How `validate_file` checks a file.
+#[derive(Debug, Clone, Copy)]
+enum FileCheck<'a> {
+    /// Compile it with `kcl lint`, without executing it.
+    Lint,
+    /// Evaluate it with `kcl run`, also catching runtime errors such as failing `check` blocks.
+    Run,
+    /// Vet a data file against this schema with `kcl vet`.
+    Vet(&'a str),
+}
+
+impl<'a> FileCheck<'a> {
+    fn new(schema: Option<&'a str>, compile_only: bool) -> Self {
+        match schema {
+            Some(schema) => FileCheck::Vet(schema),
+            None if compile_only => FileCheck::Lint,
+            None => FileCheck::Run,
+        }
+    }
+}
+
+/// List the files of `dir` to validate, sorted by path.
+///
+/// If `dir` is a file, only that file is selected, whatever the globs. Without `include` globs, every `*.k` file (or JSON/YAML data file with a schema) is
+/// selected, otherwise the files matching any of them. Globs are relative to `dir` (e.g. `**/prod-*.k`), and a file matching any
+/// `exclude` glob is skipped even if it matches `include`. An exclude without a `/` (e.g. `*_test.k`)
+/// is also matched against the file name, so it applies at any depth. With `skip_dirs`, the files
+/// under hidden directories or directories named in it are skipped too, see `is_skipped_dir`,
+/// unless an `include` glob names the directory literally.
+///
+/// # Returns
+/// * `Ok(Vec<String>)` with the selected files.
+/// * `Err(anyhow::Error)` if a glob is invalid or the files cannot be listed.
+pub fn select_files(
+    dir: &str,
+    schema: Option<&str>,
+    include: &[String],
+    exclude: &[String],
+    skip_dirs: Option<&[String]>,
+) -> Result<Vec<String>> {
+    if std::path::Path::new(dir).is_file() {
+        return Ok(vec![dir.to_string()]);
+    }
+    let mut files: Vec<String> = if include.is_empty() {
+        let patterns: &[&str] = match schema {
+            Some(_) => &["*.json", "*.yaml", "*.yml"],
+            None => &["*.k"],
+        };
+        find_files(dir, patterns, skip_dirs)?
+    } else {
+        let mut files = Vec::new();
+        for pattern in include {
+            let pattern = std::path::Path::new(dir).join(pattern);
+            // Directories named literally in the glob (e.g. `.github/*.k`) are never skipped.
+            let base: std::path::PathBuf = pattern
+                .components()
+                .take_while(|part| !is_glob(&part.as_os_str().to_string_lossy()))
+                .collect();
+            files.extend(
+                glob::glob(&pattern.to_string_lossy())
+                    .map_err(|e| anyhow::anyhow!("Invalid pattern '{}': {}", pattern.display(), e))?
+                    .filter_map(|entry| entry.ok())
+                    .filter(|path| path.is_file())
+                    .filter(|path| {
+                        let relative = path.strip_prefix(&base).unwrap_or(path);
+                        !skip_dirs.is_some_and(|skip| {
+                            relative
+                                .parent()
+                                .into_iter()
+                                .flat_map(|parent| parent.components())
+                                .any(|part| match part {
+                                    std::path::Component::Normal(name) => {
+                                        is_skipped_dir(&name.to_string_lossy(), skip)
+                                    }
+                                    _ => false,
+                                })
+                        })
+                    })
+                    .map(|path| path.to_string_lossy().into_owned()),
+            );
+        }
+        files
+    };
+    let exclude = exclude
+        .iter()
+        .map(|pattern| {
+            glob::Pattern::new(pattern)
+                .map_err(|e| anyhow::anyhow!("Invalid exclude pattern '{}': {}", pattern, e))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let options = glob::MatchOptions {
+        require_literal_separator: true,
+        ..glob::MatchOptions::new()
+    };
+    files.retain(|file| {
+        let path = std::path::Path::new(file);
+        let relative = path.strip_prefix(dir).unwrap_or(path);
+        let name = path.file_name().map(std::path::Path::new);
+        !exclude.iter().any(|pattern| {
+            pattern.matches_path_with(relative, options)
+                || (!pattern.as_str().contains('/')
+                    && name.is_some_and(|name| pattern.matches_path_with(name, options)))
+        })
+    });
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
+
+/// Directories `validate_kcl` does not descend into by default, besides hidden ones:
+/// the KCL module cache and build output holding vendored dependencies.
+pub const SKIPPED_DIRS: &[&str] = &[".kclvm", "target"];
+
+/// Whether a directory named `name` is skipped when looking for files to validate:
+/// it is hidden (e.g. `.git`) or one of `skip_dirs`.
+pub fn is_skipped_dir(name: &str, skip_dirs: &[String]) -> bool {
+    (name.starts_with('.') && name != "." && name != "..")
+        || skip_dirs.iter().any(|dir| dir == name)
+}
+
+/// Drop the files of `dir` that `.gitignore`, `.ignore` or `.git/info/exclude` rules ignore.
+///
+/// Rules are read from `dir` and its parents, whether or not `dir` is in a git repository.
+/// Files outside `dir` are dropped too, as the walk only sees files under it.
+pub fn retain_not_ignored(dir: &str, files: &mut Vec<String>) {
+    let root = match dir {
+        "" => ".",
+        dir => dir,
+    };
+    let visible: HashSet<std::path::PathBuf> = ignore::WalkBuilder::new(root)
+        .hidden(false)
+        .require_git(false)
+        .build()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_some_and(|t| t.is_file()))
+        .map(|entry| normalize_path(entry.path()))
+        .collect();
+    files.retain(|file| visible.contains(&normalize_path(std::path::Path::new(file))));
+}
+
+/// Drop `.` components so paths from different walkers compare equal.
+fn normalize_path(path: &std::path::Path) -> std::path::PathBuf {
+    path.components()
+        .filter(|component| !matches!(component, std::path::Component::CurDir))
+        .collect()
+}
+
+/// Whether `path` contains glob metacharacters (`*`, `?` or `[`), e.g. `config/**/*.k`.
+pub fn is_glob(path: &str) -> bool {
+    path.contains(['*', '?', '['])
+}
+
+/// Settings shared by the checks of a validation, see `validate_files`.
+struct FileChecker<'a> {
+    runner: &'a dyn KclRunner,
+    bin: &'a str,
+    check: FileCheck<'a>,
+    timeout: Option<Duration>,
+    cwd: Option<&'a str>,
+    ignore: Option<&'a Regex>,
+}
+
+/// Check `files` with up to `jobs` concurrent KCL processes, see `validate_kcl_project`.
+///
+/// With `fail_fast`, the first invalid file stops launching KCL processes and kills the
+/// running ones, and only the results up to that file (in path order) are returned.
+fn validate_files(
+    checker: &FileChecker,
+    files: Vec<String>,
+    jobs: usize,
+    interrupt: Option<&AtomicBool>,
+    fail_fast: bool,
+    progress: Option<&Progress>,
+) -> Result<Vec<FileValidation>> {
+    let jobs = match jobs {
+        0 => thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    }
+    .clamp(1, files.len().max(1));
+    // Workers pull the next file index from a shared counter, results are put back
+    // in path order afterwards so the output does not depend on scheduling.
+    let next = AtomicUsize::new(0);
+    // Set on interrupt or, with `fail_fast`, on the first invalid file.
+    let stop = AtomicBool::new(interrupt.is_some_and(|i| i.load(Ordering::Relaxed)));
+    let finished = AtomicBool::new(false);
+    let checked = AtomicUsize::new(0);
+    let mut results: Vec<(usize, Result<Option<FileValidation>>)> = thread::scope(|scope| {
+        if let Some(interrupt) = interrupt {
+            scope.spawn(|| {
+                while !finished.load(Ordering::Relaxed) {
+                    if interrupt.load(Ordering::Relaxed) {
+                        stop.store(true, Ordering::Relaxed);
+                    }
+                    thread::sleep(Duration::from_millis(20));
+                }
+            });
+        }
+        let workers: Vec<_> = (0..jobs)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+                    while !stop.load(Ordering::Relaxed) {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(file) = files.get(index) else {
+                            break;
+                        };
+                        let result = validate_file(checker, file, &stop);
+                        if let Some(progress) = progress {
+                            (progress.0)(checked.fetch_add(1, Ordering::Relaxed) + 1, files.len());
+                        }
+                        if fail_fast && matches!(&result, Ok(Some(result)) if result.is_invalid()) {
+                            stop.store(true, Ordering::Relaxed);
+                        }
+                        done.push((index, result));
+                    }
+                    done
+                })
+            })
+            .collect();
+        let results = workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("Validation worker panicked"))
+            .collect();
+        finished.store(true, Ordering::Relaxed);
+        results
+    });
+    results.sort_by_key(|(index, _)| *index);
+    let mut results = results
+        .into_iter()
+        .filter_map(|(_, result)| result.transpose())
+        .collect::<Result<Vec<_>>>()?;
+    if fail_fast && let Some(first) = results.iter().position(FileValidation::is_invalid) {
+        results.truncate(first + 1);
+    }
+    Ok(results)
+}
+
+/// Check a single file with `kcl lint`, `kcl run` or `kcl vet`, depending on `checker.check`.
+///
+/// Returns `Ok(None)` if the check was killed because `stop` was set.
+fn validate_file(
+    checker: &FileChecker,
+    file: &str,
+    stop: &AtomicBool,
+) -> Result<Option<FileValidation>> {
+    let bin = checker.bin;
+    let subcommand = match checker.check {
+        FileCheck::Lint => "lint",
+        FileCheck::Run => "run",
+        FileCheck::Vet(_) => "vet",
+    };
+    let mut cmd = match checker.check {
+        FileCheck::Vet(schema) => vet_command(bin, file, schema, None, None),
+        _ => {
+            let mut cmd = kcl_command(bin);
+            cmd.arg(subcommand).arg(file);
+            cmd
+        }
+    };
+    if let Some(cwd) = checker.cwd {
+        cmd.current_dir(cwd);
+    }
+    let error = match checker.runner.output(&mut cmd, checker.timeout, Some(stop)) {
+        Ok(Some(output)) if output.status.success() => None,
+        Ok(Some(output)) => Some(strip_ansi(&String::from_utf8_lossy(&output.stderr))),
+        Ok(None) if stop.load(Ordering::Relaxed) => return Ok(None),
+        Ok(None) => Some(format!(
+            "Timed out after {:?}",
+            checker.timeout.unwrap_or_default()
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(exec_error(bin, subcommand, e));
+        }
+        Err(e) => Some(format!("Execution error: {}", e)),
+    };
+    let mut result = FileValidation {
+        file: file.to_string(),
+        error,
+        ignored: false,
+    };
+    if let Some(pattern) = checker.ignore {
+        ignore_errors_matching(std::slice::from_mut(&mut result), pattern);
+    }
+    Ok(Some(result))
+}
+
+/// Number of files in each state after a validation.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ValidationCounts {
+    /// Files checked.
+    pub total: usize,
+    /// Files KCL reported no error for.
+    pub valid: usize,
+    /// Files with an error that was not ignored.
+    pub invalid: usize,
+    /// Files whose error matched an allowlisted pattern.
+    pub ignored: usize,
+}
+
+impl ValidationCounts {
+    /// No file is invalid; ignored failures count as passes.
+    pub fn all_valid(&self) -> bool {
+        self.invalid == 0
+    }
+}
+
+/// Count the valid, invalid and ignored files of validation results.
+pub fn validation_counts(results: &[FileValidation]) -> ValidationCounts {
+    ValidationCounts {
+        total: results.len(),
+        valid: results.iter().filter(|result| result.is_valid()).count(),
+        invalid: results.iter().filter(|result| result.is_invalid()).count(),
+        ignored: results.iter().filter(|result| result.ignored).count(),
+    }
+}
+
+/// Render validation results as a human-readable summary.
+///
+/// # Arguments
+/// * `dir` - Path of the validated directory, used when no files were found.
+/// * `schema` - Schema the data files were vetted against, if any.
+/// * `results` - Results returned by `validate_kcl_project`.
+///
+/// # Returns
+/// A summary line followed by one ✅/⚠/❌ line per file. Ignored files are counted separately.
+pub fn validation_summary(dir: &str, schema: Option<&str>, results: &[FileValidation]) -> String {
+    if results.is_empty() {
+        return match schema {
+            Some(_) => format!("No data files found in {}", dir),
+            None => format!("No KCL files found in {}", dir),
+        };
+    }
+
+    let lines: Vec<String> = results
+        .iter()
+        .map(|result| match &result.error {
+            None => format!("✅ {}", result.file),
+            Some(error) if result.ignored => format!("⚠ ignored {}: {}", result.file, error),
+            Some(error) => format!("❌ {}: {}", result.file, error),
+        })
+        .collect();
+
+    let ignored = results.iter().filter(|result| result.ignored).count();
+    let invalid = results.iter().any(FileValidation::is_invalid);
+    let summary = match (invalid, ignored) {
+        (false, 0) if results.len() == 1 => "✅ 1 file is valid".to_string(),
+        (false, 0) => format!("✅ All {} files are valid", results.len()),
+        (false, ignored) => format!(
+            "✅ {} valid, ⚠ {} ignored",
+            files_are(results.len() - ignored),
+            ignored
+        ),
+        (true, 0) => "❌ Errors found in some files".to_string(),
+        (true, ignored) => format!("❌ Errors found in some files (⚠ {} ignored)", ignored),
+    };
+
+    format!("{}\n\n{}", summary, lines.join("\n"))
+}
+
+/// "1 file is" or "N files are", for summaries.
+fn files_are(count: usize) -> String {
+    match count {
+        1 => "1 file is".to_string(),
+        n => format!("{} files are", n),
+    }
+}
+
+/// Render validation results as a JUnit XML report, one test case per file.
+///
+/// # Arguments
+/// * `results` - Results returned by `validate_kcl_project`.
+///
+/// # Returns
+/// The XML document, with invalid files reported as `<failure>` elements holding KCL's message
+/// and ignored files as `<skipped>` elements.
+pub fn junit_report(results: &[FileValidation]) -> String {
+    let failures = results.iter().filter(|result| result.is_invalid()).count();
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!(
+        "<testsuites tests=\"{}\" failures=\"{}\">\n",
+        results.len(),
+        failures
+    ));
+    xml.push_str(&format!(
+        "  <testsuite name=\"kcl-validate\" tests=\"{}\" failures=\"{}\">\n",
+        results.len(),
+        failures
+    ));
+    for result in results {
+        let name = xml_escape(&result.file);
+        match &result.error {
+            None => xml.push_str(&format!(
+                "    <testcase classname=\"kcl-validate\" name=\"{}\"/>\n",
+                name
+            )),
+            Some(error) if result.ignored => xml.push_str(&format!(
+                "    <testcase classname=\"kcl-validate\" name=\"{}\">\n      <skipped message=\"ignored\">{}</skipped>\n    </testcase>\n",
+                name,
+                xml_escape(error)
+            )),
+            Some(error) => xml.push_str(&format!(
+                "    <testcase classname=\"kcl-validate\" name=\"{}\">\n      <failure message=\"invalid KCL\">{}</failure>\n    </testcase>\n",
+                name,
+                xml_escape(error)
+            )),
+        }
+    }
+    xml.push_str("  </testsuite>\n</testsuites>\n");
+    xml
+}
+
+/// Render validation results as a JSON array of `{file, status, error}` objects.
+///
+/// # Arguments
+/// * `results` - Results returned by `validate_kcl_project`.
+pub fn json_report(results: &[FileValidation]) -> Result<String> {
+    let rows: Vec<serde_json::Value> = results
+        .iter()
+        .map(|result| {
+            serde_json::json!({
+                "file": result.file,
+                "status": result.status(),
+                "error": result.error,
+            })
+        })
+        .collect();
+    Ok(serde_json::to_string_pretty(&rows)?)
+}
+
+/// Escape the XML special characters of a text or attribute value.
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Options for `run_kcl`, built with chained setters.
+///
+/// ```no_run
+/// use nu_plugin_kcl::helpers::{RunOptions, run_kcl};
+/// use std::time::Duration;
+///
+/// let opts = RunOptions::new("main.k")
+///     .file("prod.k")
+///     .format("json")
+///     .define("env=prod")
+///     .timeout(Some(Duration::from_secs(30)));
+/// let documents = run_kcl(opts)?.documents()?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct RunOptions {
+    /// Name or path of the `kcl` binary.
+    pub bin: String,
+    /// KCL files compiled together into one program.
+    pub files: Vec<String>,
+    /// Output format, one of `OUTPUT_FORMATS`.
+    pub format: String,
+    /// Variable definitions passed as `-D key=value`.
+    pub defines: Vec<String>,
+    /// Overrides passed as `-O path=value`.
+    pub overrides: Vec<String>,
+    /// Settings files passed as `-Y <file>`, in order.
+    pub settings: Vec<String>,
+    /// Sort the keys of the output (`--sort_keys`).
+    pub sort_keys: bool,
+    /// Omit attributes whose value is `None` from the output (`--disable_none`).
+    pub disable_none: bool,
+    /// Paths of the values to output instead of the whole program (`-S`), e.g. `spec.replicas`.
+    pub path_selectors: Vec<String>,
+    /// Check the rendered output parses in `format` (only used by `run_kcl_command`).
+    pub validate_output: bool,
+    /// Keep the ANSI escape sequences (colors) KCL prints instead of stripping them.
+    pub color: bool,
+    /// File to write the output to instead of stdout.
+    pub output: Option<String>,
+    /// Working directory of the KCL process.
+    pub cwd: Option<String>,
+    /// Run KCL from the package root of the first file instead of `cwd`, see `in_package_root`.
+    pub package_root: bool,
+    /// Environment variables set for the KCL process, on top of the inherited ones.
+    pub env: Vec<(String, String)>,
+    /// Limit after which the KCL process is killed.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        Self {
+            bin: kcl_binary(),
+            files: Vec::new(),
+            format: "yaml".to_string(),
+            defines: Vec::new(),
+            overrides: Vec::new(),
+            settings: Vec::new(),
+            sort_keys: false,
+            disable_none: false,
+            path_selectors: Vec::new(),
+            validate_output: false,
+            color: false,
+            output: None,
+            cwd: None,
+            package_root: false,
+            env: Vec::new(),
+            timeout: None,
+        }
+    }
+}
+
+impl RunOptions {
+    /// Options to run a single KCL file with the defaults (`kcl_binary()`, YAML output).
+    pub fn new(file: impl Into<String>) -> Self {
+        Self::default().file(file)
+    }
+
+    /// Set the `kcl` binary to run.
+    pub fn bin(mut self, bin: impl Into<String>) -> Self {
+        self.bin = bin.into();
+        self
+    }
+
+    /// Add a KCL file to the program.
+    pub fn file(mut self, file: impl Into<String>) -> Self {
+        self.files.push(file.into());
+        self
+    }
+
+    /// Set the output format, one of `OUTPUT_FORMATS` (`yaml`, `json`, `toml` or `ndjson`).
+    pub fn format(mut self, format: impl Into<String>) -> Self {
+        self.format = format.into();
+        self
+    }
+
+    /// Add a `key=value` variable definition.
+    pub fn define(mut self, define: impl Into<String>) -> Self {
+        self.defines.push(define.into());
+        self
+    }
+
+    /// Add several `key=value` variable definitions.
+    pub fn defines<I, S>(mut self, defines: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.defines.extend(defines.into_iter().map(Into::into));
+        self
+    }
+
+    /// Add several `path=value` overrides.
+    pub fn overrides<I, S>(mut self, overrides: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.overrides.extend(overrides.into_iter().map(Into::into));
+        self
+    }
+
+    /// Add several settings files (e.g. `kcl.yaml`), passed in order.
+    pub fn settings<I, S>(mut self, settings: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.settings.extend(settings.into_iter().map(Into::into));
+        self
+    }
+
+    /// Sort the keys of the output, for deterministic diffs.
+    pub fn sort_keys(mut self, sort_keys: bool) -> Self {
+        self.sort_keys = sort_keys;
+        self
+    }
+
+    /// Omit `None` valued attributes, for tools that reject null keys.
+    pub fn disable_none(mut self, disable_none: bool) -> Self {
+        self.disable_none = disable_none;
+        self
+    }
+
+    /// Only output the value at this path (e.g. `spec.replicas` or `pkg:app.name`).
+    pub fn path_selector(mut self, selector: impl Into<String>) -> Self {
+        self.path_selectors.push(selector.into());
+        self
+    }
+
+    /// Only output the values at these paths.
+    pub fn path_selectors<I, S>(mut self, selectors: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.path_selectors
+            .extend(selectors.into_iter().map(Into::into));
+        self
+    }
+
+    /// Set an environment variable for the KCL process, e.g. read by a KCL plugin.
+    pub fn env_var(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.env.push((key.into(), value.into()));
+        self
+    }
+
+    /// Set these environment variables for the KCL process, later pairs win.
+    pub fn env<I, K, V>(mut self, env: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.env.extend(
+            env.into_iter()
+                .map(|(key, value)| (key.into(), value.into())),
+        );
+        self
+    }
+
+    /// Check the rendered output is well-formed in its format.
+    pub fn validate_output(mut self, validate_output: bool) -> Self {
+        self.validate_output = validate_output;
+        self
+    }
+
+    /// Keep the ANSI escape sequences in KCL's output and errors.
+    pub fn color(mut self, color: bool) -> Self {
+        self.color = color;
+        self
+    }
+
+    /// Write the output to this file instead of stdout.
+    pub fn output(mut self, output: impl Into<String>) -> Self {
+        self.output = Some(output.into());
+        self
+    }
+
+    /// Run KCL in this working directory.
+    pub fn cwd(mut self, cwd: impl Into<String>) -> Self {
+        self.cwd = Some(cwd.into());
+        self
+    }
+
+    /// Kill the KCL process after this long (`None` waits forever).
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Run KCL from the root of the package (`kcl.mod`) the first file is part of, so
+    /// its imports of sibling packages resolve.
+    pub fn package_root(mut self, package_root: bool) -> Self {
+        self.package_root = package_root;
+        self
+    }
+
+    /// These options as they run with `package_root`: from the package root of the first
+    /// file, with relative files, settings and output resolved against `cwd` beforehand.
+    ///
+    /// Unchanged without `package_root`, or if the file is not part of a package.
+    pub fn in_package_root(&self) -> RunOptions {
+        let base = std::path::Path::new(self.cwd.as_deref().unwrap_or(""));
+        let root = match self.files.first() {
+            Some(file) if self.package_root => find_package_root(&base.join(file)),
+            _ => None,
+        };
+        let Some(root) = root else {
+            return self.clone();
+        };
+        let resolve = |path: &String| base.join(path).to_string_lossy().into_owned();
+        RunOptions {
+            files: self.files.iter().map(resolve).collect(),
+            settings: self.settings.iter().map(resolve).collect(),
+            output: self.output.as_ref().map(resolve),
+            cwd: Some(root.to_string_lossy().into_owned()),
+            ..self.clone()
+        }
+    }
+
+    /// Arguments passed to the `kcl` binary for these options, starting with `run`.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = vec!["run".to_string()];
+        args.extend(self.files.iter().cloned());
+        args.push("--format".to_string());
+        // KCL cannot render TOML or NDJSON, they are converted from JSON by `kcl_run`.
+        args.push(match self.format.as_str() {
+            "toml" | "ndjson" => "json".to_string(),
+            format => format.to_string(),
+        });
+        for settings_file in &self.settings {
+            args.push("-Y".to_string());
+            args.push(settings_file.clone());
+        }
+        for define in &self.defines {
+            args.push("-D".to_string());
+            args.push(define.clone());
+        }
+        for override_spec in &self.overrides {
+            args.push("-O".to_string());
+            args.push(override_spec.clone());
+        }
+        if self.sort_keys {
+            args.push("--sort_keys".to_string());
+        }
+        if self.disable_none {
+            args.push("--disable_none".to_string());
+        }
+        for selector in &self.path_selectors {
+            args.push("-S".to_string());
+            args.push(selector.clone());
+        }
+        if let Some(output_file) = &self.output {
+            args.push("-o".to_string());
+            args.push(output_file.clone());
+        }
+        args
+    }
+
+    /// Build the `kcl run` command for these options.
+    fn command(&self) -> Command {
+        let opts = self.in_package_root();
+        let mut cmd = kcl_command(&opts.bin);
+        cmd.args(opts.args());
+        if let Some(cwd) = &opts.cwd {
+            cmd.current_dir(cwd);
+        }
+        cmd.envs(opts.env.iter().map(|(key, value)| (key, value)));
+        cmd
+    }
+}
+
+/// Result of a successful `run_kcl`.
+#[derive(Debug, Clone)]
+pub struct KclRunResult {
+    /// KCL files that were run.
+    pub files: Vec<String>,
+    /// Output format the result was rendered in.
+    pub format: String,
+    /// Rendered output (empty when it was written to `output`).
+    pub stdout: String,
+    /// Warnings KCL printed to stderr.
+    pub stderr: String,
+    /// File the output was written to, if any.
+    pub output: Option<String>,
+    /// Wall-clock time of the KCL process.
+    pub duration: Duration,
+}
+
+impl KclRunResult {
+    /// Parse the rendered output (from `stdout` or the output file) into documents.
+    pub fn documents(&self) -> Result<Vec<serde_json::Value>> {
+        match &self.output {
+            Some(output_file) => {
+                let rendered = std::fs::read_to_string(output_file)
+                    .map_err(|e| anyhow::anyhow!("Error reading {}: {}", output_file, e))?;
+                parse_output(&rendered, &self.format)
+            }
+            None => parse_output(&self.stdout, &self.format),
+        }
+    }
+}
+
+/// Run KCL files with `kcl run`.
+///
+/// All files in `opts` are compiled together into one program, as `kcl run a.k b.k` does.
+///
+/// # Returns
+/// * `Ok(KclRunResult)` with the rendered output on success.
+/// * `Err(anyhow::Error)` if no file is given, or KCL fails or times out.
+pub fn run_kcl(opts: RunOptions) -> Result<KclRunResult> {
+    let start = Instant::now();
+    let output = kcl_run(&CliRunner, &opts)?;
+    Ok(KclRunResult {
+        stdout: utf8_output(output.stdout.clone())?,
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        duration: start.elapsed(),
+        files: opts.files,
+        format: opts.format,
+        output: opts.output,
+    })
+}
+
+/// Output of a `kcl run` process, successful or not.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KclProcessOutput {
+    /// Rendered output (empty when it was written to the output file).
+    pub stdout: String,
+    /// Errors and warnings KCL printed.
+    pub stderr: String,
+    /// Exit code of KCL, `None` if it was killed by a signal.
+    pub exit_code: Option<i32>,
+}
+
+/// Run KCL with `kcl run` and return its output along with its exit code.
+///
+/// Unlike `run_kcl_command`, a failing KCL run is not an error, so callers can branch
+/// on the exit code themselves.
+///
+/// # Returns
+/// * `Ok(KclProcessOutput)` once KCL exited, whatever its exit code.
+/// * `Err(anyhow::Error)` if KCL cannot be started, times out, or its TOML output cannot be converted.
+pub fn run_kcl_process(opts: &RunOptions) -> Result<KclProcessOutput> {
+    let mut output_res = kcl_exec(&CliRunner, opts)?;
+    if output_res.status.success() {
+        output_res = convert_output(opts, output_res)?;
+    }
+    Ok(KclProcessOutput {
+        stdout: String::from_utf8_lossy(&output_res.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&output_res.stderr).into_owned(),
+        exit_code: output_res.status.code(),
+    })
+}
+
+/// Everything about a `kcl run` invocation, to reproduce it when reporting issues.
+#[derive(Debug, Clone)]
+pub struct KclInvocation {
+    /// Path of the `kcl` binary that ran, as resolved from PATH.
+    pub bin: std::path::PathBuf,
+    /// Arguments passed to it, starting with `run`.
+    pub args: Vec<String>,
+    /// Directory KCL ran in.
+    pub cwd: String,
+    /// Raw output of the KCL process, whatever its exit status.
+    pub output: Output,
+    /// Wall-clock time of the KCL process.
+    pub duration: Duration,
+}
+
+/// Run KCL with `kcl run` and return its raw output, also recording how it was invoked.
+///
+/// Unlike `run_kcl_process`, the output is exactly what KCL printed: it is not converted
+/// to the requested format (e.g. TOML) nor decoded.
+///
+/// # Returns
+/// * `Ok(KclInvocation)` once KCL exited, whatever its exit code.
+/// * `Err(anyhow::Error)` if the options are not supported, KCL cannot be started or it times out.
+pub fn debug_kcl(opts: &RunOptions) -> Result<KclInvocation> {
+    let resolved = opts.in_package_root();
+    let cwd = match &resolved.cwd {
+        Some(cwd) => cwd.clone(),
+        None => std::env::current_dir()?.to_string_lossy().into_owned(),
+    };
+    let started = Instant::now();
+    let output = kcl_exec(&CliRunner, opts)?;
+    Ok(KclInvocation {
+        bin: resolve_kcl_binary(&opts.bin),
+        args: resolved.args(),
+        cwd,
+        output,
+        duration: started.elapsed(),
+    })
+}
+
+/// Options for `format_kcl`, built with chained setters.
+#[derive(Debug, Clone)]
+pub struct FormatOptions {
+    /// Name or path of the `kcl` binary.
+    pub bin: String,
+    /// KCL file, or directory whose `*.k` files are formatted.
+    pub path: String,
+    /// Only report the files that need formatting, without writing them.
+    pub check: bool,
+    /// Also format the files in subdirectories of a directory.
+    pub recursive: bool,
+}
+
+impl FormatOptions {
+    /// Options to format a file or directory with the default `kcl_binary()`.
+    pub fn new(path: impl Into<String>) -> Self {
+        Self {
+            bin: kcl_binary(),
+            path: path.into(),
+            check: false,
+            recursive: false,
+        }
+    }
+
+    /// Set the `kcl` binary to run.
+    pub fn bin(mut self, bin: impl Into<String>) -> Self {
+        self.bin = bin.into();
+        self
+    }
+
+    /// Only report the files that need formatting.
+    pub fn check(mut self, check: bool) -> Self {
+        self.check = check;
+        self
+    }
+
+    /// Also format the files in subdirectories.
+    pub fn recursive(mut self, recursive: bool) -> Self {
+        self.recursive = recursive;
+        self
+    }
+}
+
+/// Formatting outcome for a single file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormattedFile {
+    /// Path of the KCL file.
+    pub file: String,
+    /// True if the file was reformatted (or, with `check`, would be).
+    pub changed: bool,
+}
+
+/// Format KCL files with `kcl fmt`.
+///
+/// Only the files that are not formatted yet are rewritten, clean files keep their mtime.
+///
+/// # Returns
+/// * `Ok(Vec<FormattedFile>)` with one entry per KCL file found.
+/// * `Err(anyhow::Error)` if a file cannot be read or `kcl fmt` fails.
+pub fn format_kcl(opts: FormatOptions) -> Result<Vec<FormattedFile>> {
+    let unformatted = check_kcl_format(&opts.bin, &opts.path, opts.recursive)?;
+    if !opts.check {
+        for file in &unformatted {
+            format_kcl_file(&opts.bin, file)?;
+        }
+    }
+    Ok(kcl_files(&opts.path, opts.recursive)?
+        .into_iter()
+        .map(|file| FormattedFile {
+            changed: unformatted.contains(&file),
+            file,
+        })
+        .collect())
+}
+
+/// Callback of `validate_kcl` called with the number of files checked so far and the
+/// number of files to check, see `ValidateOptions::progress`.
+#[derive(Clone)]
+pub struct Progress(Arc<dyn Fn(usize, usize) + Send + Sync>);
+
+impl std::fmt::Debug for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Progress")
+    }
+}
+
+/// Options for `validate_kcl`, built with chained setters.
+#[derive(Debug, Clone)]
+pub struct ValidateOptions {
+    /// Name or path of the `kcl` binary.
+    pub bin: String,
+    /// Directory to validate, or a glob of the files to validate (e.g. `config/**/*.k`).
+    pub dir: String,
+    /// KCL schema file to vet JSON/YAML data files against.
+    pub schema: Option<String>,
+    /// Per-file limit after which the KCL process is killed.
+    pub timeout: Option<Duration>,
+    /// Failures whose error matches this regex are marked as ignored.
+    pub ignore_errors_matching: Option<Regex>,
+    /// Number of files checked concurrently (`0` uses the number of CPUs).
+    pub jobs: usize,
+    /// Stop validating (keeping the results so far) once this flag is set.
+    pub interrupt: Option<Arc<AtomicBool>>,
+    /// Working directory of the KCL processes, a relative `dir` resolves against it.
+    pub cwd: Option<String>,
+    /// Globs selecting the files to validate, relative to `dir` (e.g. `**/prod-*.k`).
+    pub include: Vec<String>,
+    /// Globs of files to skip, relative to `dir`. Excludes win over `include`.
+    pub exclude: Vec<String>,
+    /// Only compile KCL files (`kcl lint`) instead of evaluating them (`kcl run`).
+    pub compile_only: bool,
+    /// Stop at the first invalid file, killing the checks still running.
+    pub fail_fast: bool,
+    /// Skip the files ignored by `.gitignore`/`.ignore` rules.
+    pub respect_gitignore: bool,
+    /// Also validate the files under hidden directories and `skip_dirs`.
+    pub include_hidden: bool,
+    /// Directories not descended into unless `include_hidden` is set, `SKIPPED_DIRS` by default.
+    pub skip_dirs: Vec<String>,
+    /// Called each time a file has been checked.
+    pub progress: Option<Progress>,
+    /// Files to check instead of discovering them in `dir`.
+    pub files: Option<Vec<String>>,
+}
+
+impl ValidateOptions {
+    /// Options to lint the KCL files of a directory with the default `kcl_binary()`.
+    pub fn new(dir: impl Into<String>) -> Self {
+        Self {
+            bin: kcl_binary(),
+            dir: dir.into(),
+            schema: None,
+            timeout: None,
+            ignore_errors_matching: None,
+            jobs: 0,
+            interrupt: None,
+            cwd: None,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            compile_only: true,
+            fail_fast: false,
+            respect_gitignore: false,
+            include_hidden: false,
+            skip_dirs: SKIPPED_DIRS.iter().map(|dir| dir.to_string()).collect(),
+            progress: None,
+            files: None,
+        }
+    }
+
+    /// Set the `kcl` binary to run.
+    pub fn bin(mut self, bin: impl Into<String>) -> Self {
+        self.bin = bin.into();
+        self
+    }
+
+    /// Vet JSON/YAML data files against this schema instead of linting KCL files.
+    pub fn schema(mut self, schema: impl Into<String>) -> Self {
+        self.schema = Some(schema.into());
+        self
+    }
+
+    /// Kill the KCL process of a file after this long.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Check up to `jobs` files concurrently (`0` uses the number of CPUs).
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.jobs = jobs;
+        self
+    }
+
+    /// Stop validating once `interrupt` is set, e.g. from a Ctrl-C handler.
+    pub fn interrupt(mut self, interrupt: Arc<AtomicBool>) -> Self {
+        self.interrupt = Some(interrupt);
+        self
+    }
+
+    /// Run KCL in this working directory, resolving a relative `dir` against it.
+    ///
+    /// Discovered files keep the path of `dir` as given, relative to this directory.
+    pub fn cwd(mut self, cwd: impl Into<String>) -> Self {
+        self.cwd = Some(cwd.into());
+        self
+    }
+
+    /// Only validate the files matching this glob, relative to the directory.
+    pub fn pattern(mut self, pattern: impl Into<String>) -> Self {
+        self.include.push(pattern.into());
+        self
+    }
+
+    /// Only validate the files matching any of these globs, relative to the directory.
+    pub fn include<I, S>(mut self, include: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.include.extend(include.into_iter().map(Into::into));
+        self
+    }
+
+    /// Skip the files matching these globs, even if they match `include`.
+    pub fn exclude<I, S>(mut self, exclude: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.exclude.extend(exclude.into_iter().map(Into::into));
+        self
+    }
+
+    /// Compile KCL files without executing them (the default), or evaluate them with
+    /// `kcl run` to also catch runtime errors. Ignored when vetting against a schema.
+    pub fn compile_only(mut self, compile_only: bool) -> Self {
+        self.compile_only = compile_only;
+        self
+    }
+
+    /// Stop at the first invalid file (in path order), returning the results up to it.
+    pub fn fail_fast(mut self, fail_fast: bool) -> Self {
+        self.fail_fast = fail_fast;
+        self
+    }
+
+    /// Skip the files that `.gitignore` or `.ignore` rules ignore, e.g. generated or vendored KCL.
+    pub fn respect_gitignore(mut self, respect_gitignore: bool) -> Self {
+        self.respect_gitignore = respect_gitignore;
+        self
+    }
+
+    /// Also validate the files under hidden directories (e.g. `.git`) and `skip_dirs`.
+    pub fn include_hidden(mut self, include_hidden: bool) -> Self {
+        self.include_hidden = include_hidden;
+        self
+    }
+
+    /// Replace the directories skipped by name (`SKIPPED_DIRS` by default), e.g. with a
+    /// `vendor` directory of the project.
+    pub fn skip_dirs<I, S>(mut self, skip_dirs: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.skip_dirs = skip_dirs.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Check exactly these files, relative to `cwd`, instead of discovering files in the
+    /// directory. The globs, excludes and `.gitignore` rules are not applied to them.
+    pub fn files<I, S>(mut self, files: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.files
+            .get_or_insert_with(Vec::new)
+            .extend(files.into_iter().map(Into::into));
+        self
+    }
+
+    /// Call `progress(checked, total)` each time a file has been checked, e.g. to show a
+    /// status line. With several jobs it is called from the worker threads.
+    pub fn progress(mut self, progress: impl Fn(usize, usize) + Send + Sync + 'static) -> Self {
+        self.progress = Some(Progress(Arc::new(progress)));
+        self
+    }
+
+    /// Mark failures whose error matches `pattern` as ignored.
+    pub fn ignore_errors_matching(mut self, pattern: Regex) -> Self {
+        self.ignore_errors_matching = Some(pattern);
+        self
+    }
+}
+
+/// Validate the files of a directory, see `validate_kcl_project`.
+///
+/// # Returns
+/// * `Ok(Vec<FileValidation>)` with one result per file found.
+/// * `Err(anyhow::Error)` if the files cannot be listed.
+pub fn validate_kcl(opts: ValidateOptions) -> Result<Vec<FileValidation>> {
+    let files = match &opts.files {
+        Some(files) => files.clone(),
+        None => discover_files(&opts)?,
+    };
+    let checker = FileChecker {
+        runner: &CliRunner,
+        bin: &opts.bin,
+        check: FileCheck::new(opts.schema.as_deref(), opts.compile_only),
+        timeout: opts.timeout,
+        cwd: opts.cwd.as_deref(),
+        ignore: opts.ignore_errors_matching.as_ref(),
+    };
+    validate_files(
+        &checker,
+        files,
+        opts.jobs,
+        opts.interrupt.as_deref(),
+        opts.fail_fast,
+        opts.progress.as_ref(),
+    )
+}
+
+/// Select the files of `opts.dir` that `validate_kcl` checks.
+fn discover_files(opts: &ValidateOptions) -> Result<Vec<String>> {
+    // A glob as `dir` (e.g. `config/**/*.k`) selects files from the working directory.
+    let (dir, include) = match is_glob(&opts.dir) {
+        true => {
+            let include = std::iter::once(opts.dir.clone()).chain(opts.include.iter().cloned());
+            ("", include.collect())
+        }
+        false => (opts.dir.as_str(), opts.include.clone()),
+    };
+    let Some(cwd) = &opts.cwd else {
+        return select_dir_files(opts, dir, &include);
+    };
+    // The directory is searched under `cwd`, but the files are reported under `dir` as
+    // given: KCL runs in `cwd`, where they resolve to the same files.
+    let searched = std::path::Path::new(cwd).join(dir);
+    let files = select_dir_files(opts, &searched.to_string_lossy(), &include)?;
+    Ok(files
+        .into_iter()
+        .map(
+            |file| match std::path::Path::new(&file).strip_prefix(&searched) {
+                Ok(relative) if relative.as_os_str().is_empty() => dir.to_string(),
+                Ok(relative) => std::path::Path::new(dir)
+                    .join(relative)
+                    .to_string_lossy()
+                    .into_owned(),
+                Err(_) => file,
+            },
+        )
+        .collect())
+}
+
+/// Select the files of `dir` with the filters of `opts`, see `discover_files`.
+fn select_dir_files(opts: &ValidateOptions, dir: &str, include: &[String]) -> Result<Vec<String>> {
+    let skip_dirs = (!opts.include_hidden).then_some(opts.skip_dirs.as_slice());
+    let mut files = select_files(
+        dir,
+        opts.schema.as_deref(),
+        include,
+        &opts.exclude,
+        skip_dirs,
+    )?;
+    if opts.respect_gitignore && !std::path::Path::new(dir).is_file() {
+        retain_not_ignored(dir, &mut files);
+    }
+    Ok(files)
 }
@@ -0,0 +1,13 @@
+//! KCL CLI wrapper used by the `nu_plugin_kcl` Nushell plugin.
+//!
+//! The [`helpers`] module can be embedded by other plugins or tools to run,
+//! format and validate KCL through the `kcl` binary:
+//!
+//! ```no_run
+//! use nu_plugin_kcl::helpers::{RunOptions, run_kcl};
+//!
+//! let result = run_kcl(RunOptions::new("main.k").format("json").define("env=prod"))?;
+//! println!("{}", result.stdout);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+pub mod helpers;
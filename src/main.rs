@@ -2,22 +2,46 @@ use nu_plugin::{
     EngineInterface, EvaluatedCall, MsgPackSerializer, Plugin, PluginCommand, SimplePluginCommand,
     serve_plugin,
 };
-use nu_protocol::{Category, Example, LabeledError, Signature, SyntaxShape, Type, Value};
+use nu_protocol::{
+    Category, Example, IntoSpanned, LabeledError, ListStream, PipelineData, Record, SignalAction,
+    Signature, Span, SyntaxShape, Type, Value, record,
+};
 
 use anyhow::Result;
-mod helpers;
+use nu_plugin_kcl::helpers;
+use regex::Regex;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 #[cfg(test)]
 mod tests;
 
-use crate::helpers::{format_kcl_file, run_kcl_command, validate_kcl_project};
+use crate::helpers::{
+    FileValidation, FormatOptions, KCL_INSTALL_URL, KclNotFoundError, NotUtf8Error, OUTPUT_FORMATS,
+    RunOptions, TimeoutError, ValidateOptions, apply_type_hints, check_kcl_format, debug_kcl,
+    diff_kcl, eval_kcl, expand_path, export_kcl, format_kcl, format_kcl_diff, format_kcl_diffs,
+    format_kcl_file, format_kcl_source, import_kcl, import_kcl_source, json_report, junit_report,
+    kcl_mod, kcl_package_dir, kcl_version_info, parse_diagnostics, parse_env_vars, parse_output,
+    plain_text, read_overrides_file, render_kcl, run_hook, run_kcl_command, run_kcl_files,
+    run_kcl_process, run_kcl_tests, stream_kcl, validate_kcl, validation_counts,
+    validation_summary, vet_kcl_data, write_checksum_file, write_if_changed, write_temp_kcl,
+};
 
 /// Nushell plugin for running, formatting, and validating KCL files using the KCL CLI.
 ///
-/// This plugin provides three commands:
+/// This plugin provides eleven commands:
 /// - `kcl-run`: Execute KCL files and return their output.
+/// - `kcl-eval`: Evaluate an inline KCL expression.
+/// - `kcl-diff`: Compare the results of two KCL evaluations.
 /// - `kcl-format`: Format KCL files.
 /// - `kcl-validate`: Validate all KCL files in a directory.
+/// - `kcl-test`: Run the KCL tests of a package.
+/// - `kcl-vet`: Validate a JSON/YAML data file against a KCL schema.
+/// - `kcl-import`: Convert JSON/YAML/OpenAPI/CRD files into KCL.
+/// - `kcl-mod`: Manage the dependencies of a KCL package.
+/// - `kcl-export`: Export the schemas of a KCL package to an OpenAPI spec.
+/// - `kcl-version`: Report the KCL CLI and plugin versions.
 ///
 /// See each command struct for more details and usage examples.
 struct KclWrapperPlugin;
@@ -31,21 +55,479 @@ impl Plugin for KclWrapperPlugin {
     }
 
     fn commands(&self) -> Vec<Box<dyn PluginCommand<Plugin = Self>>> {
-        vec![Box::new(KclRun), Box::new(KclFormat), Box::new(KclValidate)]
+        vec![
+            Box::new(KclRun),
+            Box::new(KclEval),
+            Box::new(KclDiff),
+            Box::new(KclFormat),
+            Box::new(KclValidate),
+            Box::new(KclTest),
+            Box::new(KclVet),
+            Box::new(KclImport),
+            Box::new(KclMod),
+            Box::new(KclExport),
+            Box::new(KclVersion),
+        ]
+    }
+}
+
+/// Resolve the `kcl` binary the helpers should run.
+///
+/// The `bin` field of the plugin configuration wins, e.g.
+/// `$env.config.plugins.kcl = { bin: "/opt/kcl/bin/kcl" }`, then the `KCL_BIN`
+/// environment variable of the engine. Falls back to `helpers::kcl_binary()`.
+fn kcl_binary(engine: &EngineInterface) -> Result<String, LabeledError> {
+    if let Some(config) = engine.get_plugin_config()?
+        && let Some(bin) = config.as_record()?.get("bin")
+    {
+        return Ok(bin.coerce_string()?);
+    }
+    if let Some(bin) = engine.get_env_var("KCL_BIN")? {
+        return Ok(bin.coerce_string()?);
+    }
+    Ok(helpers::kcl_binary())
+}
+
+/// Read a flag that takes either a single string or a list of strings.
+///
+/// The flag may be repeated (e.g. `-D a=1 -D b=2`), every occurrence is collected in order.
+fn string_list_flag(call: &EvaluatedCall, name: &str) -> Result<Vec<String>, LabeledError> {
+    let mut strings = Vec::new();
+    for (_, value) in call.named.iter().filter(|(flag, _)| flag.item == name) {
+        match value {
+            Some(Value::List { vals, .. }) => {
+                for v in vals {
+                    strings.push(v.coerce_string()?);
+                }
+            }
+            Some(value) => strings.push(value.coerce_string()?),
+            None => {}
+        }
+    }
+    Ok(strings)
+}
+
+/// Check that every path passed to a flag exists, pointing at the first missing one.
+///
+/// Like `string_list_flag`, every occurrence of the flag is checked, each a path or a list of paths.
+fn check_paths_exist(
+    call: &EvaluatedCall,
+    engine: &EngineInterface,
+    name: &str,
+    working_dir: Option<&str>,
+) -> Result<(), LabeledError> {
+    for (_, value) in call.named.iter().filter(|(flag, _)| flag.item == name) {
+        let paths = match value {
+            Some(Value::List { vals, .. }) => vals.as_slice(),
+            Some(value) => std::slice::from_ref(value),
+            None => &[],
+        };
+        for path in paths {
+            let file = expand(engine, &path.coerce_str()?, path.span())?;
+            if !in_working_dir(working_dir, &file).exists() {
+                return Err(LabeledError::new(format!("--{} file not found", name))
+                    .with_label(format!("'{}' does not exist", file), path.span()));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The shell's current directory (`$env.PWD`).
+///
+/// Falls back to the plugin's own working directory when the engine has none, which
+/// only happens outside an interactive shell (e.g. in plugin tests).
+fn shell_cwd(engine: &EngineInterface) -> Result<String, LabeledError> {
+    match engine.get_current_dir() {
+        Ok(cwd) => Ok(cwd),
+        Err(e) => std::env::current_dir()
+            .map(|dir| dir.to_string_lossy().into_owned())
+            .map_err(|_| e.into()),
+    }
+}
+
+/// Expand `~` and `$VAR` in a path given at `span`, with the variables of the shell.
+fn expand(engine: &EngineInterface, path: &str, span: Span) -> Result<String, LabeledError> {
+    expand_path(path, |name| {
+        match engine.get_env_var(name) {
+            Ok(Some(value)) => value.coerce_into_string().ok(),
+            _ => None,
+        }
+        .or_else(|| std::env::var(name).ok())
+    })
+    .map_err(|e| LabeledError::new("Invalid path").with_label(e.to_string(), span))
+}
+
+/// Expand a path given at `span` and resolve it against the shell's current directory.
+///
+/// The plugin process does not follow `cd` in the shell, so relative paths must not be
+/// resolved against the plugin's own working directory.
+fn shell_path(engine: &EngineInterface, path: &str, span: Span) -> Result<String, LabeledError> {
+    let path = expand(engine, path, span)?;
+    let cwd = shell_cwd(engine)?;
+    Ok(std::path::Path::new(&cwd)
+        .join(path)
+        .to_string_lossy()
+        .into_owned())
+}
+
+/// Directory KCL runs in: the `--working-dir` flag, which must be an existing directory,
+/// or the shell's current directory. Both are absolute.
+fn working_dir(engine: &EngineInterface, call: &EvaluatedCall) -> Result<String, LabeledError> {
+    let Some(dir) = call.get_flag::<String>("working-dir")? else {
+        return shell_cwd(engine);
+    };
+    let span = call.get_flag_span("working-dir").unwrap_or(call.head);
+    let dir = shell_path(engine, &dir, span)?;
+    if !std::path::Path::new(&dir).is_dir() {
+        return Err(LabeledError::new("Invalid working directory").with_label(
+            format!("'{}' is not a directory", dir),
+            call.get_flag_span("working-dir").unwrap_or(call.head),
+        ));
+    }
+    Ok(dir)
+}
+
+/// Resolve a relative path against `--working-dir`, if given.
+fn in_working_dir(working_dir: Option<&str>, path: &str) -> std::path::PathBuf {
+    match working_dir {
+        Some(dir) => std::path::Path::new(dir).join(path),
+        None => std::path::PathBuf::from(path),
+    }
+}
+
+/// Span of the positional argument at `index`, or of the command name if it was not given.
+///
+/// Errors about a file or directory argument point at it, so Nushell underlines the
+/// offending argument instead of the whole command.
+fn arg_span(call: &EvaluatedCall, index: usize) -> Span {
+    call.positional.get(index).map_or(call.head, Value::span)
+}
+
+/// Check the path argument at `index`, resolved to `path`, exists, pointing at it otherwise.
+fn check_path_arg(
+    call: &EvaluatedCall,
+    index: usize,
+    what: &str,
+    path: &str,
+) -> Result<(), LabeledError> {
+    let Some(arg) = call.positional.get(index) else {
+        return Ok(());
+    };
+    if !std::path::Path::new(path).exists() {
+        let path = arg.coerce_str()?;
+        return Err(LabeledError::new(format!("{} not found", what))
+            .with_label(format!("'{}' does not exist", path), arg.span()));
+    }
+    Ok(())
+}
+
+/// Check the file arguments of `kcl-run` are existing `.k` files (or directories),
+/// pointing at the offending argument otherwise.
+fn check_kcl_files(
+    call: &EvaluatedCall,
+    files: &[String],
+    working_dir: Option<&str>,
+) -> Result<(), LabeledError> {
+    for (arg, file) in call.positional.iter().zip(files) {
+        let path = in_working_dir(working_dir, file);
+        if !path.exists() {
+            return Err(LabeledError::new("KCL file not found")
+                .with_label(format!("'{}' does not exist", file), arg.span()));
+        }
+        if path.is_file() && path.extension().is_none_or(|ext| ext != "k") {
+            return Err(LabeledError::new("Not a KCL file").with_label(
+                format!("'{}' does not have a .k extension", file),
+                arg.span(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Turn the fields of a record (from `--define-record` or the pipeline) into `key=value` defines.
+///
+/// Numbers are passed as is, booleans as `True`/`False`, null as `None` and strings
+/// unquoted (as with `-D`). Nested lists and records are JSON-encoded, which KCL parses
+/// as list and dict literals.
+fn record_defines(record: &Record) -> Result<Vec<String>, LabeledError> {
+    record
+        .iter()
+        .map(|(key, value)| {
+            let literal = match value {
+                Value::Int { val, .. } => val.to_string(),
+                Value::Float { val, .. } => val.to_string(),
+                Value::Bool { val, .. } => if *val { "True" } else { "False" }.to_string(),
+                Value::Nothing { .. } => "None".to_string(),
+                Value::String { val, .. } => val.clone(),
+                Value::List { .. } | Value::Record { .. } => {
+                    serde_json::to_string(&value_to_json(value)?).map_err(|e| {
+                        LabeledError::new("Invalid define").with_label(e.to_string(), value.span())
+                    })?
+                }
+                other => {
+                    return Err(LabeledError::new("Invalid define").with_label(
+                        format!(
+                            "'{}' is a {}, which cannot be defined",
+                            key,
+                            other.get_type()
+                        ),
+                        other.span(),
+                    ));
+                }
+            };
+            Ok(format!("{}={}", key, literal))
+        })
+        .collect()
+}
+
+/// Convert a Nushell value into JSON, for the nested values of piped defines.
+fn value_to_json(value: &Value) -> Result<serde_json::Value, LabeledError> {
+    Ok(match value {
+        Value::Nothing { .. } => serde_json::Value::Null,
+        Value::Bool { val, .. } => serde_json::Value::Bool(*val),
+        Value::Int { val, .. } => serde_json::Value::from(*val),
+        Value::Float { val, .. } => serde_json::Value::from(*val),
+        Value::String { val, .. } => serde_json::Value::String(val.clone()),
+        Value::List { vals, .. } => {
+            serde_json::Value::Array(vals.iter().map(value_to_json).collect::<Result<_, _>>()?)
+        }
+        Value::Record { val, .. } => serde_json::Value::Object(
+            val.iter()
+                .map(|(key, value)| Ok((key.clone(), value_to_json(value)?)))
+                .collect::<Result<_, LabeledError>>()?,
+        ),
+        other => serde_json::Value::String(other.coerce_string()?),
+    })
+}
+
+/// Read the `--overrides-file` of `kcl-run`, resolved like KCL paths against `working_dir`.
+///
+/// # Returns
+/// The entries as `(defines, overrides)`, depending on `--as`; the other one is empty.
+fn overrides_file(
+    call: &EvaluatedCall,
+    engine: &EngineInterface,
+    working_dir: Option<&str>,
+) -> Result<(Vec<String>, Vec<String>), LabeledError> {
+    let kind = call.get_flag::<String>("as")?;
+    let as_span = call.get_flag_span("as").unwrap_or(call.head);
+    let Some(value) = call.get_flag_value("overrides-file") else {
+        if kind.is_some() {
+            return Err(LabeledError::new("Missing argument")
+                .with_label("--as requires --overrides-file", as_span));
+        }
+        return Ok((Vec::new(), Vec::new()));
+    };
+    let file = expand(engine, &value.coerce_str()?, value.span())?;
+    let file = in_working_dir(working_dir, &file)
+        .to_string_lossy()
+        .into_owned();
+    let entries = read_overrides_file(&file).map_err(|e| {
+        LabeledError::new("Invalid overrides file").with_label(e.to_string(), value.span())
+    })?;
+    match kind.as_deref().unwrap_or("override") {
+        "override" => Ok((Vec::new(), entries)),
+        "define" => Ok((entries, Vec::new())),
+        other => Err(LabeledError::new("Invalid argument").with_label(
+            format!("'{}' is not one of: override, define", other),
+            as_span,
+        )),
+    }
+}
+
+/// "1 file needs" or "N files need", for `kcl-format --check`.
+fn files_need(count: usize) -> String {
+    match count {
+        1 => "1 file needs".to_string(),
+        n => format!("{} files need", n),
+    }
+}
+
+/// Build the error of a helper that failed with `e`, titled `title`.
+///
+/// A missing KCL binary gets its own error with install instructions, whatever the command.
+fn kcl_error(title: &str, e: anyhow::Error, span: Span) -> LabeledError {
+    match e.downcast_ref::<KclNotFoundError>() {
+        Some(not_found) => LabeledError::new("KCL CLI not found")
+            .with_label(
+                format!("'{}' is not installed or not on PATH", not_found.bin),
+                span,
+            )
+            .with_help(format!(
+                "Install KCL ({}), or point $env.KCL_BIN or the plugin's `bin` config at it",
+                KCL_INSTALL_URL
+            )),
+        None => LabeledError::new(title).with_label(e.to_string(), span),
+    }
+}
+
+/// Build the error of a failed KCL run, pointing at the location KCL reported if any.
+///
+/// The label shows `file:line:col: message` of the first diagnostic, its KCL error
+/// code becomes the error code (`kcl::E2L23`), and the other diagnostics are attached
+/// as inner errors. The raw KCL output is kept as help.
+fn kcl_run_error(e: anyhow::Error, span: Span) -> LabeledError {
+    if e.is::<KclNotFoundError>() {
+        return kcl_error("Error executing KCL", e, span);
+    }
+    if let Some(not_utf8) = e.downcast_ref::<NotUtf8Error>() {
+        return LabeledError::new("KCL output is not text")
+            .with_label(not_utf8.to_string(), span)
+            .with_help("Pass --binary to get the raw bytes");
+    }
+    if let Some(timeout) = e.downcast_ref::<TimeoutError>() {
+        return LabeledError::new("KCL timed out")
+            .with_label(timeout.to_string(), span)
+            .with_help("Raise --timeout, or check the program for long-running loops");
+    }
+    let text = e.to_string();
+    let diagnostics = parse_diagnostics(&text);
+    let Some((first, rest)) = diagnostics.split_first() else {
+        return LabeledError::new("Error executing KCL").with_label(text, span);
+    };
+    let mut error = LabeledError::new(format!("Error executing KCL in {}", first.file))
+        .with_label(first.to_string(), span)
+        .with_help(text);
+    if let Some(code) = &first.code {
+        error = error.with_code(format!("kcl::{}", code));
+    }
+    for diagnostic in rest {
+        error = error.with_inner(LabeledError::new(diagnostic.to_string()));
+    }
+    error
+}
+
+/// Whether a command writes plain `OK`/`FAIL`/`WARN` words instead of emoji.
+///
+/// Set by the `--plain` switch, or a non-empty `NO_EMOJI` or `NO_COLOR` environment variable.
+fn plain_mode(engine: &EngineInterface, call: &EvaluatedCall) -> Result<bool, LabeledError> {
+    if call.has_flag("plain")? {
+        return Ok(true);
+    }
+    for name in ["NO_EMOJI", "NO_COLOR"] {
+        let value = match engine.get_env_var(name)? {
+            Some(value) => value.coerce_into_string().ok(),
+            None => std::env::var(name).ok(),
+        };
+        if value.is_some_and(|value| !value.is_empty()) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Replace the emoji of every string in `value` (including nested ones), see `plain_text`.
+fn plain_value(value: Value) -> Value {
+    let span = value.span();
+    match value {
+        Value::String { val, .. } => Value::string(plain_text(&val), span),
+        Value::List { vals, .. } => Value::list(vals.into_iter().map(plain_value).collect(), span),
+        Value::Record { val, .. } => Value::record(
+            val.into_owned()
+                .into_iter()
+                .map(|(key, value)| (key, plain_value(value)))
+                .collect(),
+            span,
+        ),
+        value => value,
+    }
+}
+
+/// Replace the ✅ `run_kcl_command` puts before the output of `kcl-run` in plain mode.
+///
+/// Only this decoration is replaced: the output KCL rendered is user data and may hold
+/// emoji on purpose.
+fn plain_status(plain: bool, text: String) -> String {
+    match text.strip_prefix('✅') {
+        Some(rest) if plain => format!("OK{}", rest),
+        _ => text,
+    }
+}
+
+/// Replace the emoji in the messages, labels and help of an error, see `plain_text`.
+fn plain_error(mut error: LabeledError) -> LabeledError {
+    error.msg = plain_text(&error.msg);
+    for label in error.labels.iter_mut() {
+        label.text = plain_text(&label.text);
+    }
+    error.help = error.help.map(|help| plain_text(&help));
+    *error.inner = std::mem::take(&mut *error.inner)
+        .into_iter()
+        .map(plain_error)
+        .collect();
+    error
+}
+
+/// Print a warning to stderr, without emoji in plain mode.
+fn warn(plain: bool, message: String) {
+    match plain {
+        true => eprintln!("{}", plain_text(&message)),
+        false => eprintln!("{}", message),
+    }
+}
+
+/// Print `text` to the shell's stdout, or its stderr with `stderr`, with Nushell's `print` command.
+///
+/// For output that must show up while the command runs or even though it then fails.
+/// Nothing is printed if `print` is not available, e.g. in an engine without the standard
+/// commands.
+fn print(
+    engine: &EngineInterface,
+    head: Span,
+    text: String,
+    stderr: bool,
+) -> Result<(), LabeledError> {
+    if let Some(decl_id) = engine.find_decl("print")? {
+        let mut print_call = EvaluatedCall::new(head);
+        if stderr {
+            print_call.add_flag("stderr".into_spanned(head));
+        }
+        engine.call_decl(
+            decl_id,
+            print_call,
+            PipelineData::Value(Value::string(text, head), None),
+            false,
+            false,
+        )?;
     }
+    Ok(())
+}
+
+/// Read the `--timeout` flag of a command, a duration (e.g. `30sec`) or a number of seconds.
+fn timeout_flag(call: &EvaluatedCall) -> Result<Option<Duration>, LabeledError> {
+    let timeout = match call.get_flag_value("timeout") {
+        Some(Value::Duration { val, .. }) if val > 0 => Duration::from_nanos(val as u64),
+        Some(Value::Int { val, .. }) if val > 0 => Duration::from_secs(val as u64),
+        Some(_) => {
+            return Err(LabeledError::new("Invalid timeout").with_label(
+                "Timeout must be a positive duration or number of seconds",
+                call.get_flag_span("timeout").unwrap_or(call.head),
+            ));
+        }
+        None => return Ok(None),
+    };
+    Ok(Some(timeout))
 }
 
 /// Command to execute KCL files using the KCL CLI.
 ///
+/// The KCL source can also be piped in as a string instead of passing a file, and a
+/// piped record is turned into `-D` defines.
+/// With several files, each one is run separately and a record keyed by file is returned.
+///
 /// # Usage
 /// ```nu
 /// kcl-run myfile.k -D foo=bar -f json
+/// kcl-run app.k db.k
+/// "a = 1" | kcl-run
+/// {env: prod, replicas: 3} | kcl-run template.k
 /// ```
 ///
 /// See `examples()` for more.
 struct KclRun;
 
-impl SimplePluginCommand for KclRun {
+impl PluginCommand for KclRun {
     type Plugin = KclWrapperPlugin;
 
     fn name(&self) -> &str {
@@ -54,67 +536,861 @@ impl SimplePluginCommand for KclRun {
 
     fn signature(&self) -> Signature {
         Signature::build(PluginCommand::name(self))
-            .input_output_type(Type::Any, Type::String)
-            .required("file", SyntaxShape::Filepath, "KCL file to execute")
+            .input_output_types(vec![
+                (Type::Any, Type::String),
+                (Type::String, Type::String),
+                (Type::record(), Type::String),
+                (Type::Any, Type::record()),
+                (Type::Any, Type::list(Type::String)),
+                (Type::record(), Type::list(Type::String)),
+            ])
+            .optional(
+                "file",
+                SyntaxShape::Filepath,
+                "KCL file to execute (reads KCL source from the pipeline if omitted)",
+            )
+            .rest(
+                "files",
+                SyntaxShape::Filepath,
+                "More KCL files to execute, each one separately unless --merge is given",
+            )
+            .switch(
+                "merge",
+                "Compile all the files together into one program and return its merged output",
+                None,
+            )
+            .switch(
+                "package",
+                "Run the whole KCL package (kcl.mod) of the file or directory, or of the working directory",
+                None,
+            )
+            .switch(
+                "no-package-root",
+                "Run KCL from the shell's directory, not from the package root (kcl.mod) of the file",
+                None,
+            )
             .named(
                 "format",
                 SyntaxShape::String,
-                "Output format (yaml/json)",
+                "Output format (yaml/json/toml/ndjson)",
                 Some('f'),
             )
             .named("output", SyntaxShape::Filepath, "Output file", Some('o'))
+            .switch(
+                "output-if-changed",
+                "Only write the output file if its content changed (returns {file, written})",
+                None,
+            )
+            .switch(
+                "output-checksum-file",
+                "Also write a <output>.sha256 sidecar with the output digest",
+                None,
+            )
             .named(
                 "define",
-                SyntaxShape::String,
-                "Variables to define (key=value)",
+                SyntaxShape::OneOf(vec![
+                    SyntaxShape::List(Box::new(SyntaxShape::String)),
+                    SyntaxShape::String,
+                ]),
+                "Variables to define (key=value), repeat the flag or pass a list for several",
                 Some('D'),
             )
+            .named(
+                "settings",
+                SyntaxShape::OneOf(vec![
+                    SyntaxShape::List(Box::new(SyntaxShape::Filepath)),
+                    SyntaxShape::Filepath,
+                ]),
+                "KCL settings files (e.g. kcl.yaml or [base.yaml ci.yaml]), passed in order as -Y",
+                Some('Y'),
+            )
+            .named(
+                "override",
+                SyntaxShape::OneOf(vec![
+                    SyntaxShape::List(Box::new(SyntaxShape::String)),
+                    SyntaxShape::String,
+                ]),
+                "Override fields anywhere in the program (pkg:path.to.field=value), passed as -O",
+                Some('O'),
+            )
+            .named(
+                "env",
+                SyntaxShape::OneOf(vec![
+                    SyntaxShape::List(Box::new(SyntaxShape::String)),
+                    SyntaxShape::String,
+                ]),
+                "Environment variables (KEY=VALUE) for the KCL process, repeat the flag or pass a list",
+                None,
+            )
+            .named(
+                "overrides-file",
+                SyntaxShape::Filepath,
+                "File of key=value lines ('#' comments allowed), passed as -O or as -D with --as",
+                None,
+            )
+            .named(
+                "as",
+                SyntaxShape::String,
+                "How to pass the --overrides-file entries: override (-O, default) or define (-D)",
+                None,
+            )
+            .named(
+                "define-record",
+                SyntaxShape::Record(vec![]),
+                "Variables to define from a record, e.g. {replicas: 3, debug: true}",
+                None,
+            )
+            .named(
+                "define-type-hints",
+                SyntaxShape::Record(vec![]),
+                "Types of defined variables (int/float/bool/string), e.g. {replicas: int}",
+                None,
+            )
+            .switch(
+                "quiet",
+                "Do not print the warnings KCL reports on success",
+                Some('q'),
+            )
+            .switch(
+                "with-warnings",
+                "Return an {output, warnings} record with the KCL warnings and post-run hook failure, instead of printing them",
+                None,
+            )
+            .switch(
+                "binary",
+                "Return the output as binary if it is not valid UTF-8, instead of failing",
+                None,
+            )
+            .switch(
+                "stream",
+                "Stream the output as a list of unparsed text lines while KCL prints it",
+                None,
+            )
+            .switch(
+                "color",
+                "Keep the ANSI colors KCL prints in its output and errors",
+                None,
+            )
+            .switch(
+                "meta",
+                "Return a {stdout, stderr, exit_code, success} record, also when KCL fails",
+                None,
+            )
+            .switch("capture", "Same as --meta", None)
+            .switch(
+                "debug",
+                "Return a record of the resolved binary, arguments, directory, output, exit code and duration of the run",
+                None,
+            )
+            .switch(
+                "split-docs",
+                "Return a list with each '---' separated document of the output parsed",
+                None,
+            )
+            .switch(
+                "dry-run",
+                "Return the kcl command line that would run, without running it",
+                None,
+            )
+            .switch(
+                "sort-keys",
+                "Sort the keys of the output, for deterministic diffs",
+                Some('k'),
+            )
+            .switch(
+                "disable-none",
+                "Omit attributes whose value is None from the output",
+                Some('n'),
+            )
+            .named(
+                "select",
+                SyntaxShape::OneOf(vec![
+                    SyntaxShape::List(Box::new(SyntaxShape::String)),
+                    SyntaxShape::String,
+                ]),
+                "Only output the value at this path of the result (e.g. spec.replicas), passed as -S. Repeat the flag or pass a list for several",
+                Some('S'),
+            )
+            .switch(
+                "validate-output",
+                "Fail if the rendered output is not well-formed in its format",
+                None,
+            )
+            .named(
+                "working-dir",
+                SyntaxShape::Directory,
+                "Directory to run KCL from, relative files, outputs and imports resolve against it",
+                Some('C'),
+            )
+            .named(
+                "timeout",
+                SyntaxShape::OneOf(vec![SyntaxShape::Duration, SyntaxShape::Int]),
+                "Kill the KCL process after this long (a duration, or seconds)",
+                Some('t'),
+            )
+            .named(
+                "pre-run-hook",
+                SyntaxShape::String,
+                "Shell command to run before KCL (aborts the run if it fails)",
+                None,
+            )
+            .named(
+                "post-run-hook",
+                SyntaxShape::String,
+                "Shell command to run after KCL (only warns if it fails)",
+                None,
+            )
+            .switch(
+                "plain",
+                "Write OK/FAIL/WARN instead of emoji (also set by the NO_EMOJI or NO_COLOR env vars)",
+                None,
+            )
             .category(Category::Experimental)
     }
     fn description(&self) -> &str {
         "Execute KCL files using the CLI wrapper"
     }
 
-    fn examples(&self) -> Vec<Example> {
-        vec![Example {
-            example: "kcl-run myfile.k -D foo=bar -f json",
-            description: "Run 'myfile.k' with variable 'foo=bar' and output as JSON.",
-            result: Some(Value::test_string("{\n  \"foo\": \"bar\"\n}")),
-        }]
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![
+            Example {
+                example: "kcl-run myfile.k -D foo=bar -f json",
+                description: "Run 'myfile.k' with variable 'foo=bar' and output as JSON.",
+                result: Some(Value::test_string("{\n  \"foo\": \"bar\"\n}")),
+            },
+            Example {
+                example: "kcl-run app.k -o app.yaml --output-checksum-file",
+                description: "Write 'app.yaml' along with an 'app.yaml.sha256' checksum file.",
+                result: Some(Value::test_string("✅ app.yaml\n✅ app.yaml.sha256")),
+            },
+            Example {
+                example: "kcl-run app.k -o app.yaml --output-if-changed",
+                description: "Write 'app.yaml' only if the rendered output differs from its content.",
+                result: Some(Value::test_record(record! {
+                    "file" => Value::test_string("app.yaml"),
+                    "written" => Value::test_bool(false),
+                })),
+            },
+            Example {
+                example: "kcl-run main.k --define-record {replicas: 3, debug: true}",
+                description: "Define 'replicas=3' and 'debug=True' from a record.",
+                result: None,
+            },
+            Example {
+                example: "kcl-run main.k -D env=prod -O app.replicas=3",
+                description: "Set the top-level option 'env' with -D and override the nested field 'app.replicas' with -O.",
+                result: None,
+            },
+            Example {
+                example: "kcl-run main.k -Y ci.yaml",
+                description: "Run 'main.k' with the options of the settings file 'ci.yaml'.",
+                result: None,
+            },
+            Example {
+                example: "kcl-run app.k db.k",
+                description: "Run 'app.k' and 'db.k' separately, keyed by file.",
+                result: Some(Value::test_record(record! {
+                    "app.k" => Value::test_string("✅ app: web\n"),
+                    "db.k" => Value::test_string("✅ db: postgres\n"),
+                })),
+            },
+            Example {
+                example: "{env: prod, replicas: 3} | kcl-run template.k",
+                description: "Run 'template.k' with 'env=prod' and 'replicas=3' defined from the piped record.",
+                result: None,
+            },
+            Example {
+                example: "\"a = 1\" | kcl-run",
+                description: "Run KCL source piped in as a string.",
+                result: Some(Value::test_string("a: 1\n")),
+            },
+            Example {
+                example: "kcl-run myfile.k -D replicas=3 -D tag=3 --define-type-hints {replicas: int, tag: string}",
+                description: "Run 'myfile.k' passing 'replicas' as a number and 'tag' as a string.",
+                result: Some(Value::test_string("replicas: 3\ntag: '3'\n")),
+            },
+            Example {
+                example: "kcl-run myfile.k --pre-run-hook 'nu gen-input.nu' --post-run-hook 'rm input.json'",
+                description: "Generate an input file before running 'myfile.k' and remove it afterwards.",
+                result: None,
+            },
+            Example {
+                example: "kcl-run deployment.k -S spec.replicas",
+                description: "Output only the 'spec.replicas' value of 'deployment.k'.",
+                result: Some(Value::test_string("3\n")),
+            },
+            Example {
+                example: "kcl-run myfile.k -D foo=bar -f toml",
+                description: "Run 'myfile.k' and convert its output to TOML.",
+                result: Some(Value::test_string("foo = \"bar\"\n")),
+            },
+            Example {
+                example: "kcl-run myfile.k -f json --validate-output",
+                description: "Run 'myfile.k' and check the JSON output parses before returning it.",
+                result: Some(Value::test_string("{\n  \"foo\": \"bar\"\n}")),
+            },
+            Example {
+                example: "kcl-run manifests.k --split-docs | length",
+                description: "Run 'manifests.k' and count the YAML documents (e.g. Kubernetes manifests) it renders.",
+                result: Some(Value::test_int(2)),
+            },
+            Example {
+                example: "kcl-run base.k prod.k --merge",
+                description: "Compile 'base.k' and 'prod.k' into one program and return the merged config.",
+                result: None,
+            },
+            Example {
+                example: "kcl-run ./app --package",
+                description: "Run the KCL package in './app' as a whole, as declared by its kcl.mod.",
+                result: None,
+            },
+            Example {
+                example: "kcl-run myfile.k --debug | to json",
+                description: "Record how 'myfile.k' was run and what KCL printed, e.g. for a bug report.",
+                result: None,
+            },
+            Example {
+                example: "kcl-run myfile.k --meta | if $in.exit_code != 0 { $in.stderr }",
+                description: "Run 'myfile.k' and branch on the KCL exit code instead of failing.",
+                result: None,
+            },
+            Example {
+                example: "kcl-run main.k -D env=prod -o out.yaml --dry-run",
+                description: "Show the kcl command line running 'main.k' would use, without running it.",
+                result: Some(Value::test_list(
+                    [
+                        "kcl", "run", "main.k", "--format", "yaml", "-D", "env=prod", "-o",
+                        "out.yaml",
+                    ]
+                    .into_iter()
+                    .map(Value::test_string)
+                    .collect(),
+                )),
+            },
+            Example {
+                example: "kcl-run manifests.k --stream | where $it =~ 'image:'",
+                description: "Stream the output of 'manifests.k' line by line and keep the image lines.",
+                result: None,
+            },
+        ]
     }
 
     fn run(
         &self,
         _plugin: &KclWrapperPlugin,
-        _engine: &EngineInterface,
+        engine: &EngineInterface,
         call: &EvaluatedCall,
-        _input: &Value,
-    ) -> Result<Value, LabeledError> {
-        let file_path: String = call.req(0)?;
+        input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let plain = plain_mode(engine, call)?;
+        // The output keeps the emoji KCL rendered, `execute` only strips the ones it adds.
+        match self.execute(engine, call, input, plain) {
+            Err(e) if plain => Err(plain_error(e)),
+            result => result,
+        }
+    }
+}
+
+impl KclRun {
+    fn execute(
+        &self,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        input: PipelineData,
+        plain: bool,
+    ) -> Result<PipelineData, LabeledError> {
+        let input = &input.into_value(call.head)?;
+        let bin = kcl_binary(engine)?;
+        // `_piped_source` keeps the temp file holding piped source alive until the run ends.
+        let mut files = call
+            .positional
+            .iter()
+            .map(|arg| expand(engine, &arg.coerce_str()?, arg.span()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let working_dir = Some(working_dir(engine, call)?);
+        check_kcl_files(call, &files, working_dir.as_deref())?;
+        // `kcl run <dir>` runs a package as a whole, with the entries its kcl.mod lists.
+        if call.has_flag("package")? {
+            if files.len() > 1 {
+                return Err(LabeledError::new("Conflicting arguments").with_label(
+                    "--package takes a single file or directory of the package",
+                    arg_span(call, 1),
+                ));
+            }
+            let path = files
+                .first()
+                .or(working_dir.as_ref())
+                .cloned()
+                .unwrap_or_default();
+            let Some(dir) = kcl_package_dir(&in_working_dir(working_dir.as_deref(), &path)) else {
+                return Err(LabeledError::new("Not a KCL package").with_label(
+                    format!("No kcl.mod found for '{}'", path),
+                    arg_span(call, 0),
+                ));
+            };
+            files = vec![dir.to_string_lossy().into_owned()];
+        }
+        let (file_path, _piped_source): (String, _) = match files.first() {
+            Some(file) => (file.clone(), None),
+            None => match input {
+                Value::String { val, .. } if !val.trim().is_empty() => {
+                    let temp = write_temp_kcl(val).map_err(|e| {
+                        LabeledError::new("Error reading KCL from pipeline")
+                            .with_label(e.to_string(), call.head)
+                    })?;
+                    (temp.path().to_string_lossy().into_owned(), Some(temp))
+                }
+                _ => {
+                    return Err(LabeledError::new("No KCL source")
+                        .with_label("Pass a KCL file or pipe KCL source in", call.head));
+                }
+            },
+        };
         let format = call
             .get_flag_value("format")
             .and_then(|v| v.as_str().ok().map(|s| s.to_string()))
             .unwrap_or_else(|| "yaml".to_string());
-        let output = call
-            .get_flag_value("output")
-            .and_then(|v| v.as_str().ok().map(|s| s.to_string()));
-        let defines: Vec<String> = call
-            .get_flag_value("define")
-            .and_then(|v| v.as_list().ok().map(|list| list.to_vec()))
-            .map(|list| {
-                list.into_iter()
-                    .filter_map(|v| v.as_str().ok().map(|s| s.to_string()))
-                    .collect()
-            })
-            .unwrap_or_default();
+        if !OUTPUT_FORMATS.contains(&format.as_str()) {
+            return Err(LabeledError::new("Unsupported format").with_label(
+                format!("'{}' is not one of: {}", format, OUTPUT_FORMATS.join(", ")),
+                call.get_flag_span("format").unwrap_or(call.head),
+            ));
+        }
+        // KCL writes the output relative to its working directory, so resolve it the same way.
+        let output = match call.get_flag_value("output") {
+            Some(value) => {
+                let output = expand(engine, &value.coerce_str()?, value.span())?;
+                Some(
+                    in_working_dir(working_dir.as_deref(), &output)
+                        .to_string_lossy()
+                        .into_owned(),
+                )
+            }
+            None => None,
+        };
+        let (file_defines, file_overrides) = overrides_file(call, engine, working_dir.as_deref())?;
+        // A piped record, --define-record and --overrides-file are turned into defines,
+        // explicit -D flags come after them and win.
+        let mut defines = match input {
+            Value::Record { val, .. } if !files.is_empty() => record_defines(val)?,
+            _ => Vec::new(),
+        };
+        if let Some(value) = call.get_flag_value("define-record") {
+            defines.extend(record_defines(value.as_record()?)?);
+        }
+        defines.extend(file_defines);
+        defines.extend(string_list_flag(call, "define")?);
+        check_paths_exist(call, engine, "settings", working_dir.as_deref())?;
+        let settings_span = call.get_flag_span("settings").unwrap_or(call.head);
+        let settings = string_list_flag(call, "settings")?
+            .iter()
+            .map(|file| expand(engine, file, settings_span))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut overrides = file_overrides;
+        overrides.extend(string_list_flag(call, "override")?);
+        let env = parse_env_vars(&string_list_flag(call, "env")?).map_err(|e| {
+            LabeledError::new("Invalid environment variable").with_label(
+                e.to_string(),
+                call.get_flag_span("env").unwrap_or(call.head),
+            )
+        })?;
+        let hints: Vec<(String, String)> = match call.get_flag_value("define-type-hints") {
+            Some(value) => value
+                .as_record()?
+                .iter()
+                .map(|(key, ty)| Ok((key.clone(), ty.as_str()?.to_string())))
+                .collect::<Result<_, LabeledError>>()?,
+            None => Vec::new(),
+        };
+        let defines = apply_type_hints(&defines, &hints).map_err(|e| {
+            LabeledError::new("Invalid define type hints").with_label(e.to_string(), call.head)
+        })?;
+        let quiet = call.has_flag("quiet")?;
+        let binary = call.has_flag("binary")?;
+        let checksum = call.has_flag("output-checksum-file")?;
+        if checksum && output.is_none() {
+            return Err(LabeledError::new("Missing argument").with_label(
+                "--output-checksum-file requires --output",
+                call.get_flag_span("output-checksum-file")
+                    .unwrap_or(call.head),
+            ));
+        }
+        let if_changed = call.has_flag("output-if-changed")?;
+        if if_changed && output.is_none() {
+            return Err(LabeledError::new("Missing argument").with_label(
+                "--output-if-changed requires --output",
+                call.get_flag_span("output-if-changed").unwrap_or(call.head),
+            ));
+        }
+        // Several files run one by one, unless --merge compiles them into a single program.
+        let separate = files.len() > 1 && !call.has_flag("merge")?;
+        if separate && output.is_some() {
+            return Err(LabeledError::new("Conflicting arguments").with_label(
+                "--output can only be used with a single file, or with --merge",
+                call.get_flag_span("output").unwrap_or(call.head),
+            ));
+        }
+        // Piped source and an explicit --working-dir run where they are.
+        let package_root = !files.is_empty()
+            && call.get_flag_value("working-dir").is_none()
+            && !call.has_flag("no-package-root")?;
+        let mut opts = RunOptions {
+            files: if files.is_empty() {
+                vec![file_path]
+            } else {
+                files
+            },
+            output: output.clone(),
+            ..RunOptions::default()
+        }
+        .bin(bin)
+        .format(format)
+        .defines(defines)
+        .settings(settings)
+        .overrides(overrides)
+        .env(env)
+        .sort_keys(call.has_flag("sort-keys")?)
+        .disable_none(call.has_flag("disable-none")?)
+        .validate_output(call.has_flag("validate-output")?)
+        .color(call.has_flag("color")?)
+        .timeout(timeout_flag(call)?)
+        .package_root(package_root);
+        if let Some(dir) = working_dir {
+            opts = opts.cwd(dir);
+        }
+        opts = opts.path_selectors(string_list_flag(call, "select")?);
 
-        match run_kcl_command(&file_path, &format, &output, &defines) {
-            Ok(result) => Ok(Value::string(result, call.head)),
-            Err(e) => {
-                Err(LabeledError::new("Error executing KCL").with_label(e.to_string(), call.head))
+        if call.has_flag("dry-run")? {
+            return Ok(PipelineData::Value(
+                dry_run(&opts, separate, call.head),
+                None,
+            ));
+        }
+
+        let pre_run_hook: Option<String> = call.get_flag("pre-run-hook")?;
+        let post_run_hook: Option<String> = call.get_flag("post-run-hook")?;
+        let stream = call.has_flag("stream")?;
+        if stream {
+            check_streamable(call, &opts)?;
+        }
+        // --capture is an alias of --meta.
+        let meta_flag = match call.has_flag("capture")? {
+            true => "capture",
+            false => "meta",
+        };
+        let meta = call.has_flag(meta_flag)?;
+        if meta && (separate || if_changed) {
+            return Err(LabeledError::new("Conflicting arguments").with_label(
+                format!(
+                    "--{} can only be used with a single file, without --output-if-changed",
+                    meta_flag
+                ),
+                call.get_flag_span(meta_flag).unwrap_or(call.head),
+            ));
+        }
+        let with_warnings = call.has_flag("with-warnings")?;
+        if with_warnings && meta {
+            return Err(LabeledError::new("Conflicting arguments").with_label(
+                "--with-warnings cannot be used with --meta, which returns the stderr of KCL",
+                call.get_flag_span("with-warnings").unwrap_or(call.head),
+            ));
+        }
+        let debug = call.has_flag("debug")?;
+        if debug && (separate || if_changed || meta || with_warnings) {
+            return Err(LabeledError::new("Conflicting arguments").with_label(
+                "--debug can only be used with a single file, without --output-if-changed, --meta or --with-warnings",
+                call.get_flag_span("debug").unwrap_or(call.head),
+            ));
+        }
+        let split_docs = call.has_flag("split-docs")?;
+        if split_docs && (separate || output.is_some() || meta || debug) {
+            return Err(LabeledError::new("Conflicting arguments").with_label(
+                "--split-docs can only be used with a single file, without --output, --meta or --debug",
+                call.get_flag_span("split-docs").unwrap_or(call.head),
+            ));
+        }
+
+        // Hooks run where KCL runs and with its environment, to prepare its inputs.
+        let hook_cwd = opts.in_package_root().cwd;
+        if let Some(hook) = &pre_run_hook {
+            run_hook(hook, hook_cwd.as_deref(), &opts.env).map_err(|e| {
+                LabeledError::new("Pre-run hook failed").with_label(
+                    e.to_string(),
+                    call.get_flag_span("pre-run-hook").unwrap_or(call.head),
+                )
+            })?;
+        }
+
+        if stream {
+            let lines = stream_kcl(&opts).map_err(|e| kcl_run_error(e, arg_span(call, 0)))?;
+            let span = call.head;
+            let piped_source = _piped_source;
+            let values = lines.map(move |line| {
+                let _source = &piped_source;
+                match line {
+                    Ok(line) => Value::string(line, span),
+                    Err(e) => Value::error(kcl_run_error(e, span).into(), span),
+                }
+            });
+            return Ok(PipelineData::ListStream(
+                ListStream::new(values, span, engine.signals().clone()),
+                None,
+            ));
+        }
+
+        let result = if meta {
+            run_kcl_process(&opts)
+                .map(|out| {
+                    let exit_code = match out.exit_code {
+                        Some(code) => Value::int(code.into(), call.head),
+                        None => Value::nothing(call.head),
+                    };
+                    let value = Value::record(
+                        record! {
+                            "stdout" => Value::string(out.stdout, call.head),
+                            "stderr" => Value::string(out.stderr, call.head),
+                            "exit_code" => exit_code,
+                            "success" => Value::bool(out.exit_code == Some(0), call.head),
+                        },
+                        call.head,
+                    );
+                    (value, Vec::new())
+                })
+                .map_err(|e| kcl_run_error(e, arg_span(call, 0)))
+        } else if debug {
+            debug_kcl(&opts)
+                .map(|run| {
+                    let exit_code = match run.output.status.code() {
+                        Some(code) => Value::int(code.into(), call.head),
+                        None => Value::nothing(call.head),
+                    };
+                    let args = run
+                        .args
+                        .into_iter()
+                        .map(|arg| Value::string(arg, call.head))
+                        .collect();
+                    let value = Value::record(
+                        record! {
+                            "bin" => Value::string(run.bin.to_string_lossy(), call.head),
+                            "args" => Value::list(args, call.head),
+                            "cwd" => Value::string(run.cwd, call.head),
+                            "stdout" => Value::string(String::from_utf8_lossy(&run.output.stdout), call.head),
+                            "stderr" => Value::string(String::from_utf8_lossy(&run.output.stderr), call.head),
+                            "exit_code" => exit_code,
+                            "duration" => Value::duration(run.duration.as_nanos() as i64, call.head),
+                        },
+                        call.head,
+                    );
+                    (value, Vec::new())
+                })
+                .map_err(|e| kcl_run_error(e, arg_span(call, 0)))
+        } else if split_docs {
+            render_kcl(&opts)
+                .and_then(|rendered| {
+                    let documents = parse_output(&rendered.text, &opts.format)?
+                        .iter()
+                        .map(|document| json_to_value(document, call.head))
+                        .collect();
+                    Ok((
+                        Value::list(documents, call.head),
+                        Vec::from_iter(rendered.warnings),
+                    ))
+                })
+                .map_err(|e| kcl_run_error(e, arg_span(call, 0)))
+        } else if separate {
+            run_many(&opts, call, plain)
+        } else if let (true, Some(output_file)) = (if_changed, &output) {
+            render_kcl(&opts)
+                .and_then(|rendered| {
+                    let written = write_if_changed(output_file, &rendered.text)?;
+                    let mut result = record! {
+                        "file" => Value::string(output_file, call.head),
+                        "written" => Value::bool(written, call.head),
+                    };
+                    if checksum {
+                        let sidecar = format!("{}.sha256", output_file);
+                        if written || !std::path::Path::new(&sidecar).exists() {
+                            write_checksum_file(output_file)?;
+                        }
+                        result.push("checksum", Value::string(sidecar, call.head));
+                    }
+                    Ok((
+                        Value::record(result, call.head),
+                        Vec::from_iter(rendered.warnings),
+                    ))
+                })
+                .map_err(|e| kcl_run_error(e, arg_span(call, 0)))
+        } else {
+            match run_kcl_command(&opts) {
+                Err(e) if binary && e.is::<NotUtf8Error>() => {
+                    let bytes = e
+                        .downcast::<NotUtf8Error>()
+                        .map(|e| e.bytes)
+                        .unwrap_or_default();
+                    Ok((Value::binary(bytes, call.head), Vec::new()))
+                }
+                result => result
+                    .and_then(|result| {
+                        let text = plain_status(plain, result.text);
+                        let text = match (&output, checksum) {
+                            (Some(output_file), true) => {
+                                let sidecar = write_checksum_file(output_file)?;
+                                let sidecar = plain_status(plain, format!("✅ {}", sidecar));
+                                format!("{}\n{}", text, sidecar)
+                            }
+                            _ => text,
+                        };
+                        Ok((
+                            Value::string(text, call.head),
+                            Vec::from_iter(result.warnings),
+                        ))
+                    })
+                    .map_err(|e| kcl_run_error(e, arg_span(call, 0))),
+            }
+        };
+
+        let hook_error = post_run_hook.as_ref().and_then(|hook| {
+            run_hook(hook, hook_cwd.as_deref(), &opts.env)
+                .err()
+                .map(|e| format!("Post-run hook failed: {}", e))
+        });
+        let (value, mut warnings) = match (result, hook_error.clone()) {
+            (Ok(result), _) => result,
+            (Err(e), Some(hook_error)) => return Err(e.with_inner(LabeledError::new(hook_error))),
+            (Err(e), None) => return Err(e),
+        };
+        if with_warnings {
+            warnings.extend(hook_error);
+            let warnings = warnings
+                .into_iter()
+                .map(|warning| Value::string(warning, call.head))
+                .collect();
+            let value = Value::record(
+                record! {
+                    "output" => value,
+                    "warnings" => Value::list(warnings, call.head),
+                },
+                call.head,
+            );
+            return Ok(PipelineData::Value(value, None));
+        }
+        if let Some(hook_error) = hook_error {
+            warn(plain, format!("⚠ {}", hook_error));
+        }
+        if !quiet {
+            for warning in warnings {
+                warn(plain, format!("⚠ KCL warnings:\n{}", warning));
+            }
+        }
+        Ok(PipelineData::Value(value, None))
+    }
+}
+
+/// The `kcl run` command lines `opts` would execute, without running them.
+///
+/// A single file, or files merged into one program, give the list of the binary and its
+/// arguments. `separate` files, which run one by one, give a record of such lists keyed by file.
+fn dry_run(opts: &RunOptions, separate: bool, span: Span) -> Value {
+    let command = |opts: &RunOptions| {
+        let mut args = vec![Value::string(&opts.bin, span)];
+        let run_args = opts.in_package_root().args();
+        args.extend(run_args.into_iter().map(|arg| Value::string(arg, span)));
+        Value::list(args, span)
+    };
+    if !separate {
+        return command(opts);
+    }
+    let mut commands = Record::new();
+    for file in &opts.files {
+        let single = RunOptions {
+            files: vec![file.clone()],
+            ..opts.clone()
+        };
+        commands.push(file, command(&single));
+    }
+    Value::record(commands, span)
+}
+
+/// Reject the `kcl-run` flags that need the whole output, which `--stream` never holds.
+fn check_streamable(call: &EvaluatedCall, opts: &RunOptions) -> Result<(), LabeledError> {
+    let switches = [
+        "output-if-changed",
+        "output-checksum-file",
+        "validate-output",
+        "meta",
+        "capture",
+        "debug",
+        "split-docs",
+        "with-warnings",
+    ];
+    let named = ["output", "timeout", "post-run-hook"];
+    for flag in switches.into_iter().chain(named) {
+        let given = if switches.contains(&flag) {
+            call.has_flag(flag)?
+        } else {
+            call.get_flag_value(flag).is_some()
+        };
+        if given {
+            return Err(LabeledError::new("Conflicting arguments").with_label(
+                format!("--stream cannot be used with --{}", flag),
+                call.get_flag_span(flag).unwrap_or(call.head),
+            ));
+        }
+    }
+    if opts.files.len() > 1 && !call.has_flag("merge")? {
+        return Err(LabeledError::new("Conflicting arguments").with_label(
+            "--stream can only be used with a single file, or with --merge",
+            call.head,
+        ));
+    }
+    if opts.format == "toml" || opts.format == "ndjson" {
+        return Err(LabeledError::new("Conflicting arguments").with_label(
+            format!("--stream cannot be used with the {} format", opts.format),
+            call.get_flag_span("format").unwrap_or(call.head),
+        ));
+    }
+    Ok(())
+}
+
+/// Run several KCL files and collect their outputs in a record keyed by file.
+///
+/// Fails listing every file that could not be run if any of them fails. Otherwise
+/// returns the record along with the warnings of each file, prefixed by its name.
+fn run_many(
+    opts: &RunOptions,
+    call: &EvaluatedCall,
+    plain: bool,
+) -> Result<(Value, Vec<String>), LabeledError> {
+    let mut outputs = Record::new();
+    let mut warnings = Vec::new();
+    let mut errors = Vec::new();
+    for (file, result) in run_kcl_files(opts) {
+        match result {
+            Ok(output) => {
+                if let Some(warning) = output.warnings {
+                    warnings.push(format!("{}: {}", file, warning));
+                }
+                outputs.push(
+                    file,
+                    Value::string(plain_status(plain, output.text), call.head),
+                );
             }
+            Err(e) => errors.push(format!("{}: {}", file, e)),
         }
     }
+    if errors.is_empty() {
+        Ok((Value::record(outputs, call.head), warnings))
+    } else {
+        Err(LabeledError::new("Error executing KCL").with_label(
+            format!(
+                "{} of {} files failed\n{}",
+                errors.len(),
+                opts.files.len(),
+                errors.join("\n")
+            ),
+            call.head,
+        ))
+    }
 }
 
 /// Command to format KCL files using the KCL CLI.
@@ -139,32 +1415,210 @@ impl SimplePluginCommand for KclFormat {
     }
     fn signature(&self) -> Signature {
         Signature::build(PluginCommand::name(self))
-            .input_output_type(Type::String, Type::String)
-            .required("file", SyntaxShape::Filepath, "KCL file to format")
+            .input_output_types(vec![
+                (Type::Any, Type::String),
+                (Type::Any, Type::table()),
+                (Type::String, Type::String),
+            ])
+            .optional(
+                "path",
+                SyntaxShape::Filepath,
+                "KCL file, or directory whose .k files are formatted (formats piped source if omitted)",
+            )
+            .switch(
+                "stdout",
+                "Return the formatted content of the file instead of writing it",
+                None,
+            )
+            .switch(
+                "check",
+                "Only check the files are formatted, without writing them (fails if any would change)",
+                None,
+            )
+            .switch(
+                "recursive",
+                "Also format the files in subdirectories of a directory",
+                Some('r'),
+            )
+            .switch(
+                "diff",
+                "Return a unified diff of the formatting changes instead of writing the files",
+                Some('d'),
+            )
+            .switch(
+                "plain",
+                "Write OK/FAIL/WARN instead of emoji (also set by the NO_EMOJI or NO_COLOR env vars)",
+                None,
+            )
             .category(Category::Experimental)
     }
     fn run(
         &self,
         _plugin: &KclWrapperPlugin,
-        _engine: &EngineInterface,
+        engine: &EngineInterface,
         call: &EvaluatedCall,
-        _input: &Value,
+        input: &Value,
+    ) -> Result<Value, LabeledError> {
+        // Formatted source is returned as is, its strings may hold emoji on purpose.
+        let returns_source = call.positional.is_empty() || call.has_flag("stdout")?;
+        let plain = plain_mode(engine, call)? && !returns_source;
+        match self.execute(engine, call, input) {
+            Ok(value) if plain => Ok(plain_value(value)),
+            Err(e) if plain => Err(plain_error(e)),
+            result => result,
+        }
+    }
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![
+            Example {
+                example: "kcl-format myfile.k",
+                description: "Format the KCL file 'myfile.k'.",
+                result: Some(Value::test_string("✅ File formatted: myfile.k")),
+            },
+            Example {
+                example: "kcl-format ./project_dir --recursive",
+                description: "Format every KCL file under './project_dir'.",
+                result: Some(Value::test_list(vec![
+                    Value::test_record(record! {
+                        "file" => Value::test_string("./project_dir/lib/vars.k"),
+                        "changed" => Value::test_bool(false),
+                        "status" => Value::test_string("clean"),
+                    }),
+                    Value::test_record(record! {
+                        "file" => Value::test_string("./project_dir/main.k"),
+                        "changed" => Value::test_bool(true),
+                        "status" => Value::test_string("formatted"),
+                    }),
+                ])),
+            },
+            Example {
+                example: "\"a=1\" | kcl-format",
+                description: "Format KCL source piped in and return it.",
+                result: Some(Value::test_string("a = 1\n")),
+            },
+            Example {
+                example: "kcl-format myfile.k --stdout",
+                description: "Return 'myfile.k' formatted, leaving the file untouched.",
+                result: Some(Value::test_string("a = 1\n")),
+            },
+            Example {
+                example: "kcl-format myfile.k --check",
+                description: "Check that 'myfile.k' is formatted without modifying it.",
+                result: Some(Value::test_string("✅ Already formatted: myfile.k")),
+            },
+            Example {
+                example: "kcl-format myfile.k --diff",
+                description: "Show the changes formatting 'myfile.k' would make, without modifying it.",
+                result: Some(Value::test_string(
+                    "--- myfile.k\n+++ myfile.k\n@@ -1 +1 @@\n-a=1\n+a = 1\n",
+                )),
+            },
+        ]
+    }
+}
+
+impl KclFormat {
+    fn execute(
+        &self,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        input: &Value,
     ) -> Result<Value, LabeledError> {
-        let file_path: String = call.req(0)?;
+        let bin = kcl_binary(engine)?;
+        let Some(path) = call.opt::<String>(0)? else {
+            let Value::String { val: source, .. } = input else {
+                return Err(LabeledError::new("No KCL source")
+                    .with_label("Pass a KCL file or pipe KCL source in", call.head));
+            };
+            return format_kcl_source(&bin, source)
+                .map(|formatted| Value::string(formatted, call.head))
+                .map_err(|e| kcl_error("Error formatting KCL", e, call.head));
+        };
+        let file_path = shell_path(engine, &path, arg_span(call, 0))?;
+        check_path_arg(call, 0, "KCL file", &file_path)?;
+        let recursive = call.has_flag("recursive")?;
 
-        match format_kcl_file(&file_path) {
-            Ok(result) => Ok(Value::string(result, call.head)),
-            Err(e) => {
-                Err(LabeledError::new("Error formatting KCL").with_label(e.to_string(), call.head))
+        if call.has_flag("stdout")? {
+            if std::path::Path::new(&file_path).is_dir() {
+                return Err(LabeledError::new("Conflicting arguments").with_label(
+                    "--stdout can only be used with a file",
+                    call.get_flag_span("stdout").unwrap_or(call.head),
+                ));
             }
+            return std::fs::read_to_string(&file_path)
+                .map_err(|e| anyhow::anyhow!("Error reading {}: {}", file_path, e))
+                .and_then(|source| format_kcl_source(&bin, &source))
+                .map(|formatted| Value::string(formatted, call.head))
+                .map_err(|e| kcl_error("Error formatting KCL", e, arg_span(call, 0)));
+        }
+
+        if call.has_flag("diff")? && call.has_flag("check")? {
+            let diffs = format_kcl_diffs(&bin, &file_path, recursive)
+                .map_err(|e| kcl_error("Error checking KCL format", e, arg_span(call, 0)))?;
+            if !diffs.is_empty() {
+                return Err(LabeledError::new("KCL files need formatting")
+                    .with_label(
+                        format!("❌ {} formatting", files_need(diffs.len())),
+                        arg_span(call, 0),
+                    )
+                    .with_help(diffs.into_iter().map(|(_, diff)| diff).collect::<String>()));
+            }
+            return Ok(Value::string(
+                format!("✅ Already formatted: {}", file_path),
+                call.head,
+            ));
+        }
+
+        if call.has_flag("diff")? {
+            return format_kcl_diff(&bin, &file_path, recursive)
+                .map(|diff| Value::string(diff, call.head))
+                .map_err(|e| kcl_error("Error formatting KCL", e, arg_span(call, 0)));
+        }
+
+        if call.has_flag("check")? {
+            let unformatted = check_kcl_format(&bin, &file_path, recursive)
+                .map_err(|e| kcl_error("Error checking KCL format", e, arg_span(call, 0)))?;
+            if !unformatted.is_empty() {
+                return Err(LabeledError::new("KCL files need formatting")
+                    .with_label(
+                        format!("❌ {} formatting", files_need(unformatted.len())),
+                        arg_span(call, 0),
+                    )
+                    .with_help(unformatted.join("\n")));
+            }
+            return Ok(Value::string(
+                format!("✅ Already formatted: {}", file_path),
+                call.head,
+            ));
+        }
+
+        if std::path::Path::new(&file_path).is_dir() {
+            let opts = FormatOptions::new(&file_path).bin(bin).recursive(recursive);
+            let files = format_kcl(opts)
+                .map_err(|e| kcl_error("Error formatting KCL", e, arg_span(call, 0)))?;
+            let rows = files
+                .into_iter()
+                .map(|formatted| {
+                    Value::record(
+                        record! {
+                            "file" => Value::string(formatted.file, call.head),
+                            "changed" => Value::bool(formatted.changed, call.head),
+                            "status" => Value::string(
+                                if formatted.changed { "formatted" } else { "clean" },
+                                call.head,
+                            ),
+                        },
+                        call.head,
+                    )
+                })
+                .collect();
+            return Ok(Value::list(rows, call.head));
+        }
+
+        match format_kcl_file(&bin, &file_path) {
+            Ok(result) => Ok(Value::string(result, call.head)),
+            Err(e) => Err(kcl_error("Error formatting KCL", e, arg_span(call, 0))),
         }
-    }
-    fn examples(&self) -> Vec<Example> {
-        vec![Example {
-            example: "kcl-format myfile.k",
-            description: "Format the KCL file 'myfile.k'.",
-            result: Some(Value::test_string("✅ File formatted: myfile.k")),
-        }]
     }
 }
 
@@ -189,34 +1643,1345 @@ impl SimplePluginCommand for KclValidate {
 
     fn signature(&self) -> Signature {
         Signature::build(PluginCommand::name(self))
-            .input_output_type(Type::Any, Type::String)
-            .optional("dir", SyntaxShape::Directory, "Directory to validate")
-            .category(Category::Experimental)
-    }
-
-    fn run(
-        &self,
-        _plugin: &KclWrapperPlugin,
-        _engine: &EngineInterface,
-        call: &EvaluatedCall,
-        _input: &Value,
-    ) -> Result<Value, LabeledError> {
-        let dir = call.opt::<String>(0)?.unwrap_or_else(|| ".".to_string());
-
-        match validate_kcl_project(&dir) {
-            Ok(result) => Ok(Value::string(result, call.head)),
-            Err(e) => Err(LabeledError::new("Error validating KCL project")
-                .with_label(e.to_string(), call.head)),
-        }
-    }
-    fn examples(&self) -> Vec<Example> {
-        vec![Example {
-            example: "kcl-validate ./project_dir",
-            description: "Validate all KCL files in the directory './project_dir'.",
-            result: Some(Value::test_string(
-                "✅ All 3 files are valid\n\n✅ ./project_dir/main.k\n✅ ./project_dir/vars.k\n✅ ./project_dir/other.k",
-            )),
-        }]
+            .input_output_types(vec![
+                (Type::Any, Type::table()),
+                (Type::Any, Type::String),
+                (Type::list(Type::String), Type::table()),
+                (Type::list(Type::String), Type::String),
+            ])
+            .optional(
+                "dir",
+                SyntaxShape::Filepath,
+                "Directory to validate, a single KCL (or data) file, or a glob like 'config/**/*.k' (wins over piped files)",
+            )
+            .named(
+                "schema",
+                SyntaxShape::Filepath,
+                "KCL schema file to vet JSON/YAML data files against",
+                Some('s'),
+            )
+            .switch(
+                "summary",
+                "Return a human-readable summary instead of a table",
+                None,
+            )
+            .switch(
+                "stats",
+                "Return a record counting the total, valid, invalid and ignored files, with all_valid and the duration_ms, instead of a table",
+                None,
+            )
+            .named(
+                "report-file",
+                SyntaxShape::Filepath,
+                "Also write a validation report to this file",
+                None,
+            )
+            .named(
+                "output-format",
+                SyntaxShape::String,
+                "Format of the report file (junit/json, default junit)",
+                None,
+            )
+            .named(
+                "working-dir",
+                SyntaxShape::Directory,
+                "Directory to run KCL from, a relative dir argument resolves against it",
+                Some('C'),
+            )
+            .named(
+                "timeout",
+                SyntaxShape::OneOf(vec![SyntaxShape::Duration, SyntaxShape::Int]),
+                "Kill the KCL process of a file after this long (a duration, or seconds)",
+                Some('t'),
+            )
+            .named(
+                "pattern",
+                SyntaxShape::String,
+                "Glob of the files to validate, relative to the directory (e.g. '**/prod-*.k')",
+                Some('p'),
+            )
+            .named(
+                "include",
+                SyntaxShape::OneOf(vec![
+                    SyntaxShape::List(Box::new(SyntaxShape::String)),
+                    SyntaxShape::String,
+                ]),
+                "Globs of the files to validate, relative to the directory (a file matching any of them is checked)",
+                None,
+            )
+            .named(
+                "exclude",
+                SyntaxShape::OneOf(vec![
+                    SyntaxShape::List(Box::new(SyntaxShape::String)),
+                    SyntaxShape::String,
+                ]),
+                "Globs of files to skip, relative to the directory (e.g. '**/vendor/**' or '*_test.k'), they win over --include",
+                None,
+            )
+            .named(
+                "jobs",
+                SyntaxShape::Int,
+                "Number of files to check concurrently (defaults to the number of CPUs)",
+                Some('j'),
+            )
+            .named(
+                "ignore-errors-matching",
+                SyntaxShape::String,
+                "Treat failures whose KCL error matches this regex as passes (marked ignored)",
+                None,
+            )
+            .switch(
+                "fail-on-invalid",
+                "Return an error (non-zero exit in scripts) if any file is invalid",
+                None,
+            )
+            .switch(
+                "respect-gitignore",
+                "Skip the files ignored by .gitignore or .ignore rules",
+                None,
+            )
+            .switch(
+                "include-hidden",
+                "Also validate files under hidden directories and vendored ones (.kclvm, target)",
+                None,
+            )
+            .switch(
+                "quiet",
+                "Do not print the 'Validated n/total files' progress lines on stderr",
+                Some('q'),
+            )
+            .switch(
+                "fail-fast",
+                "Stop at the first invalid file, cancelling the checks still running",
+                None,
+            )
+            .named(
+                "compile-only",
+                SyntaxShape::Boolean,
+                "Only compile KCL files without executing them (default true, false runs them with kcl run)",
+                None,
+            )
+            .switch(
+                "plain",
+                "Write OK/FAIL/WARN instead of emoji (also set by the NO_EMOJI or NO_COLOR env vars)",
+                None,
+            )
+            .category(Category::Experimental)
+    }
+
+    fn run(
+        &self,
+        _plugin: &KclWrapperPlugin,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        input: &Value,
+    ) -> Result<Value, LabeledError> {
+        let plain = plain_mode(engine, call)?;
+        match self.execute(engine, call, input, plain) {
+            Ok(value) if plain => Ok(plain_value(value)),
+            Err(e) if plain => Err(plain_error(e)),
+            result => result,
+        }
+    }
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![
+            Example {
+                example: "kcl-validate ./project_dir",
+                description: "Validate all KCL files in the directory './project_dir'.",
+                result: Some(Value::test_list(vec![
+                    Value::test_record(record! {
+                        "file" => Value::test_string("./project_dir/main.k"),
+                        "valid" => Value::test_bool(true),
+                        "status" => Value::test_string("valid"),
+                        "error" => Value::test_nothing(),
+                    }),
+                    Value::test_record(record! {
+                        "file" => Value::test_string("./project_dir/vars.k"),
+                        "valid" => Value::test_bool(true),
+                        "status" => Value::test_string("valid"),
+                        "error" => Value::test_nothing(),
+                    }),
+                ])),
+            },
+            Example {
+                example: "glob 'config/**/*.k' | kcl-validate",
+                description: "Validate the files listed by Nushell's glob command.",
+                result: None,
+            },
+            Example {
+                example: "kcl-validate ./project_dir | where valid == false",
+                description: "List only the invalid KCL files in './project_dir'.",
+                result: None,
+            },
+            Example {
+                example: "kcl-validate ./project_dir --pattern '**/prod-*.k' --exclude '**/tests/**'",
+                description: "Validate only the production files, skipping test fixtures.",
+                result: None,
+            },
+            Example {
+                example: "kcl-validate ./project_dir --summary",
+                description: "Summarize the validation of './project_dir' as text.",
+                result: Some(Value::test_string(
+                    "✅ All 3 files are valid\n\n✅ ./project_dir/main.k\n✅ ./project_dir/vars.k\n✅ ./project_dir/other.k",
+                )),
+            },
+            Example {
+                example: "kcl-validate ./project_dir --summary --output-format junit --report-file report.xml --fail-on-invalid",
+                description: "CI run: print a summary, write a JUnit report and fail if any file is invalid.",
+                result: Some(Value::test_string(
+                    "✅ All 3 files are valid\n\n✅ ./project_dir/main.k\n✅ ./project_dir/vars.k\n✅ ./project_dir/other.k",
+                )),
+            },
+            Example {
+                example: "kcl-validate ./project_dir --summary --ignore-errors-matching 'lib\\.k'",
+                description: "Validate './project_dir', allowlisting errors that mention 'lib.k'.",
+                result: Some(Value::test_string(
+                    "✅ 1 file is valid, ⚠ 1 ignored\n\n✅ ./project_dir/main.k\n⚠ ignored ./project_dir/lib.k: error in lib.k",
+                )),
+            },
+            Example {
+                example: "kcl-validate ./data -s schema.k --summary",
+                description: "Vet the JSON/YAML data files in './data' against 'schema.k'.",
+                result: Some(Value::test_string(
+                    "✅ All 2 files are valid\n\n✅ ./data/app.json\n✅ ./data/db.yaml",
+                )),
+            },
+        ]
+    }
+}
+
+impl KclValidate {
+    fn execute(
+        &self,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        input: &Value,
+        plain: bool,
+    ) -> Result<Value, LabeledError> {
+        let dir = match call.opt::<String>(0)? {
+            Some(dir) => expand(engine, &dir, arg_span(call, 0))?,
+            None => ".".to_string(),
+        };
+        // Files piped in (e.g. from `glob`) are checked as given, unless a path argument
+        // asks for discovery.
+        let piped_files = match input {
+            _ if !call.positional.is_empty() => None,
+            Value::List { vals, .. } => Some(
+                vals.iter()
+                    .map(|file| match file {
+                        Value::Record { val, .. } => match val.get("name") {
+                            Some(name) => expand(engine, &name.coerce_str()?, name.span()),
+                            None => Err(LabeledError::new("Invalid input").with_label(
+                                "Expected file paths, or records with a name column",
+                                file.span(),
+                            )),
+                        },
+                        file => expand(engine, &file.coerce_str()?, file.span()),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+            Value::String { val, .. } => Some(vec![expand(engine, val, input.span())?]),
+            _ => None,
+        };
+        let schema = match call.get_flag::<String>("schema")? {
+            Some(schema) => Some(shell_path(
+                engine,
+                &schema,
+                call.get_flag_span("schema").unwrap_or(call.head),
+            )?),
+            None => None,
+        };
+        let working_dir = working_dir(engine, call)?;
+
+        let summary = call.has_flag("summary")?;
+        let stats = call.has_flag("stats")?;
+        let report_file = match call.get_flag::<String>("report-file")? {
+            Some(file) => Some(shell_path(
+                engine,
+                &file,
+                call.get_flag_span("report-file").unwrap_or(call.head),
+            )?),
+            None => None,
+        };
+        let output_format = call
+            .get_flag::<String>("output-format")?
+            .unwrap_or_else(|| "junit".to_string());
+        if !["junit", "json"].contains(&output_format.as_str()) {
+            return Err(LabeledError::new("Unsupported report format").with_label(
+                format!("'{}' is not one of: junit, json", output_format),
+                call.get_flag_span("output-format").unwrap_or(call.head),
+            ));
+        }
+        let fail_on_invalid = call.has_flag("fail-on-invalid")?;
+        let timeout = timeout_flag(call)?;
+        let jobs = match call.get_flag::<i64>("jobs")? {
+            Some(jobs) if jobs > 0 => jobs as usize,
+            Some(_) => {
+                return Err(LabeledError::new("Invalid jobs").with_label(
+                    "Jobs must be a positive number",
+                    call.get_flag_span("jobs").unwrap_or(call.head),
+                ));
+            }
+            None => 0,
+        };
+        let ignore_pattern = match call.get_flag::<String>("ignore-errors-matching")? {
+            Some(pattern) => Some(Regex::new(&pattern).map_err(|e| {
+                LabeledError::new("Invalid regex").with_label(
+                    e.to_string(),
+                    call.get_flag_span("ignore-errors-matching")
+                        .unwrap_or(call.head),
+                )
+            })?),
+            None => None,
+        };
+        let bin = kcl_binary(engine)?;
+
+        // Ctrl-C stops launching KCL processes and kills the running ones; the files
+        // checked so far are still returned.
+        let interrupt = Arc::new(AtomicBool::new(engine.signals().interrupted()));
+        let _signal_guard = engine.register_signal_handler(Box::new({
+            let interrupt = interrupt.clone();
+            move |action| {
+                if let SignalAction::Interrupt = action {
+                    interrupt.store(true, Ordering::Relaxed);
+                }
+            }
+        }))?;
+
+        let mut opts = ValidateOptions::new(&dir)
+            .bin(bin)
+            .timeout(timeout)
+            .jobs(jobs)
+            .include(string_list_flag(call, "include")?)
+            .exclude(string_list_flag(call, "exclude")?)
+            .interrupt(interrupt.clone())
+            .compile_only(call.get_flag("compile-only")?.unwrap_or(true))
+            .fail_fast(call.has_flag("fail-fast")?)
+            .respect_gitignore(call.has_flag("respect-gitignore")?)
+            .include_hidden(call.has_flag("include-hidden")?)
+            .cwd(working_dir);
+        if let Some(files) = piped_files {
+            opts = opts.files(files);
+        }
+        if let Some(pattern) = call.get_flag::<String>("pattern")? {
+            opts = opts.pattern(pattern);
+        }
+        if let Some(schema) = &schema {
+            opts = opts.schema(schema);
+        }
+        if let Some(pattern) = ignore_pattern {
+            opts = opts.ignore_errors_matching(pattern);
+        }
+        // Progress is printed by the engine on the shell's stderr, so it stays out of the
+        // result. A failure to print it does not fail the validation.
+        if !call.has_flag("quiet")? {
+            let engine = engine.clone();
+            let head = call.head;
+            opts = opts.progress(move |checked, total| {
+                let text = format!("Validated {}/{} files", checked, total);
+                let _ = print(&engine, head, text, true);
+            });
+        }
+        let started = Instant::now();
+        let results = validate_kcl(opts);
+        let duration = started.elapsed();
+        let results =
+            results.map_err(|e| kcl_error("Error validating KCL project", e, arg_span(call, 0)))?;
+        let interrupted = interrupt
+            .load(Ordering::Relaxed)
+            .then(|| format!("⚠ Interrupted after validating {} files", results.len()));
+        if let Some(message) = &interrupted {
+            warn(plain, message.clone());
+        }
+
+        if let Some(report_file) = report_file {
+            let report = match output_format.as_str() {
+                "json" => json_report(&results).map_err(|e| {
+                    LabeledError::new("Error writing report").with_label(e.to_string(), call.head)
+                })?,
+                _ => junit_report(&results),
+            };
+            std::fs::write(&report_file, report).map_err(|e| {
+                LabeledError::new("Error writing report")
+                    .with_label(format!("{}: {}", report_file, e), call.head)
+            })?;
+        }
+
+        let invalid = results.iter().filter(|result| result.is_invalid()).count();
+        if fail_on_invalid && invalid > 0 {
+            let text = validation_summary(&dir, schema.as_deref(), &results);
+            if summary {
+                print(
+                    engine,
+                    call.head,
+                    if plain { plain_text(&text) } else { text },
+                    false,
+                )?;
+            }
+            return Err(LabeledError::new("KCL validation failed")
+                .with_label(
+                    format!("{} of {} files are invalid", invalid, results.len()),
+                    arg_span(call, 0),
+                )
+                .with_help(validation_summary(&dir, schema.as_deref(), &results)));
+        }
+
+        if summary {
+            let mut text = validation_summary(&dir, schema.as_deref(), &results);
+            if let Some(message) = interrupted {
+                text = format!("{}\n{}", text, message);
+            }
+            Ok(Value::string(text, call.head))
+        } else if stats {
+            let counts = validation_counts(&results);
+            Ok(Value::record(
+                record! {
+                    "total" => Value::int(counts.total as i64, call.head),
+                    "valid" => Value::int(counts.valid as i64, call.head),
+                    "invalid" => Value::int(counts.invalid as i64, call.head),
+                    "ignored" => Value::int(counts.ignored as i64, call.head),
+                    "all_valid" => Value::bool(counts.all_valid(), call.head),
+                    "duration_ms" => Value::int(duration.as_millis() as i64, call.head),
+                },
+                call.head,
+            ))
+        } else {
+            Ok(validation_records(&results, call.head))
+        }
+    }
+}
+
+/// Convert validation results into a table with `file`, `valid`, `status` and `error` columns.
+///
+/// `valid` is false only for invalid files (ignored failures pass), `status` is `"valid"`,
+/// `"ignored"` or `"invalid"`, and `error` holds KCL's message or null.
+fn validation_records(results: &[FileValidation], span: Span) -> Value {
+    let rows = results
+        .iter()
+        .map(|result| {
+            Value::record(
+                record! {
+                    "file" => Value::string(&result.file, span),
+                    "valid" => Value::bool(!result.is_invalid(), span),
+                    "status" => Value::string(result.status(), span),
+                    "error" => result
+                        .error
+                        .as_ref()
+                        .map_or(Value::nothing(span), |e| Value::string(e, span)),
+                },
+                span,
+            )
+        })
+        .collect();
+    Value::list(rows, span)
+}
+
+/// Command to run KCL tests (`*_test.k` files) using the KCL CLI.
+///
+/// # Usage
+/// ```nu
+/// kcl-test ./mypkg --run test_app
+/// ```
+///
+/// See `examples()` for more.
+struct KclTest;
+
+impl SimplePluginCommand for KclTest {
+    type Plugin = KclWrapperPlugin;
+
+    fn name(&self) -> &str {
+        "kcl-test"
+    }
+
+    fn description(&self) -> &str {
+        "Run KCL tests and summarize the results"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self))
+            .input_output_type(Type::Any, Type::record())
+            .optional("dir", SyntaxShape::Directory, "Directory holding the tests")
+            .named(
+                "run",
+                SyntaxShape::String,
+                "Only run tests matching this regex",
+                None,
+            )
+            .switch(
+                "no-fail",
+                "Return the summary record even if some tests fail",
+                None,
+            )
+            .category(Category::Experimental)
+    }
+
+    fn run(
+        &self,
+        _plugin: &KclWrapperPlugin,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        _input: &Value,
+    ) -> Result<Value, LabeledError> {
+        let dir = shell_path(
+            engine,
+            &call.opt::<String>(0)?.unwrap_or_default(),
+            arg_span(call, 0),
+        )?;
+        let run: Option<String> = call.get_flag("run")?;
+        let no_fail = call.has_flag("no-fail")?;
+        let bin = kcl_binary(engine)?;
+
+        let cases = run_kcl_tests(&bin, &dir, run.as_deref())
+            .map_err(|e| kcl_error("Error running KCL tests", e, call.head))?;
+
+        let failing: Vec<&str> = cases
+            .iter()
+            .filter(|case| !case.passed)
+            .map(|case| case.name.as_str())
+            .collect();
+        if !failing.is_empty() && !no_fail {
+            return Err(LabeledError::new("KCL tests failed").with_label(
+                format!(
+                    "{} of {} tests failed: {}",
+                    failing.len(),
+                    cases.len(),
+                    failing.join(", ")
+                ),
+                call.head,
+            ));
+        }
+
+        let span = call.head;
+        let tests = cases
+            .iter()
+            .map(|case| {
+                Value::record(
+                    record! {
+                        "name" => Value::string(&case.name, span),
+                        "status" => Value::string(if case.passed { "pass" } else { "fail" }, span),
+                        "duration" => case
+                            .duration
+                            .as_ref()
+                            .map_or(Value::nothing(span), |d| Value::string(d, span)),
+                    },
+                    span,
+                )
+            })
+            .collect();
+
+        Ok(Value::record(
+            record! {
+                "passed" => Value::int((cases.len() - failing.len()) as i64, span),
+                "failed" => Value::int(failing.len() as i64, span),
+                "total" => Value::int(cases.len() as i64, span),
+                "failing" => Value::list(
+                    failing.iter().map(|name| Value::string(*name, span)).collect(),
+                    span,
+                ),
+                "tests" => Value::list(tests, span),
+            },
+            span,
+        ))
+    }
+
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![
+            Example {
+                example: "kcl-test ./mypkg --run test_app",
+                description: "Run the tests in './mypkg' whose names match 'test_app'.",
+                result: Some(Value::test_record(record! {
+                    "passed" => Value::test_int(1),
+                    "failed" => Value::test_int(0),
+                    "total" => Value::test_int(1),
+                    "failing" => Value::test_list(vec![]),
+                    "tests" => Value::test_list(vec![Value::test_record(record! {
+                        "name" => Value::test_string("test_app"),
+                        "status" => Value::test_string("pass"),
+                        "duration" => Value::test_string("2ms"),
+                    })]),
+                })),
+            },
+            Example {
+                example: "kcl-test ./mypkg --no-fail | get failing",
+                description: "List the names of the failing tests in './mypkg' without erroring.",
+                result: None,
+            },
+        ]
+    }
+}
+
+/// Command to validate a data file against a KCL schema using the KCL CLI.
+///
+/// # Usage
+/// ```nu
+/// kcl-vet data.json schema.k --schema-name App
+/// ```
+///
+/// See `examples()` for more.
+struct KclVet;
+
+impl SimplePluginCommand for KclVet {
+    type Plugin = KclWrapperPlugin;
+
+    fn name(&self) -> &str {
+        "kcl-vet"
+    }
+
+    fn description(&self) -> &str {
+        "Validate a JSON/YAML data file against a KCL schema"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self))
+            .input_output_types(vec![(Type::Any, Type::record())])
+            .required(
+                "data",
+                SyntaxShape::Filepath,
+                "JSON/YAML data file to check",
+            )
+            .required(
+                "schema_file",
+                SyntaxShape::Filepath,
+                "KCL file defining the schema",
+            )
+            .named(
+                "schema-name",
+                SyntaxShape::String,
+                "Schema to validate against (defaults to the last schema in the file)",
+                Some('s'),
+            )
+            .named(
+                "format",
+                SyntaxShape::String,
+                "Data format (json/yaml)",
+                Some('f'),
+            )
+            .category(Category::Experimental)
+    }
+
+    fn run(
+        &self,
+        _plugin: &KclWrapperPlugin,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        _input: &Value,
+    ) -> Result<Value, LabeledError> {
+        let data = shell_path(engine, &call.req::<String>(0)?, arg_span(call, 0))?;
+        let schema_file = shell_path(engine, &call.req::<String>(1)?, arg_span(call, 1))?;
+        let schema: Option<String> = call.get_flag("schema-name")?;
+        let format: Option<String> = call.get_flag("format")?;
+        let bin = kcl_binary(engine)?;
+        check_path_arg(call, 0, "Data file", &data)?;
+        check_path_arg(call, 1, "Schema file", &schema_file)?;
+
+        match vet_kcl_data(
+            &bin,
+            &data,
+            &schema_file,
+            schema.as_deref(),
+            format.as_deref(),
+        ) {
+            Ok(result) => Ok(Value::record(
+                record! {
+                    "valid" => Value::bool(result.valid, call.head),
+                    "errors" => Value::list(
+                        result
+                            .errors
+                            .into_iter()
+                            .map(|e| Value::string(e, call.head))
+                            .collect(),
+                        call.head,
+                    ),
+                },
+                call.head,
+            )),
+            Err(e) => Err(kcl_error("Error vetting data", e, arg_span(call, 0))),
+        }
+    }
+
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![
+            Example {
+                example: "kcl-vet data.json schema.k",
+                description: "Check 'data.json' against the schema defined in 'schema.k'.",
+                result: Some(Value::test_record(record! {
+                    "valid" => Value::test_bool(true),
+                    "errors" => Value::test_list(vec![]),
+                })),
+            },
+            Example {
+                example: "kcl-vet data.yaml schema.k --schema-name App --format yaml",
+                description: "Check the YAML file 'data.yaml' against the 'App' schema.",
+                result: Some(Value::test_record(record! {
+                    "valid" => Value::test_bool(true),
+                    "errors" => Value::test_list(vec![]),
+                })),
+            },
+        ]
+    }
+}
+
+/// Command to convert other formats into KCL using the KCL CLI.
+///
+/// # Usage
+/// ```nu
+/// kcl-import crd.yaml --mode crd --output ./models
+/// ```
+///
+/// See `examples()` for more.
+struct KclImport;
+
+impl SimplePluginCommand for KclImport {
+    type Plugin = KclWrapperPlugin;
+
+    fn name(&self) -> &str {
+        "kcl-import"
+    }
+
+    fn description(&self) -> &str {
+        "Convert JSON/YAML/JSON Schema/Protobuf/OpenAPI/CRD files into KCL"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self))
+            .input_output_types(vec![
+                (Type::Any, Type::List(Box::new(Type::String))),
+                (Type::Any, Type::String),
+            ])
+            .required("file", SyntaxShape::Filepath, "File to import")
+            .named(
+                "mode",
+                SyntaxShape::String,
+                "Import mode (json/yaml/jsonschema/protobuf/openapi/crd/auto, default auto)",
+                Some('m'),
+            )
+            .named(
+                "output",
+                SyntaxShape::Directory,
+                "Directory to generate the KCL files in",
+                Some('o'),
+            )
+            .switch(
+                "content",
+                "Return the generated KCL source instead of writing files",
+                Some('c'),
+            )
+            .category(Category::Experimental)
+    }
+
+    fn run(
+        &self,
+        _plugin: &KclWrapperPlugin,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        _input: &Value,
+    ) -> Result<Value, LabeledError> {
+        let file = shell_path(engine, &call.req::<String>(0)?, arg_span(call, 0))?;
+        let mode: String = call.get_flag("mode")?.unwrap_or_else(|| "auto".to_string());
+        // Without --output, KCL generates the files in the shell's current directory.
+        let output = shell_path(
+            engine,
+            &call.get_flag::<String>("output")?.unwrap_or_default(),
+            call.get_flag_span("output").unwrap_or(call.head),
+        )?;
+        let bin = kcl_binary(engine)?;
+        check_path_arg(call, 0, "Input file", &file)?;
+
+        if call.has_flag("content")? {
+            if call.get_flag_value("output").is_some() {
+                return Err(LabeledError::new("Conflicting arguments").with_label(
+                    "--content cannot be used with --output",
+                    call.get_flag_span("output").unwrap_or(call.head),
+                ));
+            }
+            return import_kcl_source(&bin, &file, &mode)
+                .map(|source| Value::string(source, call.head))
+                .map_err(|e| kcl_error("Error importing into KCL", e, arg_span(call, 0)));
+        }
+
+        match import_kcl(&bin, &file, &mode, Some(&output)) {
+            Ok(files) => Ok(Value::list(
+                files
+                    .into_iter()
+                    .map(|file| Value::string(file, call.head))
+                    .collect(),
+                call.head,
+            )),
+            Err(e) => Err(kcl_error("Error importing into KCL", e, arg_span(call, 0))),
+        }
+    }
+
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![
+            Example {
+                example: "kcl-import config.json",
+                description: "Generate KCL from 'config.json', detecting the format.",
+                result: Some(Value::test_list(vec![Value::test_string("./config.k")])),
+            },
+            Example {
+                example: "kcl-import crd.yaml --mode crd --output ./models",
+                description: "Generate KCL schemas from a Kubernetes CRD into './models'.",
+                result: Some(Value::test_list(vec![Value::test_string(
+                    "./models/v1/app.k",
+                )])),
+            },
+            Example {
+                example: "kcl-import config.json --mode json --content",
+                description: "Print the KCL generated from 'config.json' without writing any file.",
+                result: Some(Value::test_string("name = \"web\"\nreplicas = 2\n")),
+            },
+        ]
+    }
+}
+
+/// Command to manage the dependencies of a KCL package using the KCL CLI.
+///
+/// # Usage
+/// ```nu
+/// kcl-mod --action add k8s:1.28
+/// ```
+///
+/// See `examples()` for more.
+struct KclMod;
+
+impl SimplePluginCommand for KclMod {
+    type Plugin = KclWrapperPlugin;
+
+    fn name(&self) -> &str {
+        "kcl-mod"
+    }
+
+    fn description(&self) -> &str {
+        "Add, update or list the dependencies of a KCL package"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self))
+            .input_output_type(Type::Any, Type::record())
+            .optional(
+                "package",
+                SyntaxShape::String,
+                "Package to add (e.g. k8s or k8s:1.28), only for the add action",
+            )
+            .named(
+                "action",
+                SyntaxShape::String,
+                "Action to run (add/update/metadata, default metadata)",
+                Some('a'),
+            )
+            .named(
+                "working-dir",
+                SyntaxShape::Directory,
+                "Directory of the KCL package (defaults to the current directory)",
+                Some('C'),
+            )
+            .category(Category::Experimental)
+    }
+
+    fn run(
+        &self,
+        _plugin: &KclWrapperPlugin,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        _input: &Value,
+    ) -> Result<Value, LabeledError> {
+        let action: String = call
+            .get_flag("action")?
+            .unwrap_or_else(|| "metadata".to_string());
+        let package: Option<String> = call.opt(0)?;
+        if package.is_some() && action != "add" {
+            return Err(LabeledError::new("Unexpected package").with_label(
+                format!("Only the add action takes a package, not {}", action),
+                arg_span(call, 0),
+            ));
+        }
+        let dir = working_dir(engine, call)?;
+        let bin = kcl_binary(engine)?;
+
+        let manifest = kcl_mod(&bin, &action, package.as_deref(), &dir).map_err(|e| {
+            kcl_error(
+                "Error managing KCL dependencies",
+                e,
+                call.get_flag_span("action").unwrap_or(call.head),
+            )
+        })?;
+        Ok(json_to_value(&manifest, call.head))
+    }
+
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![
+            Example {
+                example: "kcl-mod",
+                description: "List the dependencies of the KCL package in the current directory.",
+                result: Some(Value::test_record(record! {
+                    "package" => Value::test_record(record! {
+                        "name" => Value::test_string("app"),
+                        "edition" => Value::test_string("v0.10.0"),
+                        "version" => Value::test_string("0.0.1"),
+                    }),
+                    "dependencies" => Value::test_record(record! {
+                        "k8s" => Value::test_string("1.28"),
+                    }),
+                })),
+            },
+            Example {
+                example: "kcl-mod --action add k8s:1.28",
+                description: "Add version 1.28 of the 'k8s' package to the current package.",
+                result: None,
+            },
+            Example {
+                example: "kcl-mod --action update -C ./app | get dependencies",
+                description: "Update the dependencies of the package in './app' and list them.",
+                result: None,
+            },
+        ]
+    }
+}
+
+/// Command to export the schemas of a KCL package (e.g. to OpenAPI) using the KCL CLI.
+///
+/// # Usage
+/// ```nu
+/// kcl-export ./models --target openapi
+/// ```
+///
+/// See `examples()` for more.
+struct KclExport;
+
+impl SimplePluginCommand for KclExport {
+    type Plugin = KclWrapperPlugin;
+
+    fn name(&self) -> &str {
+        "kcl-export"
+    }
+
+    fn description(&self) -> &str {
+        "Export the schemas of a KCL package to an OpenAPI spec"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self))
+            .input_output_types(vec![(Type::Any, Type::record()), (Type::Any, Type::String)])
+            .optional(
+                "package",
+                SyntaxShape::Filepath,
+                "KCL package directory, or a KCL file of it (defaults to the current directory)",
+            )
+            .named(
+                "target",
+                SyntaxShape::String,
+                "Format to export to (openapi, default openapi)",
+                Some('t'),
+            )
+            .named(
+                "output",
+                SyntaxShape::Filepath,
+                "File to write the spec to (returns its path)",
+                Some('o'),
+            )
+            .category(Category::Experimental)
+    }
+
+    fn run(
+        &self,
+        _plugin: &KclWrapperPlugin,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        _input: &Value,
+    ) -> Result<Value, LabeledError> {
+        let package = shell_path(
+            engine,
+            &call.opt::<String>(0)?.unwrap_or_default(),
+            arg_span(call, 0),
+        )?;
+        let target: String = call
+            .get_flag("target")?
+            .unwrap_or_else(|| "openapi".to_string());
+        let output = match call.get_flag::<String>("output")? {
+            Some(file) => Some(shell_path(
+                engine,
+                &file,
+                call.get_flag_span("output").unwrap_or(call.head),
+            )?),
+            None => None,
+        };
+        let bin = kcl_binary(engine)?;
+        check_path_arg(call, 0, "KCL package", &package)?;
+
+        let spec = export_kcl(&bin, &package, &target).map_err(|e| {
+            kcl_error(
+                "Error exporting KCL",
+                e,
+                call.get_flag_span("target").unwrap_or(arg_span(call, 0)),
+            )
+        })?;
+        if let Some(output) = output {
+            std::fs::write(&output, &spec).map_err(|e| {
+                LabeledError::new("Error writing spec")
+                    .with_label(format!("{}: {}", output, e), call.head)
+            })?;
+            return Ok(Value::string(output, call.head));
+        }
+        // Specs are JSON, fall back to the raw text if KCL ever emits something else.
+        match serde_json::from_str::<serde_json::Value>(&spec) {
+            Ok(json) => Ok(json_to_value(&json, call.head)),
+            Err(_) => Ok(Value::string(spec, call.head)),
+        }
+    }
+
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![
+            Example {
+                example: "kcl-export ./models | get components.schemas | columns",
+                description: "List the schemas of the KCL package in './models' as OpenAPI components.",
+                result: None,
+            },
+            Example {
+                example: "kcl-export ./models --target openapi --output openapi.json",
+                description: "Write the OpenAPI spec of the package in './models' to 'openapi.json'.",
+                result: Some(Value::test_string("openapi.json")),
+            },
+        ]
+    }
+}
+
+/// Command to evaluate an inline KCL expression using the KCL CLI.
+///
+/// # Usage
+/// ```nu
+/// kcl-eval "1 + 2"
+/// "[1, 2] + [3]" | kcl-eval
+/// ```
+///
+/// See `examples()` for more.
+struct KclEval;
+
+impl SimplePluginCommand for KclEval {
+    type Plugin = KclWrapperPlugin;
+
+    fn name(&self) -> &str {
+        "kcl-eval"
+    }
+
+    fn description(&self) -> &str {
+        "Evaluate an inline KCL expression"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self))
+            .input_output_types(vec![(Type::Any, Type::Any), (Type::String, Type::Any)])
+            .optional(
+                "expression",
+                SyntaxShape::String,
+                "KCL expression to evaluate (read from the pipeline if omitted)",
+            )
+            .named(
+                "format",
+                SyntaxShape::String,
+                "Return the value rendered in this format (yaml/json) instead of a Nushell value",
+                Some('f'),
+            )
+            .category(Category::Experimental)
+    }
+
+    fn run(
+        &self,
+        _plugin: &KclWrapperPlugin,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        input: &Value,
+    ) -> Result<Value, LabeledError> {
+        let expression = match (call.opt::<String>(0)?, input) {
+            (Some(expression), _) => expression,
+            (None, Value::String { val, .. }) if !val.trim().is_empty() => val.clone(),
+            _ => {
+                return Err(LabeledError::new("No KCL expression")
+                    .with_label("Pass a KCL expression or pipe one in", call.head));
+            }
+        };
+        // Only what looks like a path is checked, so that e.g. `kcl-eval a` still works
+        // next to a file named `a`.
+        let trimmed = expression.trim();
+        if (trimmed.ends_with(".k") || trimmed.contains(std::path::is_separator))
+            && std::path::Path::new(&shell_cwd(engine)?)
+                .join(trimmed)
+                .is_file()
+        {
+            return Err(LabeledError::new("Expected a KCL expression")
+                .with_label(format!("'{}' is a file", trimmed), call.head)
+                .with_help("Use kcl-run to run KCL files"));
+        }
+        let format: Option<String> = call.get_flag("format")?;
+        let bin = kcl_binary(engine)?;
+
+        let value = eval_kcl(&bin, &expression)
+            .map_err(|e| kcl_error("Error evaluating KCL", e, call.head))?;
+
+        let rendered = match format.as_deref() {
+            None => return Ok(json_to_value(&value, call.head)),
+            Some("json") => serde_json::to_string_pretty(&value).map_err(anyhow::Error::from),
+            Some("yaml") => serde_yaml::to_string(&value).map_err(anyhow::Error::from),
+            Some(other) => {
+                return Err(LabeledError::new("Unsupported format").with_label(
+                    format!("'{}' is not one of: yaml, json", other),
+                    call.get_flag_span("format").unwrap_or(call.head),
+                ));
+            }
+        };
+        rendered
+            .map(|text| Value::string(text, call.head))
+            .map_err(|e| {
+                LabeledError::new("Error rendering KCL value").with_label(e.to_string(), call.head)
+            })
+    }
+
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![
+            Example {
+                example: "kcl-eval \"1 + 2\"",
+                description: "Evaluate an arithmetic expression.",
+                result: Some(Value::test_int(3)),
+            },
+            Example {
+                example: "\"{name = 'app', replicas = 2}\" | kcl-eval",
+                description: "Evaluate a config piped in as a string.",
+                result: Some(Value::test_record(record! {
+                    "name" => Value::test_string("app"),
+                    "replicas" => Value::test_int(2),
+                })),
+            },
+            Example {
+                example: "kcl-eval \"[1, 2] + [3]\" --format json",
+                description: "Evaluate a list and render it as JSON.",
+                result: Some(Value::test_string("[\n  1,\n  2,\n  3\n]")),
+            },
+        ]
+    }
+}
+
+/// Command to compare the results of two KCL evaluations key by key.
+///
+/// # Usage
+/// ```nu
+/// kcl-diff old.k new.k
+/// kcl-diff app.k --old-define [env=dev] --new-define [env=prod]
+/// ```
+///
+/// See `examples()` for more.
+struct KclDiff;
+
+impl SimplePluginCommand for KclDiff {
+    type Plugin = KclWrapperPlugin;
+
+    fn name(&self) -> &str {
+        "kcl-diff"
+    }
+
+    fn description(&self) -> &str {
+        "Compare the results of two KCL files, or of one file with two sets of defines"
+    }
+
+    fn signature(&self) -> Signature {
+        let defines = || {
+            SyntaxShape::OneOf(vec![
+                SyntaxShape::List(Box::new(SyntaxShape::String)),
+                SyntaxShape::String,
+            ])
+        };
+        Signature::build(PluginCommand::name(self))
+            .input_output_type(Type::Nothing, Type::table())
+            .required("old", SyntaxShape::Filepath, "KCL file of the old side")
+            .optional(
+                "new",
+                SyntaxShape::Filepath,
+                "KCL file of the new side (defaults to the old file)",
+            )
+            .named(
+                "define",
+                defines(),
+                "Variables to define (key=value) on both sides",
+                Some('D'),
+            )
+            .named(
+                "old-define",
+                defines(),
+                "Variables to define (key=value) on the old side only",
+                None,
+            )
+            .named(
+                "new-define",
+                defines(),
+                "Variables to define (key=value) on the new side only",
+                None,
+            )
+            .category(Category::Experimental)
+    }
+
+    fn run(
+        &self,
+        _plugin: &KclWrapperPlugin,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        _input: &Value,
+    ) -> Result<Value, LabeledError> {
+        let old_file = shell_path(engine, &call.req::<String>(0)?, arg_span(call, 0))?;
+        check_path_arg(call, 0, "KCL file", &old_file)?;
+        let new_file = match call.opt::<String>(1)? {
+            Some(file) => {
+                let file = shell_path(engine, &file, arg_span(call, 1))?;
+                check_path_arg(call, 1, "KCL file", &file)?;
+                file
+            }
+            None => old_file.clone(),
+        };
+        let old_defines = string_list_flag(call, "old-define")?;
+        let new_defines = string_list_flag(call, "new-define")?;
+        if call.positional.len() < 2 && old_defines.is_empty() && new_defines.is_empty() {
+            return Err(LabeledError::new("Missing argument")
+                .with_label("Nothing to compare the file with", call.head)
+                .with_help("Pass a second file, or --old-define/--new-define"));
+        }
+        let defines = string_list_flag(call, "define")?;
+        let bin = kcl_binary(engine)?;
+        let side = |file: &str, extra: &[String]| {
+            RunOptions::new(file)
+                .bin(&bin)
+                .defines(defines.iter().chain(extra))
+        };
+
+        let changes = diff_kcl(
+            &side(&old_file, &old_defines),
+            &side(&new_file, &new_defines),
+        )
+        .map_err(|e| kcl_run_error(e, call.head))?;
+        let span = call.head;
+        let side_value = |value: &Option<serde_json::Value>| match value {
+            Some(value) => json_to_value(value, span),
+            None => Value::nothing(span),
+        };
+        Ok(Value::list(
+            changes
+                .iter()
+                .map(|change| {
+                    Value::record(
+                        record! {
+                            "path" => Value::string(&change.path, span),
+                            "change" => Value::string(change.change, span),
+                            "old" => side_value(&change.old),
+                            "new" => side_value(&change.new),
+                        },
+                        span,
+                    )
+                })
+                .collect(),
+            span,
+        ))
+    }
+
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![
+            Example {
+                example: "kcl-diff old.k new.k",
+                description: "List the keys that differ between the configs of two files.",
+                result: None,
+            },
+            Example {
+                example: "kcl-diff app.k --old-define [env=dev] --new-define [env=prod]",
+                description: "Show what changes in 'app.k' between the dev and prod environments.",
+                result: None,
+            },
+        ]
+    }
+}
+
+/// Command to report the version of the KCL CLI driven by the plugin.
+///
+/// # Usage
+/// ```nu
+/// kcl-version
+/// ```
+struct KclVersion;
+
+impl SimplePluginCommand for KclVersion {
+    type Plugin = KclWrapperPlugin;
+
+    fn name(&self) -> &str {
+        "kcl-version"
+    }
+
+    fn description(&self) -> &str {
+        "Report the KCL CLI and plugin versions"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self))
+            .input_output_type(Type::Nothing, Type::record())
+            .category(Category::Experimental)
+    }
+
+    fn run(
+        &self,
+        _plugin: &KclWrapperPlugin,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        _input: &Value,
+    ) -> Result<Value, LabeledError> {
+        let bin = kcl_binary(engine)?;
+        let info = kcl_version_info(&bin)
+            .map_err(|e| kcl_error("Error getting the KCL version", e, call.head))?;
+        let optional = |value: Option<String>| {
+            value.map_or(Value::nothing(call.head), |v| Value::string(v, call.head))
+        };
+        let (version, git_commit, platform) = match info {
+            Some(info) => (Some(info.version), info.git_commit, info.platform),
+            None => (None, None, None),
+        };
+
+        Ok(Value::record(
+            record! {
+                "version" => optional(version),
+                "git_commit" => optional(git_commit),
+                "platform" => optional(platform),
+                "plugin_version" => Value::string(env!("CARGO_PKG_VERSION"), call.head),
+            },
+            call.head,
+        ))
+    }
+
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![Example {
+            example: "kcl-version",
+            description: "Show which KCL CLI version the plugin runs.",
+            result: Some(Value::test_record(record! {
+                "version" => Value::test_string("0.10.0"),
+                "git_commit" => Value::test_nothing(),
+                "platform" => Value::test_string("linux-amd64"),
+                "plugin_version" => Value::test_string("0.1.0"),
+            })),
+        }]
+    }
+}
+
+/// Convert a JSON value produced by KCL into a Nushell value.
+fn json_to_value(value: &serde_json::Value, span: Span) -> Value {
+    match value {
+        serde_json::Value::Null => Value::nothing(span),
+        serde_json::Value::Bool(b) => Value::bool(*b, span),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Value::int(i, span),
+            None => Value::float(n.as_f64().unwrap_or_default(), span),
+        },
+        serde_json::Value::String(s) => Value::string(s, span),
+        serde_json::Value::Array(items) => Value::list(
+            items.iter().map(|item| json_to_value(item, span)).collect(),
+            span,
+        ),
+        serde_json::Value::Object(map) => Value::record(
+            map.iter()
+                .map(|(key, item)| (key.clone(), json_to_value(item, span)))
+                .collect(),
+            span,
+        ),
     }
 }
 
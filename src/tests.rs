@@ -1,11 +1,27 @@
 /// Unit tests for KCL plugin helpers.
 ///
 /// These tests check the behavior of running, formatting, and validating KCL files
-/// using the KCL CLI. All tests are skipped if the `kcl` binary is not installed.
+/// using the KCL CLI. Tests that need the `kcl` binary are skipped if it is not installed.
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
     // use super::*;
-    use crate::helpers::{format_kcl_file, run_kcl_command, validate_kcl_project};
+    use crate::helpers::{
+        FileValidation, KclNotFoundError, KclRunner, KclVersionInfo, RunOptions, ValidateOptions,
+        ValidationCounts, apply_type_hints, check_kcl_format, eval_kcl, exe_name, expand_path,
+        find_package_root, format_kcl_diff, format_kcl_file, format_kcl_file_with,
+        ignore_errors_matching, import_kcl, import_kcl_source, is_skipped_dir, json_diff,
+        json_report, json_to_toml, junit_report, kcl_command, kcl_files, kcl_package_dir,
+        kcl_version_info, output_with_timeout, parse_diagnostics, parse_env_vars,
+        parse_kcl_version_info, parse_output, parse_test_output, plain_text, read_kcl_mod,
+        read_overrides_file, resolve_kcl_binary, run_hook, run_kcl, run_kcl_command,
+        run_kcl_command_with, run_kcl_files, run_kcl_tests, select_files, stream_kcl, strip_ansi,
+        validate_kcl, validate_kcl_project, validate_kcl_project_with, validation_counts,
+        validation_summary, vet_kcl_data, write_checksum_file, write_if_changed, write_temp_kcl,
+    };
+    use crate::{KclWrapperPlugin, json_to_value, kcl_run_error, validation_records};
+    use nu_plugin_test_support::PluginTest;
+    use nu_protocol::{PipelineData, Span, Value, record};
     use std::io::Write;
     use std::process::Command;
     use tempfile::{NamedTempFile, tempdir};
@@ -27,10 +43,10 @@ mod tests {
             .path()
             .to_str()
             .expect("Temp file path is not valid UTF-8");
-        let res = run_kcl_command(path, "yaml", &None, &[]);
+        let res = run_kcl_command(&RunOptions::new(path).bin("kcl"));
         assert!(res.is_ok(), "Expected Ok, got: {:?}", res);
         let out = res.expect("run_kcl_command returned Err unexpectedly");
-        assert!(out.contains("a = 1") || out.contains("✅") || out.contains("a: 1"));
+        assert!(out.text.contains("a = 1") || out.text.contains("✅") || out.text.contains("a: 1"));
     }
 
     /// Test that formatting a valid KCL file with `format_kcl_file` succeeds.
@@ -45,12 +61,29 @@ mod tests {
             .path()
             .to_str()
             .expect("Temp file path is not valid UTF-8");
-        let res = format_kcl_file(path);
+        let res = format_kcl_file("kcl", path);
         assert!(res.is_ok(), "Expected Ok, got: {:?}", res);
         let out = res.expect("format_kcl_file returned Err unexpectedly");
         assert!(out.contains("formatted"));
     }
 
+    /// Test that `check_kcl_format` reports a badly formatted file without modifying it.
+    #[test]
+    fn test_check_kcl_format() {
+        if !kcl_installed() {
+            return;
+        }
+        let dir = tempdir().expect("Failed to create temp dir");
+        let file = dir.path().join("ugly.k");
+        std::fs::write(&file, "a=1\nb   =  2\n").expect("Failed to write KCL file");
+        let path = file.to_string_lossy();
+        let unformatted =
+            check_kcl_format("kcl", &path, false).expect("check_kcl_format returned Err");
+        assert_eq!(unformatted, vec![path.to_string()]);
+        let content = std::fs::read_to_string(&file).expect("Failed to read KCL file");
+        assert_eq!(content, "a=1\nb   =  2\n");
+    }
+
     /// Test that validating a directory with a valid KCL file using `validate_kcl_project` succeeds.
     #[test]
     fn test_validate_kcl_project_success() {
@@ -61,13 +94,168 @@ mod tests {
         let file_path = dir.path().join("test.k");
         std::fs::write(&file_path, "a = 1").expect("Failed to write KCL code to temp file");
         let res = validate_kcl_project(
+            "kcl",
             dir.path()
                 .to_str()
                 .expect("Temp dir path is not valid UTF-8"),
+            None,
+            None,
+            0,
+            None,
         );
         assert!(res.is_ok(), "Expected Ok, got: {:?}", res);
-        let out = res.expect("validate_kcl_project returned Err unexpectedly");
-        assert!(out.contains("valid") || out.contains("✅"));
+        let results = res.expect("validate_kcl_project returned Err unexpectedly");
+        assert_eq!(results.len(), 1);
+        assert!(results[0].is_valid());
+    }
+
+    /// Test that vetting data files against a schema with `validate_kcl_project` reports per-file results.
+    #[test]
+    fn test_validate_kcl_project_schema() {
+        if !kcl_installed() {
+            return;
+        }
+        let dir = tempdir().expect("Failed to create temp dir");
+        let schema_path = dir.path().join("schema.k");
+        std::fs::write(
+            &schema_path,
+            "schema App:\n    name: str\n    replicas: int\n",
+        )
+        .expect("Failed to write KCL schema to temp file");
+        let data_dir = dir.path().join("data");
+        std::fs::create_dir(&data_dir).expect("Failed to create data dir");
+        std::fs::write(
+            data_dir.join("good.json"),
+            r#"{"name": "web", "replicas": 2}"#,
+        )
+        .expect("Failed to write valid data file");
+        std::fs::write(
+            data_dir.join("bad.json"),
+            r#"{"name": "web", "replicas": "two"}"#,
+        )
+        .expect("Failed to write invalid data file");
+        let res = validate_kcl_project(
+            "kcl",
+            data_dir.to_str().expect("Temp dir path is not valid UTF-8"),
+            Some(
+                schema_path
+                    .to_str()
+                    .expect("Schema path is not valid UTF-8"),
+            ),
+            None,
+            0,
+            None,
+        );
+        let results = res.expect("validate_kcl_project returned Err unexpectedly");
+        let out = validation_summary("data", Some("schema.k"), &results);
+        assert!(out.contains("✅") && out.contains("good.json"));
+        assert!(out.contains("❌") && out.contains("bad.json"));
+    }
+
+    /// Test that a directory with valid and invalid KCL files yields one record per file.
+    #[test]
+    fn test_validate_kcl_project_mixed_records() {
+        if !kcl_installed() {
+            return;
+        }
+        let dir = tempdir().expect("Failed to create temp dir");
+        std::fs::write(dir.path().join("good.k"), "a = 1").expect("Failed to write valid KCL file");
+        std::fs::write(dir.path().join("bad.k"), "a = ").expect("Failed to write invalid KCL file");
+        let results = validate_kcl_project(
+            "kcl",
+            dir.path()
+                .to_str()
+                .expect("Temp dir path is not valid UTF-8"),
+            None,
+            None,
+            0,
+            None,
+        )
+        .expect("validate_kcl_project returned Err unexpectedly");
+        let table = validation_records(&results, Span::test_data());
+        let rows = table.as_list().expect("Expected a list of records");
+        assert_eq!(rows.len(), 2);
+        for row in rows {
+            let row = row.as_record().expect("Expected a record");
+            let file = row.get("file").and_then(|v| v.as_str().ok()).unwrap_or("");
+            let status = row
+                .get("status")
+                .and_then(|v| v.as_str().ok())
+                .unwrap_or("");
+            let error = row.get("error").expect("Missing error column");
+            if file.ends_with("good.k") {
+                assert_eq!(status, "valid");
+                assert!(error.is_nothing());
+            } else {
+                assert_eq!(status, "invalid");
+                assert!(error.as_str().is_ok());
+            }
+        }
+    }
+
+    /// Test that validation results map to records with `file`, `status` and `error` columns.
+    #[test]
+    fn test_validation_records_shape() {
+        let results = vec![
+            FileValidation {
+                file: "good.k".to_string(),
+                error: None,
+                ignored: false,
+            },
+            FileValidation {
+                file: "bad.k".to_string(),
+                error: Some("syntax error".to_string()),
+                ignored: false,
+            },
+        ];
+        let table = validation_records(&results, Span::test_data());
+        let rows = table.as_list().expect("Expected a list of records");
+        assert_eq!(rows.len(), 2);
+        let good = rows[0].as_record().expect("Expected a record");
+        assert_eq!(
+            good.columns().collect::<Vec<_>>(),
+            ["file", "valid", "status", "error"]
+        );
+        assert_eq!(good.get("valid"), Some(&Value::test_bool(true)));
+        assert_eq!(good.get("status"), Some(&Value::test_string("valid")));
+        assert_eq!(good.get("error"), Some(&Value::test_nothing()));
+        let bad = rows[1].as_record().expect("Expected a record");
+        assert_eq!(bad.get("valid"), Some(&Value::test_bool(false)));
+        assert_eq!(bad.get("status"), Some(&Value::test_string("invalid")));
+        assert_eq!(bad.get("error"), Some(&Value::test_string("syntax error")));
+    }
+
+    /// Test that `validation_counts` counts ignored failures apart from invalid files.
+    #[test]
+    fn test_validation_counts() {
+        let result = |file: &str, error: Option<&str>, ignored| FileValidation {
+            file: file.to_string(),
+            error: error.map(str::to_string),
+            ignored,
+        };
+        let results = [
+            result("a.k", None, false),
+            result("b.k", Some("syntax error"), false),
+            result("c.k", Some("known issue"), true),
+            result("d.k", None, false),
+        ];
+        assert_eq!(
+            validation_counts(&results),
+            ValidationCounts {
+                total: 4,
+                valid: 2,
+                invalid: 1,
+                ignored: 1,
+            }
+        );
+        assert!(!validation_counts(&results).all_valid());
+        assert!(
+            validation_counts(&[
+                result("a.k", None, false),
+                result("c.k", Some("known issue"), true)
+            ])
+            .all_valid()
+        );
     }
 
     /// Test that running a nonexistent KCL file with `run_kcl_command` returns an error.
@@ -76,7 +264,4155 @@ mod tests {
         if !kcl_installed() {
             return;
         }
-        let res = run_kcl_command("nonexistent.k", "yaml", &None, &[]);
+        let res = run_kcl_command(&RunOptions::new("nonexistent.k").bin("kcl"));
+        assert!(res.is_err(), "Expected Err, got: {:?}", res);
+    }
+
+    /// Test that `parse_output` accepts well-formed output and rejects malformed output.
+    #[test]
+    fn test_parse_output() {
+        let docs = parse_output("a: 1\n---\nb: 2\n", "yaml").expect("Valid YAML was rejected");
+        assert_eq!(docs.len(), 2);
+        assert!(parse_output("{\"a\": 1}", "json").is_ok());
+        assert!(parse_output("{\"a\": 1", "json").is_err());
+        assert!(parse_output("a: [1, 2", "yaml").is_err());
+    }
+
+    /// Test that `parse_test_output` extracts pass/fail results from `kcl test` output.
+    #[test]
+    fn test_parse_test_output() {
+        let text = "test_ok: PASS (2ms)\ntest_bad: FAIL (1ms)\n  EvaluationError\n--------\nFAIL: 1/2 passed\n";
+        let cases = parse_test_output(text);
+        assert_eq!(cases.len(), 2);
+        assert_eq!(cases[0].name, "test_ok");
+        assert!(cases[0].passed);
+        assert_eq!(cases[0].duration.as_deref(), Some("2ms"));
+        assert_eq!(cases[1].name, "test_bad");
+        assert!(!cases[1].passed);
+    }
+
+    /// Test that `run_kcl_tests` reports a passing KCL test.
+    #[test]
+    fn test_run_kcl_tests_success() {
+        if !kcl_installed() {
+            return;
+        }
+        let dir = tempdir().expect("Failed to create temp dir");
+        std::fs::write(
+            dir.path().join("main_test.k"),
+            "test_one = lambda {\n    assert 1 + 1 == 2\n}\n",
+        )
+        .expect("Failed to write KCL test file");
+        let res = run_kcl_tests(
+            "kcl",
+            dir.path()
+                .to_str()
+                .expect("Temp dir path is not valid UTF-8"),
+            None,
+        );
+        let cases = res.expect("run_kcl_tests returned Err unexpectedly");
+        assert!(cases.iter().all(|case| case.passed));
+    }
+
+    /// Test that `apply_type_hints` renders define values as KCL literals of the hinted type.
+    #[test]
+    fn test_apply_type_hints() {
+        let defines = vec![
+            "replicas=3".to_string(),
+            "ratio=0.5".to_string(),
+            "debug=true".to_string(),
+            "tag=3".to_string(),
+            "other=x".to_string(),
+        ];
+        let hints = vec![
+            ("replicas".to_string(), "int".to_string()),
+            ("ratio".to_string(), "float".to_string()),
+            ("debug".to_string(), "bool".to_string()),
+            ("tag".to_string(), "string".to_string()),
+        ];
+        let res = apply_type_hints(&defines, &hints).expect("apply_type_hints returned Err");
+        assert_eq!(
+            res,
+            [
+                "replicas=3",
+                "ratio=0.5",
+                "debug=True",
+                "tag=\"3\"",
+                "other=x"
+            ]
+        );
+
+        let bad = vec![("replicas".to_string(), "bool".to_string())];
+        assert!(apply_type_hints(&defines, &bad).is_err());
+        let unknown = vec![("replicas".to_string(), "list".to_string())];
+        assert!(apply_type_hints(&defines, &unknown).is_err());
+    }
+
+    /// Test that an overridden binary path is used to build the KCL `Command`.
+    #[test]
+    fn test_kcl_command_uses_binary_override() {
+        let cmd = kcl_command("/opt/kcl/bin/kcl");
+        assert_eq!(cmd.get_program(), "/opt/kcl/bin/kcl");
+
+        let res = run_kcl_command(&RunOptions::new("main.k").bin("/nonexistent/bin/kcl"));
         assert!(res.is_err(), "Expected Err, got: {:?}", res);
     }
+
+    /// Test that `vet_kcl_data` accepts conforming data and reports errors otherwise.
+    #[test]
+    fn test_vet_kcl_data() {
+        if !kcl_installed() {
+            return;
+        }
+        let dir = tempdir().expect("Failed to create temp dir");
+        let schema = dir.path().join("schema.k");
+        std::fs::write(&schema, "schema App:\n    name: str\n")
+            .expect("Failed to write KCL schema to temp file");
+        let good = dir.path().join("good.json");
+        std::fs::write(&good, r#"{"name": "web"}"#).expect("Failed to write valid data file");
+        let bad = dir.path().join("bad.json");
+        std::fs::write(&bad, r#"{"name": 1}"#).expect("Failed to write invalid data file");
+        let schema = schema.to_str().expect("Schema path is not valid UTF-8");
+
+        let res = vet_kcl_data(
+            "kcl",
+            good.to_str().expect("Data path is not valid UTF-8"),
+            schema,
+            Some("App"),
+            None,
+        );
+        let res = res.expect("vet_kcl_data returned Err unexpectedly");
+        assert!(
+            res.valid && res.errors.is_empty(),
+            "Unexpected result: {:?}",
+            res
+        );
+
+        let res = vet_kcl_data(
+            "kcl",
+            bad.to_str().expect("Data path is not valid UTF-8"),
+            schema,
+            Some("App"),
+            Some("json"),
+        );
+        let res = res.expect("vet_kcl_data returned Err unexpectedly");
+        assert!(
+            !res.valid && !res.errors.is_empty(),
+            "Unexpected result: {:?}",
+            res
+        );
+    }
+
+    /// Test that `junit_report` emits one test case per file and a failure for invalid files.
+    #[test]
+    fn test_junit_report() {
+        let results = vec![
+            FileValidation {
+                file: "good.k".to_string(),
+                error: None,
+                ignored: false,
+            },
+            FileValidation {
+                file: "bad.k".to_string(),
+                error: Some("expected <expr> & more".to_string()),
+                ignored: false,
+            },
+        ];
+        let xml = junit_report(&results);
+        assert!(xml.contains("tests=\"2\" failures=\"1\""));
+        assert!(xml.contains("<testcase classname=\"kcl-validate\" name=\"good.k\"/>"));
+        assert!(xml.contains(
+            "<failure message=\"invalid KCL\">expected &lt;expr&gt; &amp; more</failure>"
+        ));
+
+        let json = json_report(&results).expect("json_report returned Err");
+        assert!(json.contains("\"status\": \"invalid\""));
+    }
+
+    /// Test the CI flow of `kcl-validate`: summary output, JUnit report file and failure on invalid files.
+    #[test]
+    fn test_kcl_validate_ci_flow() {
+        if !kcl_installed() {
+            return;
+        }
+        let dir = tempdir().expect("Failed to create temp dir");
+        std::fs::write(dir.path().join("good.k"), "a = 1").expect("Failed to write valid KCL file");
+        std::fs::write(dir.path().join("bad.k"), "a = ").expect("Failed to write invalid KCL file");
+        let report = dir.path().join("report.xml");
+        let source = format!(
+            "kcl-validate '{}' --summary --output-format junit --report-file '{}' --fail-on-invalid --quiet",
+            dir.path().display(),
+            report.display()
+        );
+
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+        let printed = spy_print(&mut plugin_test);
+        let res = plugin_test.eval(&source);
+        let err = res.expect_err("Expected kcl-validate to fail on an invalid file");
+        let msg = format!("{:?}", err);
+        assert!(
+            msg.contains("1 of 2 files are invalid"),
+            "Unexpected error: {}",
+            msg
+        );
+        assert!(msg.contains("good.k") && msg.contains("bad.k"));
+        let printed = printed.lock().unwrap();
+        assert_eq!(printed.len(), 1, "Expected the summary to be printed once");
+        assert!(
+            printed[0].starts_with("❌ Errors found in some files"),
+            "{}",
+            printed[0]
+        );
+        assert!(printed[0].contains("good.k") && printed[0].contains("bad.k"));
+
+        let xml = std::fs::read_to_string(&report).expect("JUnit report was not written");
+        assert!(xml.contains("tests=\"2\" failures=\"1\""));
+    }
+
+    /// Test that an unknown `--output-format` is rejected before KCL runs, even without
+    /// `--report-file`.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_validate_output_format_checked_first() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let log = dir.path().join("runs");
+        let bin = fake_kcl(dir.path(), &format!("echo run >> '{}'", log.display()));
+        kcl_file(dir.path(), "main.k");
+        let err = run_plugin(
+            &bin,
+            &format!(
+                "kcl-validate '{}' --output-format xml",
+                dir.path().display()
+            ),
+        )
+        .expect_err("Expected kcl-validate to reject the format");
+        assert!(
+            err.contains("'xml' is not one of: junit, json"),
+            "Unexpected error: {}",
+            err
+        );
+        assert!(!log.exists(), "KCL ran before the format was checked");
+    }
+
+    /// Test that `--summary --fail-on-invalid` prints the summary before failing, without
+    /// emoji in plain mode.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_validate_fail_on_invalid_prints_summary() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(
+            dir.path(),
+            "case \"$2\" in *bad.k) echo 'error in bad.k' >&2; exit 1;; esac",
+        );
+        let good = kcl_file(dir.path(), "good.k");
+        let bad = kcl_file(dir.path(), "bad.k");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+        let printed = spy_print(&mut plugin_test);
+
+        for flags in ["", "--plain"] {
+            let err = eval_plugin(
+                &mut plugin_test,
+                &bin,
+                &format!(
+                    "kcl-validate '{}' --summary --fail-on-invalid --quiet {}",
+                    dir.path().display(),
+                    flags
+                ),
+            )
+            .expect_err("Expected kcl-validate to fail on an invalid file");
+            assert!(err.contains("1 of 2 files are invalid"));
+        }
+        assert_eq!(
+            *printed.lock().unwrap(),
+            [
+                format!(
+                    "❌ Errors found in some files\n\n❌ {}: error in bad.k\n\n✅ {}",
+                    bad.display(),
+                    good.display()
+                ),
+                format!(
+                    "FAIL Errors found in some files\n\nFAIL {}: error in bad.k\n\nOK {}",
+                    bad.display(),
+                    good.display()
+                ),
+            ]
+        );
+
+        // Without --summary the table is not printed, the error alone reports the failure.
+        eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!(
+                "kcl-validate '{}' --fail-on-invalid --quiet",
+                dir.path().display()
+            ),
+        )
+        .expect_err("Expected kcl-validate to fail on an invalid file");
+        assert_eq!(printed.lock().unwrap().len(), 2);
+    }
+
+    /// Test that `output_with_timeout` kills a command that runs past its timeout.
+    #[cfg(unix)]
+    #[test]
+    fn test_output_with_timeout() {
+        let mut slow = Command::new("sleep");
+        slow.arg("5");
+        let start = std::time::Instant::now();
+        let res = output_with_timeout(&mut slow, Some(std::time::Duration::from_millis(200)))
+            .expect("Failed to run sleep");
+        assert!(res.is_none(), "Expected a timeout, got: {:?}", res);
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+
+        let mut fast = Command::new("echo");
+        fast.arg("done");
+        let res = output_with_timeout(&mut fast, Some(std::time::Duration::from_secs(5)))
+            .expect("Failed to run echo")
+            .expect("echo timed out unexpectedly");
+        assert_eq!(String::from_utf8_lossy(&res.stdout).trim(), "done");
+    }
+
+    /// Test that `run_hook` runs shell commands and reports failing ones.
+    #[cfg(unix)]
+    #[test]
+    fn test_run_hook() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let marker = dir.path().join("marker");
+        run_hook(&format!("touch '{}'", marker.display()), None, &[]).expect("Hook returned Err");
+        assert!(marker.exists());
+
+        let env = [("HOOK_NAME".to_string(), "relative".to_string())];
+        run_hook("touch \"$HOOK_NAME\"", dir.path().to_str(), &env).expect("Hook returned Err");
+        assert!(dir.path().join("relative").exists());
+
+        let err =
+            run_hook("echo boom >&2; exit 3", None, &[]).expect_err("Expected the hook to fail");
+        assert!(
+            err.to_string().contains("boom"),
+            "Unexpected error: {}",
+            err
+        );
+    }
+
+    /// Test that `import_kcl` rejects unknown modes before invoking the KCL CLI.
+    #[test]
+    fn test_import_kcl_unknown_mode() {
+        let err = import_kcl("/nonexistent/bin/kcl", "data.json", "xml", None)
+            .expect_err("Expected an unknown mode error");
+        assert!(err.to_string().contains("Unknown import mode 'xml'"));
+    }
+
+    /// Test that `import_kcl` generates a KCL file from JSON data.
+    #[test]
+    fn test_import_kcl_json() {
+        if !kcl_installed() {
+            return;
+        }
+        let dir = tempdir().expect("Failed to create temp dir");
+        let data = dir.path().join("data.json");
+        std::fs::write(&data, r#"{"name": "web", "replicas": 2}"#)
+            .expect("Failed to write JSON file");
+        let out = dir.path().join("out");
+        std::fs::create_dir(&out).expect("Failed to create output dir");
+        let files = import_kcl(
+            "kcl",
+            data.to_str().expect("Data path is not valid UTF-8"),
+            "json",
+            Some(out.to_str().expect("Output path is not valid UTF-8")),
+        )
+        .expect("import_kcl returned Err unexpectedly");
+        assert!(files.iter().all(|file| file.ends_with(".k")));
+    }
+
+    /// Test that `write_temp_kcl` writes the source to a `.k` file removed on drop.
+    #[test]
+    fn test_write_temp_kcl() {
+        let temp = write_temp_kcl("a = 1").expect("write_temp_kcl returned Err");
+        let path = temp.path().to_path_buf();
+        assert_eq!(path.extension().and_then(|e| e.to_str()), Some("k"));
+        assert_eq!(
+            std::fs::read_to_string(&path).expect("Failed to read temp file"),
+            "a = 1"
+        );
+        drop(temp);
+        assert!(!path.exists());
+    }
+
+    /// Test that KCL source piped into `kcl-run` is evaluated with its defines.
+    #[test]
+    fn test_kcl_run_piped_source() {
+        if !kcl_installed() {
+            return;
+        }
+        let input = PipelineData::Value(Value::test_string("a = 1\nb = option(\"b\")"), None);
+        let out = PluginTest::new("kcl", KclWrapperPlugin.into())
+            .expect("Failed to create plugin test")
+            .eval_with("kcl-run -D b=2", input)
+            .expect("kcl-run returned Err unexpectedly")
+            .into_value(Span::test_data())
+            .expect("Failed to collect kcl-run output");
+        let out = out.as_str().expect("Expected a string");
+        assert!(out.contains("a: 1"), "Unexpected output: {}", out);
+    }
+
+    /// Test that `kcl-run` errors when given neither a file nor non-empty piped source.
+    #[test]
+    fn test_kcl_run_without_source() {
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+        assert!(plugin_test.eval("kcl-run").is_err());
+        let blank = PipelineData::Value(Value::test_string("  \n"), None);
+        assert!(plugin_test.eval_with("kcl-run", blank).is_err());
+    }
+
+    /// Test that `run_kcl_files` runs two valid files separately.
+    #[test]
+    fn test_run_kcl_files_success() {
+        if !kcl_installed() {
+            return;
+        }
+        let dir = tempdir().expect("Failed to create temp dir");
+        let first = dir.path().join("first.k");
+        std::fs::write(&first, "a = 1").expect("Failed to write first KCL file");
+        let second = dir.path().join("second.k");
+        std::fs::write(&second, "b = 2").expect("Failed to write second KCL file");
+        let files = vec![
+            first.to_string_lossy().into_owned(),
+            second.to_string_lossy().into_owned(),
+        ];
+        let results = run_kcl_files(
+            &RunOptions {
+                files: files.clone(),
+                ..RunOptions::default()
+            }
+            .bin("kcl"),
+        );
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].0, files[0]);
+        assert!(
+            results[0]
+                .1
+                .as_ref()
+                .is_ok_and(|out| out.text.contains("a: 1"))
+        );
+        assert!(
+            results[1]
+                .1
+                .as_ref()
+                .is_ok_and(|out| out.text.contains("b: 2"))
+        );
+    }
+
+    /// Test that `run_kcl_files` keeps running after a failing file and reports it.
+    #[test]
+    fn test_run_kcl_files_mixed() {
+        if !kcl_installed() {
+            return;
+        }
+        let dir = tempdir().expect("Failed to create temp dir");
+        let good = dir.path().join("good.k");
+        std::fs::write(&good, "a = 1").expect("Failed to write valid KCL file");
+        let bad = dir.path().join("bad.k");
+        std::fs::write(&bad, "a = ").expect("Failed to write invalid KCL file");
+        let files = vec![
+            bad.to_string_lossy().into_owned(),
+            good.to_string_lossy().into_owned(),
+        ];
+        let results = run_kcl_files(
+            &RunOptions {
+                files: files.clone(),
+                ..RunOptions::default()
+            }
+            .bin("kcl"),
+        );
+        assert_eq!(results.len(), 2);
+        assert!(results[0].1.is_err());
+        assert!(results[1].1.is_ok());
+    }
+
+    /// Test that `write_checksum_file` writes a `sha256sum` compatible sidecar.
+    #[test]
+    fn test_write_checksum_file() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let file = dir.path().join("out.yaml");
+        std::fs::write(&file, "abc").expect("Failed to write output file");
+        let sidecar =
+            write_checksum_file(&file.to_string_lossy()).expect("Failed to write checksum file");
+        assert_eq!(sidecar, format!("{}.sha256", file.to_string_lossy()));
+        let contents = std::fs::read_to_string(&sidecar).expect("Failed to read checksum file");
+        assert_eq!(
+            contents,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  out.yaml\n"
+        );
+    }
+
+    /// Test that errors matching the allowlist are ignored and counted apart in the summary.
+    #[test]
+    fn test_ignore_errors_matching() {
+        let mut results = vec![
+            FileValidation {
+                file: "main.k".to_string(),
+                error: None,
+                ignored: false,
+            },
+            FileValidation {
+                file: "lib.k".to_string(),
+                error: Some("lib.k: cannot run library directly".to_string()),
+                ignored: false,
+            },
+            FileValidation {
+                file: "bad.k".to_string(),
+                error: Some("syntax error".to_string()),
+                ignored: false,
+            },
+        ];
+        let pattern = regex::Regex::new("library").expect("Invalid regex");
+        ignore_errors_matching(&mut results, &pattern);
+        let statuses: Vec<_> = results.iter().map(|r| r.status()).collect();
+        assert_eq!(statuses, ["valid", "ignored", "invalid"]);
+
+        let summary = validation_summary(".", None, &results);
+        assert!(summary.starts_with("❌ Errors found in some files (⚠ 1 ignored)"));
+        assert!(summary.contains("⚠ ignored lib.k"));
+
+        results.pop();
+        let summary = validation_summary(".", None, &results);
+        assert!(summary.starts_with("✅ 1 file is valid, ⚠ 1 ignored"));
+        let xml = junit_report(&results);
+        assert!(xml.contains("failures=\"0\""));
+        assert!(xml.contains("<skipped message=\"ignored\">"));
+    }
+
+    /// Test that `kcl-test --no-fail` returns failing test names instead of an error.
+    #[test]
+    fn test_kcl_test_no_fail() {
+        if !kcl_installed() {
+            return;
+        }
+        let dir = tempdir().expect("Failed to create temp dir");
+        std::fs::write(
+            dir.path().join("main_test.k"),
+            "test_ok = lambda {\n    assert True\n}\ntest_bad = lambda {\n    assert False\n}\n",
+        )
+        .expect("Failed to write KCL test file");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+        let failing_command = format!("kcl-test '{}'", dir.path().display());
+        assert!(plugin_test.eval(&failing_command).is_err());
+        let summary = plugin_test
+            .eval(&format!("kcl-test '{}' --no-fail", dir.path().display()))
+            .expect("kcl-test --no-fail returned Err")
+            .into_value(Span::test_data())
+            .expect("Failed to collect kcl-test output");
+        let summary = summary.as_record().expect("Expected a record");
+        assert_eq!(summary.get("failed"), Some(&Value::test_int(1)));
+        assert_eq!(
+            summary.get("failing"),
+            Some(&Value::test_list(vec![Value::test_string("test_bad")]))
+        );
+    }
+
+    /// Test that `write_if_changed` leaves files with identical content untouched.
+    #[test]
+    fn test_write_if_changed() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let file = dir.path().join("out.yaml");
+        let path = file.to_string_lossy();
+        assert!(write_if_changed(&path, "a: 1\n").expect("First write failed"));
+        let mtime = std::fs::metadata(&file)
+            .and_then(|m| m.modified())
+            .expect("Failed to read mtime");
+        assert!(!write_if_changed(&path, "a: 1\n").expect("Second write failed"));
+        let unchanged = std::fs::metadata(&file)
+            .and_then(|m| m.modified())
+            .expect("Failed to read mtime");
+        assert_eq!(mtime, unchanged);
+        assert!(write_if_changed(&path, "a: 2\n").expect("Third write failed"));
+        assert_eq!(
+            std::fs::read_to_string(&file).expect("Failed to read output"),
+            "a: 2\n"
+        );
+    }
+
+    /// Test that `eval_kcl` returns the value of an inline expression.
+    #[test]
+    fn test_eval_kcl() {
+        if !kcl_installed() {
+            return;
+        }
+        let value = eval_kcl("kcl", "1 + 2").expect("eval_kcl returned Err");
+        assert_eq!(value, serde_json::json!(3));
+    }
+
+    /// Test that KCL JSON values convert into the matching Nushell values.
+    #[test]
+    fn test_json_to_value() {
+        let json = serde_json::json!({"name": "app", "replicas": 2, "ratio": 0.5, "tags": ["a"], "extra": null});
+        let value = json_to_value(&json, Span::test_data());
+        let record = value.as_record().expect("Expected a record");
+        assert_eq!(record.get("name"), Some(&Value::test_string("app")));
+        assert_eq!(record.get("replicas"), Some(&Value::test_int(2)));
+        assert_eq!(record.get("ratio"), Some(&Value::test_float(0.5)));
+        assert_eq!(
+            record.get("tags"),
+            Some(&Value::test_list(vec![Value::test_string("a")]))
+        );
+        assert_eq!(record.get("extra"), Some(&Value::test_nothing()));
+    }
+
+    /// Test that `kcl-eval` refuses paths of existing files.
+    #[test]
+    fn test_kcl_eval_rejects_files() {
+        let file = NamedTempFile::new().expect("Failed to create temp file");
+        let result = PluginTest::new("kcl", KclWrapperPlugin.into())
+            .expect("Failed to create plugin test")
+            .eval(&format!("kcl-eval '{}'", file.path().display()));
+        assert!(result.is_err());
+    }
+
+    /// Test that `kcl-eval` evaluates an expression that only happens to name a file in the
+    /// current directory, as it is not a `.k` file or a path.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_eval_expression_named_like_file() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "echo '{\"result\": 1}'");
+        std::fs::write(dir.path().join("a"), "").expect("Failed to write file");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+        plugin_test.engine_state_mut().add_env_var(
+            "PWD".to_string(),
+            Value::test_string(dir.path().to_string_lossy()),
+        );
+        let out = eval_plugin(&mut plugin_test, &bin, "kcl-eval a").expect("kcl-eval returned Err");
+        assert_eq!(out, Value::test_int(1));
+    }
+
+    /// Test that `RunOptions` setters accumulate files, defines and overrides.
+    #[test]
+    fn test_run_options_builder() {
+        let opts = RunOptions::new("main.k")
+            .file("prod.k")
+            .bin("/opt/kcl/bin/kcl")
+            .format("json")
+            .define("env=prod")
+            .defines(["replicas=2"])
+            .overrides(["app.name=\"web\""])
+            .cwd("/tmp");
+        assert_eq!(opts.files, ["main.k", "prod.k"]);
+        assert_eq!(opts.bin, "/opt/kcl/bin/kcl");
+        assert_eq!(opts.format, "json");
+        assert_eq!(opts.defines, ["env=prod", "replicas=2"]);
+        assert_eq!(opts.overrides, ["app.name=\"web\""]);
+        assert_eq!(opts.cwd.as_deref(), Some("/tmp"));
+        assert_eq!(opts.output, None);
+    }
+
+    /// Test that `run_kcl` runs KCL and parses its output into documents.
+    #[test]
+    fn test_run_kcl() {
+        assert!(run_kcl(RunOptions::default()).is_err());
+        if !kcl_installed() {
+            return;
+        }
+        let file = write_temp_kcl("a = 1").expect("Failed to write temp KCL file");
+        let result = run_kcl(RunOptions::new(file.path().to_string_lossy()).format("json"))
+            .expect("run_kcl returned Err");
+        let documents = result.documents().expect("Failed to parse output");
+        assert_eq!(documents, [serde_json::json!({"a": 1})]);
+    }
+
+    /// Test that `kcl_files` only walks subdirectories when recursive.
+    #[test]
+    fn test_kcl_files_recursive() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        std::fs::create_dir(dir.path().join("lib")).expect("Failed to create subdir");
+        std::fs::write(dir.path().join("main.k"), "a = 1").expect("Failed to write KCL file");
+        std::fs::write(dir.path().join("notes.txt"), "").expect("Failed to write text file");
+        std::fs::write(dir.path().join("lib").join("vars.k"), "b = 2")
+            .expect("Failed to write KCL file");
+        let root = dir.path().to_string_lossy();
+
+        let top = kcl_files(&root, false).expect("kcl_files returned Err");
+        assert_eq!(top.len(), 1);
+        assert!(top[0].ends_with("main.k"));
+
+        let all = kcl_files(&root, true).expect("kcl_files returned Err");
+        assert_eq!(all.len(), 2);
+        assert!(all.iter().any(|file| file.ends_with("vars.k")));
+    }
+
+    /// Test that a bogus `KCL_BIN` yields a friendly error naming the binary instead of a panic.
+    #[test]
+    fn test_kcl_bin_bogus_path() {
+        let file = write_temp_kcl("a = 1").expect("Failed to write temp KCL file");
+        let bin = "/nonexistent/bin/kcl";
+        let err = run_plugin(bin, &format!("kcl-run '{}'", file.path().display()))
+            .expect_err("kcl-run succeeded with a bogus KCL_BIN");
+        assert!(
+            err.contains("/nonexistent/bin/kcl"),
+            "Unexpected error: {}",
+            err
+        );
+        assert!(
+            err.contains("KCL CLI not found"),
+            "Unexpected error: {}",
+            err
+        );
+
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+        for command in ["kcl-format", "kcl-validate"] {
+            let err = eval_plugin(
+                &mut plugin_test,
+                bin,
+                &format!("{} '{}'", command, file.path().display()),
+            )
+            .expect_err("Expected a bogus KCL_BIN to fail");
+            assert!(
+                err.contains("KCL CLI not found"),
+                "Unexpected error: {}",
+                err
+            );
+            assert!(err.contains("Install KCL"), "Unexpected error: {}", err);
+        }
+        let dir = file.path().parent().expect("Temp file has no parent");
+        for command in [
+            format!("kcl-test '{}'", dir.display()),
+            "kcl-eval '1 + 1'".to_string(),
+        ] {
+            let err = eval_plugin(&mut plugin_test, bin, &command)
+                .expect_err("Expected a bogus KCL_BIN to fail");
+            assert!(
+                err.contains("KCL CLI not found"),
+                "Unexpected error for {}: {}",
+                command,
+                err
+            );
+            assert!(err.contains("Install KCL"), "Unexpected error: {}", err);
+        }
+    }
+
+    /// Test that every helper reports a missing KCL binary with the install hint.
+    #[test]
+    fn test_missing_kcl_binary() {
+        let bin = "/nonexistent/bin/kcl";
+        let dir = tempdir().expect("Failed to create temp dir");
+        let file = dir.path().join("main.k");
+        std::fs::write(&file, "a = 1").expect("Failed to write KCL file");
+        let file = file.to_string_lossy();
+        let errors = [
+            run_kcl_command(&RunOptions::new(file.as_ref()).bin(bin)).unwrap_err(),
+            format_kcl_file(bin, &file).unwrap_err(),
+            validate_kcl_project(bin, &dir.path().to_string_lossy(), None, None, 0, None)
+                .unwrap_err(),
+        ];
+        for err in errors {
+            assert!(
+                err.downcast_ref::<KclNotFoundError>()
+                    .is_some_and(|not_found| not_found.bin == bin),
+                "Unexpected error: {:?}",
+                err
+            );
+            let err = err.to_string();
+            assert!(
+                err.contains("KCL CLI not found"),
+                "Unexpected error: {}",
+                err
+            );
+            assert!(err.contains("kcl-lang.io"), "Unexpected error: {}", err);
+        }
+    }
+
+    /// Test that settings files are forwarded as `-Y` arguments in order.
+    #[test]
+    fn test_run_options_settings_args() {
+        let opts = RunOptions::new("main.k")
+            .bin("kcl")
+            .settings(["base.yaml", "ci.yaml"])
+            .define("env=ci");
+        assert_eq!(
+            opts.args(),
+            [
+                "run",
+                "main.k",
+                "--format",
+                "yaml",
+                "-Y",
+                "base.yaml",
+                "-Y",
+                "ci.yaml",
+                "-D",
+                "env=ci"
+            ]
+        );
+    }
+
+    /// Test that both layouts of the `kcl version` output are parsed.
+    #[test]
+    fn test_parse_kcl_version_info() {
+        assert_eq!(
+            parse_kcl_version_info("0.10.0-linux-amd64\n"),
+            Some(KclVersionInfo {
+                version: "0.10.0".to_string(),
+                git_commit: None,
+                platform: Some("linux-amd64".to_string()),
+            })
+        );
+        assert_eq!(
+            parse_kcl_version_info(
+                "Version: 0.4.3-e07ed7af0d9bd1e86a3131714e4bd20c89ea9f54\nPlatform: x86_64-apple-darwin\n"
+            ),
+            Some(KclVersionInfo {
+                version: "0.4.3".to_string(),
+                git_commit: Some("e07ed7af0d9bd1e86a3131714e4bd20c89ea9f54".to_string()),
+                platform: Some("x86_64-apple-darwin".to_string()),
+            })
+        );
+        assert_eq!(
+            parse_kcl_version_info("kcl version 0.10.0\n").map(|info| info.version),
+            Some("0.10.0".to_string())
+        );
+        assert_eq!(
+            parse_kcl_version_info("kcl v0.9.8").map(|info| info.version),
+            Some("0.9.8".to_string())
+        );
+        assert_eq!(parse_kcl_version_info("no version here"), None);
+    }
+
+    /// Test that `kcl-version` returns the parsed version record.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_version_record() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "echo 'v0.10.0-darwin-arm64'");
+        let out = run_plugin(&bin, "kcl-version").expect("kcl-version returned Err");
+        let out = out.as_record().expect("Expected a record");
+        assert_eq!(out.get("version"), Some(&Value::test_string("0.10.0")));
+        assert_eq!(out.get("git_commit"), Some(&Value::test_nothing()));
+        assert_eq!(
+            out.get("platform"),
+            Some(&Value::test_string("darwin-arm64"))
+        );
+        assert_eq!(
+            out.get("plugin_version"),
+            Some(&Value::test_string(env!("CARGO_PKG_VERSION")))
+        );
+    }
+
+    /// Test that `kcl_version_info` reports the installed CLI, or `None` when it is missing.
+    #[test]
+    fn test_kcl_version_info() {
+        assert_eq!(
+            kcl_version_info("/nonexistent/bin/kcl").expect("kcl_version_info returned Err"),
+            None
+        );
+        if !kcl_installed() {
+            return;
+        }
+        let info = kcl_version_info("kcl").expect("kcl_version_info returned Err");
+        assert!(info.is_some());
+    }
+
+    /// Test that overrides are forwarded as `-O` arguments after the defines.
+    #[test]
+    fn test_run_options_override_args() {
+        let opts = RunOptions::new("main.k")
+            .define("env=prod")
+            .overrides(["app.replicas=3", "pkg:config.name=\"web\""]);
+        let args = opts.args();
+        assert_eq!(
+            args[args.len() - 6..],
+            [
+                "-D",
+                "env=prod",
+                "-O",
+                "app.replicas=3",
+                "-O",
+                "pkg:config.name=\"web\""
+            ]
+        );
+    }
+
+    /// Test that `--sort_keys` is passed and sorts the keys of the output.
+    #[test]
+    fn test_run_kcl_sort_keys() {
+        assert!(
+            RunOptions::new("main.k")
+                .sort_keys(true)
+                .args()
+                .contains(&"--sort_keys".to_string())
+        );
+        if !kcl_installed() {
+            return;
+        }
+        let file = write_temp_kcl("b = 1\nc = 2\na = 3\n").expect("Failed to write temp KCL file");
+        let path = file.path().to_string_lossy();
+        let run = |sort_keys| {
+            run_kcl_command(
+                &RunOptions::new(path.as_ref())
+                    .bin("kcl")
+                    .sort_keys(sort_keys),
+            )
+            .expect("run_kcl_command returned Err")
+        };
+        assert!(run(false).text.contains("b: 1\nc: 2\na: 3"));
+        assert!(run(true).text.contains("a: 3\nb: 1\nc: 2"));
+    }
+
+    /// Test that `-S` selects a sub-path of a schema instance.
+    #[test]
+    fn test_run_kcl_path_selector() {
+        let args = RunOptions::new("main.k")
+            .path_selector("app.replicas")
+            .args();
+        assert!(args.ends_with(&["-S".to_string(), "app.replicas".to_string()]));
+        if !kcl_installed() {
+            return;
+        }
+        let file = write_temp_kcl(
+            "schema App:\n    name: str\n    replicas: int = 1\n\napp = App {name = \"web\", replicas = 3}\n",
+        )
+        .expect("Failed to write temp KCL file");
+        let out = run_kcl_command(
+            &RunOptions::new(file.path().to_string_lossy())
+                .bin("kcl")
+                .path_selector("app.replicas"),
+        )
+        .expect("run_kcl_command returned Err");
+        assert_eq!(out.text.trim(), "3");
+
+        let file =
+            write_temp_kcl("servers = [{name = \"a\"}, {name = \"b\"}]\nclients = [\"c\"]\n")
+                .expect("Failed to write temp KCL file");
+        let out = run_kcl_command(
+            &RunOptions::new(file.path().to_string_lossy())
+                .bin("kcl")
+                .path_selector("servers"),
+        )
+        .expect("run_kcl_command returned Err");
+        assert!(
+            out.text.contains("name: a"),
+            "Unexpected output: {}",
+            out.text
+        );
+        assert!(
+            !out.text.contains("clients"),
+            "Unexpected output: {}",
+            out.text
+        );
+    }
+
+    /// Test that repeated `-S` flags each reach the KCL command line.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_run_repeated_selectors() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "echo \"$@\"");
+        let out = run_plugin(
+            &bin,
+            &format!(
+                "kcl-run '{}' -S servers -S [app.ports]",
+                kcl_file(dir.path(), "config.k").display()
+            ),
+        )
+        .expect("kcl-run returned Err");
+        let out = out.as_str().expect("Expected a string");
+        assert!(
+            out.contains("-S servers -S app.ports"),
+            "Unexpected output: {}",
+            out
+        );
+    }
+
+    /// Write an executable shell script standing in for `kcl` and return its path.
+    #[cfg(unix)]
+    fn fake_kcl(dir: &std::path::Path, body: &str) -> String {
+        use std::os::unix::fs::PermissionsExt;
+        let script = dir.join("kcl");
+        std::fs::write(&script, format!("#!/bin/sh\n{}\n", body))
+            .expect("Failed to write fake kcl");
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755))
+            .expect("Failed to make fake kcl executable");
+        script.to_string_lossy().into_owned()
+    }
+
+    /// Evaluate `command` in `plugin_test` with `$env.KCL_BIN` set to `bin`, collecting its output.
+    ///
+    /// Errors are returned as their debug representation, to check what they hold.
+    fn eval_plugin(
+        plugin_test: &mut PluginTest,
+        bin: &str,
+        command: &str,
+    ) -> Result<Value, String> {
+        let out = plugin_test
+            .eval(&format!("$env.KCL_BIN = '{}'; {}", bin, command))
+            .map_err(|err| format!("{:?}", err))?;
+        out.into_value(Span::test_data())
+            .map_err(|err| format!("{:?}", err))
+    }
+
+    /// Like `eval_plugin`, in a new plugin test.
+    fn run_plugin(bin: &str, command: &str) -> Result<Value, String> {
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+        eval_plugin(&mut plugin_test, bin, command)
+    }
+
+    /// Write an empty KCL file for commands that check their file arguments exist.
+    #[cfg(unix)]
+    fn kcl_file(dir: &std::path::Path, name: &str) -> std::path::PathBuf {
+        let file = dir.join(name);
+        std::fs::write(&file, "").expect("Failed to write KCL file");
+        file
+    }
+
+    /// Stand-in for Nushell's `print` command, collecting what is printed.
+    ///
+    /// What is printed with `--stderr` is prefixed with `stderr: `.
+    #[derive(Clone)]
+    struct PrintSpy(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl nu_protocol::engine::Command for PrintSpy {
+        fn name(&self) -> &str {
+            "print"
+        }
+
+        fn signature(&self) -> nu_protocol::Signature {
+            nu_protocol::Signature::build("print")
+                .input_output_types(vec![(nu_protocol::Type::Any, nu_protocol::Type::Nothing)])
+                .switch("stderr", "Print to stderr", Some('e'))
+        }
+
+        fn description(&self) -> &str {
+            "Collect the printed values"
+        }
+
+        fn run(
+            &self,
+            _engine_state: &nu_protocol::engine::EngineState,
+            stack: &mut nu_protocol::engine::Stack,
+            call: &nu_protocol::engine::Call,
+            input: nu_protocol::PipelineData,
+        ) -> Result<nu_protocol::PipelineData, nu_protocol::ShellError> {
+            let text = input.into_value(call.head)?.coerce_into_string()?;
+            // Plugins call declarations with an IR call.
+            let stderr = match &call.inner {
+                nu_protocol::engine::CallImpl::IrRef(ir) => {
+                    ir.named_iter(stack).any(|(name, value)| {
+                        name.item == "stderr" && value.is_none_or(|value| value.is_true())
+                    })
+                }
+                _ => false,
+            };
+            let text = match stderr {
+                true => format!("stderr: {}", text),
+                false => text,
+            };
+            self.0.lock().unwrap().push(text);
+            Ok(nu_protocol::PipelineData::Empty)
+        }
+    }
+
+    /// Replace `print` in the engine of `plugin_test` with a `PrintSpy`, returning what it collects.
+    fn spy_print(plugin_test: &mut PluginTest) -> std::sync::Arc<std::sync::Mutex<Vec<String>>> {
+        let spy = PrintSpy(Default::default());
+        let mut working_set = nu_protocol::engine::StateWorkingSet::new(plugin_test.engine_state());
+        working_set.add_decl(Box::new(spy.clone()));
+        let delta = working_set.render();
+        plugin_test
+            .engine_state_mut()
+            .merge_delta(delta)
+            .expect("Failed to add the print spy");
+        spy.0
+    }
+
+    /// `KclRunner` answering with canned output instead of spawning KCL, recording the arguments of each call.
+    #[cfg(unix)]
+    struct MockRunner {
+        /// Exit code, stdout and stderr to answer the arguments of a call with.
+        respond: fn(&[String]) -> (i32, &'static str, &'static str),
+        calls: std::sync::Mutex<Vec<Vec<String>>>,
+    }
+
+    #[cfg(unix)]
+    impl MockRunner {
+        fn new(respond: fn(&[String]) -> (i32, &'static str, &'static str)) -> Self {
+            Self {
+                respond,
+                calls: std::sync::Mutex::new(Vec::new()),
+            }
+        }
+
+        /// Arguments of the calls so far, sorted (checks may run concurrently).
+        fn calls(&self) -> Vec<Vec<String>> {
+            let mut calls = self
+                .calls
+                .lock()
+                .expect("Mock runner lock poisoned")
+                .clone();
+            calls.sort();
+            calls
+        }
+    }
+
+    #[cfg(unix)]
+    impl KclRunner for MockRunner {
+        fn output(
+            &self,
+            cmd: &mut Command,
+            _timeout: Option<std::time::Duration>,
+            _interrupt: Option<&std::sync::atomic::AtomicBool>,
+        ) -> std::io::Result<Option<std::process::Output>> {
+            use std::os::unix::process::ExitStatusExt;
+            let args: Vec<String> = cmd
+                .get_args()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect();
+            let (code, stdout, stderr) = (self.respond)(&args);
+            self.calls
+                .lock()
+                .expect("Mock runner lock poisoned")
+                .push(args);
+            Ok(Some(std::process::Output {
+                status: std::process::ExitStatus::from_raw(code << 8),
+                stdout: stdout.as_bytes().to_vec(),
+                stderr: stderr.as_bytes().to_vec(),
+            }))
+        }
+    }
+
+    /// Test the success, failure and output parsing paths of `run_kcl_command_with` without KCL.
+    #[cfg(unix)]
+    #[test]
+    fn test_run_kcl_command_with_mock_runner() {
+        let runner = MockRunner::new(|args| match args.iter().any(|arg| arg == "bad.k") {
+            true => (1, "", "\x1b[31merror\x1b[0m[E2G22]: expected int, got str"),
+            false => (0, "a: 1\n", ""),
+        });
+        let out = run_kcl_command_with(&runner, &RunOptions::new("main.k").validate_output(true))
+            .expect("run_kcl_command_with returned Err");
+        assert_eq!(out.text, "✅ a: 1\n");
+        assert_eq!(out.warnings, None);
+
+        let err = run_kcl_command_with(&runner, &RunOptions::new("bad.k"))
+            .expect_err("Expected the failing run to fail");
+        assert_eq!(err.to_string(), "❌: error[E2G22]: expected int, got str");
+        assert_eq!(
+            runner.calls(),
+            [
+                vec!["run", "bad.k", "--format", "yaml"],
+                vec!["run", "main.k", "--format", "yaml"],
+            ]
+        );
+
+        let runner = MockRunner::new(|_| (0, "a: [1\n", ""));
+        let err = run_kcl_command_with(&runner, &RunOptions::new("main.k").validate_output(true))
+            .expect_err("Expected malformed output to fail");
+        assert!(err.to_string().contains("malformed yaml"), "{}", err);
+    }
+
+    /// Test `format_kcl_file_with` and `validate_kcl_project_with` without KCL.
+    #[cfg(unix)]
+    #[test]
+    fn test_format_and_validate_with_mock_runner() {
+        let runner =
+            MockRunner::new(
+                |args| match args.last().is_some_and(|arg| arg.ends_with("bad.k")) {
+                    true => (1, "", "syntax error"),
+                    false => (0, "", ""),
+                },
+            );
+        assert_eq!(
+            format_kcl_file_with(&runner, "kcl", "main.k")
+                .expect("format_kcl_file_with returned Err"),
+            "✅ File formatted: main.k"
+        );
+        let err =
+            format_kcl_file_with(&runner, "kcl", "bad.k").expect_err("Expected bad.k to fail");
+        assert_eq!(err.to_string(), "KCL format failed: syntax error");
+
+        let dir = tempdir().expect("Failed to create temp dir");
+        let good = kcl_file(dir.path(), "good.k");
+        let bad = kcl_file(dir.path(), "bad.k");
+        let results = validate_kcl_project_with(
+            &runner,
+            "kcl",
+            &dir.path().to_string_lossy(),
+            None,
+            None,
+            0,
+            None,
+        )
+        .expect("validate_kcl_project_with returned Err");
+        let results: Vec<(String, Option<String>)> = results
+            .into_iter()
+            .map(|result| (result.file, result.error))
+            .collect();
+        assert_eq!(
+            results,
+            [
+                (bad.display().to_string(), Some("syntax error".to_string())),
+                (good.display().to_string(), None),
+            ]
+        );
+    }
+
+    /// Test that repeated `-D` flags all reach the KCL command line.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_run_repeated_defines() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "echo \"$@\"");
+        let out = run_plugin(
+            &bin,
+            &format!(
+                "kcl-run '{}' -D foo=1 -D bar=2 -D [baz=3]",
+                kcl_file(dir.path(), "main.k").display()
+            ),
+        )
+        .expect("kcl-run returned Err");
+        let out = out.as_str().expect("Expected a string");
+        assert!(
+            out.contains("-D foo=1 -D bar=2 -D baz=3"),
+            "Unexpected output: {}",
+            out
+        );
+    }
+
+    /// Test that a file reading two options gets both values from repeated defines.
+    #[test]
+    fn test_kcl_run_two_defines() {
+        if !kcl_installed() {
+            return;
+        }
+        let file = write_temp_kcl("foo = option(\"foo\")\nbar = option(\"bar\")\n")
+            .expect("Failed to write temp KCL file");
+        let out = PluginTest::new("kcl", KclWrapperPlugin.into())
+            .expect("Failed to create plugin test")
+            .eval(&format!(
+                "kcl-run '{}' -D foo=1 -D bar=2",
+                file.path().display()
+            ))
+            .expect("kcl-run returned Err")
+            .into_value(Span::test_data())
+            .expect("Failed to collect kcl-run output");
+        let out = out.as_str().expect("Expected a string");
+        assert!(
+            out.contains("foo: 1") && out.contains("bar: 2"),
+            "Unexpected output: {}",
+            out
+        );
+    }
+
+    /// Test that `--define-record` turns record fields into KCL literal defines, with `-D` flags winning.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_run_define_record() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "echo \"$@\"");
+        let file = kcl_file(dir.path(), "main.k");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+        let out = eval_plugin(&mut plugin_test, &bin, &format!("kcl-run '{}' -D env=prod --define-record {{replicas: 3, ratio: 0.5, debug: true, name: web}}", file.display()))
+            .expect("kcl-run returned Err");
+        let out = out.as_str().expect("Expected a string");
+        assert!(
+            out.contains("-D replicas=3 -D ratio=0.5 -D debug=True -D name=web -D env=prod"),
+            "Unexpected output: {}",
+            out
+        );
+
+        let out = eval_plugin(&mut plugin_test, &bin, &format!("kcl-run '{}' --define-record {{replicas: 3, image: \"nginx\", app: {{ports: [80]}}}} -D replicas=5", file.display()))
+            .expect("kcl-run returned Err");
+        let out = out.as_str().expect("Expected a string");
+        assert!(
+            out.contains(r#"-D replicas=3 -D image=nginx -D app={"ports":[80]} -D replicas=5"#),
+            "Unexpected output: {}",
+            out
+        );
+    }
+
+    /// Test that stderr of a successful run is returned as warnings, printed unless `--quiet`,
+    /// or returned as data with `--with-warnings`.
+    #[cfg(unix)]
+    #[test]
+    fn test_run_kcl_command_warnings() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(
+            dir.path(),
+            "echo 'a: 1'\necho 'warning: deprecated attribute' >&2",
+        );
+        let out = run_kcl_command(&RunOptions::new("main.k").bin(&bin))
+            .expect("run_kcl_command returned Err");
+        assert_eq!(out.text, "✅ a: 1\n");
+        assert_eq!(
+            out.warnings.as_deref(),
+            Some("warning: deprecated attribute")
+        );
+
+        let quiet = run_plugin(
+            &bin,
+            &format!(
+                "kcl-run '{}' --quiet",
+                kcl_file(dir.path(), "main.k").display()
+            ),
+        )
+        .expect("kcl-run --quiet returned Err");
+        assert_eq!(quiet, Value::test_string("✅ a: 1\n"));
+
+        let out = run_plugin(
+            &bin,
+            &format!(
+                "kcl-run '{}' --with-warnings --post-run-hook 'exit 2'",
+                dir.path().join("main.k").display()
+            ),
+        )
+        .expect("kcl-run --with-warnings returned Err");
+        let record = out.as_record().expect("Expected a record");
+        assert_eq!(record.get("output"), Some(&Value::test_string("✅ a: 1\n")));
+        let warnings = record
+            .get("warnings")
+            .and_then(|warnings| warnings.as_list().ok())
+            .expect("Expected a list of warnings");
+        assert_eq!(
+            warnings[0],
+            Value::test_string("warning: deprecated attribute")
+        );
+        assert!(
+            warnings[1]
+                .as_str()
+                .is_ok_and(|warning| warning.starts_with("Post-run hook failed")),
+            "{:?}",
+            warnings
+        );
+    }
+
+    /// Test that file locations and messages are parsed from both KCL error formats.
+    #[test]
+    fn test_parse_diagnostics() {
+        let current = "error[E1001]: InvalidSyntax\n --> /work/main.k:3:5\n  |\n3 | a = \n  |     ^ expected expression\n";
+        let diagnostics = parse_diagnostics(current);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].file, "/work/main.k");
+        assert_eq!((diagnostics[0].line, diagnostics[0].column), (3, 5));
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "/work/main.k:3:5: InvalidSyntax: expected expression"
+        );
+
+        let legacy = "KCL Syntax Error[E1001] : Invalid syntax\n---> File /work/app.k:1:4\n1 |a =\n  |   ^ Expected one of ['(']\n";
+        let diagnostics = parse_diagnostics(legacy);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].file, "/work/app.k");
+        assert_eq!((diagnostics[0].line, diagnostics[0].column), (1, 4));
+        assert_eq!(diagnostics[0].code.as_deref(), Some("E1001"));
+
+        assert!(parse_diagnostics("something went wrong").is_empty());
+    }
+
+    /// Test that a failed run's error carries the KCL error code and every location.
+    #[test]
+    fn test_kcl_run_error_fields() {
+        let stderr = "❌: error[E2L23]: CompileError\n --> /work/main.k:2:1\n  |\n2 | b = a\n  | ^ name 'a' is not defined\nerror[E2L23]: CompileError\n --> /work/lib.k:7:3\n  |\n7 |   c\n  |   ^ name 'c' is not defined\n";
+        let error = kcl_run_error(anyhow::anyhow!("{}", stderr), Span::test_data());
+        assert_eq!(error.msg, "Error executing KCL in /work/main.k");
+        assert_eq!(error.code.as_deref(), Some("kcl::E2L23"));
+        assert_eq!(
+            error.labels[0].text,
+            "/work/main.k:2:1: CompileError: name 'a' is not defined"
+        );
+        assert_eq!(error.inner.len(), 1);
+        assert_eq!(
+            error.inner[0].msg,
+            "/work/lib.k:7:3: CompileError: name 'c' is not defined"
+        );
+
+        let error = kcl_run_error(anyhow::anyhow!("❌: panic"), Span::test_data());
+        assert_eq!(error.msg, "Error executing KCL");
+        assert_eq!(error.labels[0].text, "❌: panic");
+    }
+
+    /// Test that running an invalid KCL file reports its line and column.
+    #[test]
+    fn test_kcl_run_error_location() {
+        if !kcl_installed() {
+            return;
+        }
+        let dir = tempdir().expect("Failed to create temp dir");
+        let file = dir.path().join("bad.k");
+        std::fs::write(&file, "a = 1\nb = \n").expect("Failed to write KCL file");
+        let err = PluginTest::new("kcl", KclWrapperPlugin.into())
+            .expect("Failed to create plugin test")
+            .eval(&format!("kcl-run '{}'", file.display()))
+            .expect_err("kcl-run succeeded on invalid KCL");
+        let err = format!("{:?}", err);
+        assert!(err.contains("bad.k:2:"), "Unexpected error: {}", err);
+    }
+
+    /// Test that `kcl-vet` returns a `{valid, errors}` record for passing and failing data.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_vet_record() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(
+            dir.path(),
+            "case \"$2\" in *bad.json) echo 'EvaluationError: expected str, got int' >&2; exit 1;; esac",
+        );
+        let schema = kcl_file(dir.path(), "schema.k");
+        for data in ["good.json", "bad.json"] {
+            std::fs::write(dir.path().join(data), "{}").expect("Failed to write data file");
+        }
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+        let mut vet = |data: &str| {
+            eval_plugin(
+                &mut plugin_test,
+                &bin,
+                &format!(
+                    "kcl-vet '{}' '{}' --schema-name App",
+                    dir.path().join(data).display(),
+                    schema.display()
+                ),
+            )
+            .expect("kcl-vet returned Err")
+        };
+
+        let good = vet("good.json");
+        let good = good.as_record().expect("Expected a record");
+        assert_eq!(good.get("valid"), Some(&Value::test_bool(true)));
+        assert_eq!(good.get("errors"), Some(&Value::test_list(vec![])));
+
+        let bad = vet("bad.json");
+        let bad = bad.as_record().expect("Expected a record");
+        assert_eq!(bad.get("valid"), Some(&Value::test_bool(false)));
+        assert_eq!(
+            bad.get("errors"),
+            Some(&Value::test_list(vec![Value::test_string(
+                "EvaluationError: expected str, got int"
+            )]))
+        );
+    }
+
+    /// Test that a single `-O` and repeated `-O` flags all reach the KCL command line.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_run_overrides() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "echo \"$@\"");
+        let file = kcl_file(dir.path(), "deploy.k");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+        let mut run = |flags: &str| {
+            let out = eval_plugin(
+                &mut plugin_test,
+                &bin,
+                &format!("kcl-run '{}' {}", file.display(), flags),
+            )
+            .expect("kcl-run returned Err");
+            out.as_str().expect("Expected a string").to_string()
+        };
+
+        let single = run("-O 'spec.replicas=5'");
+        assert!(
+            single.contains("-O spec.replicas=5"),
+            "Unexpected args: {}",
+            single
+        );
+
+        let multiple = run("-O spec.replicas=5 -O [spec.image=nginx app:name=web]");
+        assert!(
+            multiple.contains("-O spec.replicas=5 -O spec.image=nginx -O app:name=web"),
+            "Unexpected args: {}",
+            multiple
+        );
+    }
+
+    /// Test that parallel validation returns every file, sorted by path, whatever the `jobs`.
+    #[cfg(unix)]
+    #[test]
+    fn test_validate_kcl_project_jobs() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(
+            dir.path(),
+            "case \"$2\" in */bad*) echo \"error in $2\" >&2; exit 1;; esac",
+        );
+        let project = dir.path().join("project");
+        std::fs::create_dir(&project).expect("Failed to create project dir");
+        let mut expected = Vec::new();
+        for i in 0..20 {
+            let name = if i % 5 == 0 {
+                format!("bad{:02}.k", i)
+            } else {
+                format!("good{:02}.k", i)
+            };
+            let path = project.join(name);
+            std::fs::write(&path, "a = 1").expect("Failed to write KCL file");
+            expected.push(path.to_string_lossy().into_owned());
+        }
+        expected.sort();
+
+        for jobs in [1, 4, 0] {
+            let results = validate_kcl_project(
+                &bin,
+                project.to_str().expect("Project path is not valid UTF-8"),
+                None,
+                None,
+                jobs,
+                None,
+            )
+            .expect("validate_kcl_project returned Err unexpectedly");
+            let files: Vec<&str> = results.iter().map(|r| r.file.as_str()).collect();
+            assert_eq!(files, expected, "Unexpected order with {} jobs", jobs);
+            assert_eq!(results.iter().filter(|r| r.is_invalid()).count(), 4);
+            for result in &results {
+                let name = std::path::Path::new(&result.file).file_name();
+                let name = name
+                    .map(|n| n.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                assert_eq!(result.is_invalid(), name.starts_with("bad"));
+            }
+        }
+    }
+
+    /// Test that a missing settings file is reported before KCL runs, and existing ones are forwarded.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_run_missing_settings_file() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "echo \"$@\"");
+        let file = kcl_file(dir.path(), "main.k");
+        let settings = dir.path().join("kcl.yaml");
+        std::fs::write(&settings, "kcl_options: []\n").expect("Failed to write settings file");
+        let missing = dir.path().join("ci.yaml");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+
+        let out = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-run '{}' -Y '{}'", file.display(), settings.display()),
+        )
+        .expect("kcl-run returned Err");
+        let out = out.as_str().expect("Expected a string");
+        assert!(
+            out.contains(&format!("-Y {}", settings.display())),
+            "Unexpected args: {}",
+            out
+        );
+
+        let msg = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!(
+                "kcl-run '{}' -Y ['{}' '{}']",
+                file.display(),
+                settings.display(),
+                missing.display()
+            ),
+        )
+        .expect_err("Expected kcl-run to fail on a missing settings file");
+        assert!(
+            msg.contains("--settings file not found"),
+            "Unexpected error: {}",
+            msg
+        );
+        assert!(msg.contains("ci.yaml"), "Unexpected error: {}", msg);
+    }
+
+    /// Test that parsed output keeps the key order KCL rendered, sorted or not.
+    #[test]
+    fn test_parse_output_keeps_key_order() {
+        let columns = |output: &str, format: &str| -> Vec<String> {
+            let docs = parse_output(output, format).expect("Failed to parse output");
+            let value = json_to_value(&docs[0], Span::test_data());
+            value
+                .as_record()
+                .expect("Expected a record")
+                .columns()
+                .cloned()
+                .collect()
+        };
+        assert_eq!(
+            columns("name: web\nimage: nginx\n", "yaml"),
+            ["name", "image"]
+        );
+        assert_eq!(
+            columns("image: nginx\nname: web\n", "yaml"),
+            ["image", "name"]
+        );
+        assert_eq!(
+            columns("{\"name\": \"web\", \"image\": \"nginx\"}", "json"),
+            ["name", "image"]
+        );
+    }
+
+    /// Test that `kcl-run --sort-keys` passes `--sort_keys` and the parsed documents follow it.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_run_sort_keys_flag() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "echo \"$@\"");
+        let out = run_plugin(
+            &bin,
+            &format!(
+                "kcl-run '{}' --sort-keys",
+                kcl_file(dir.path(), "main.k").display()
+            ),
+        )
+        .expect("kcl-run returned Err");
+        let out = out.as_str().expect("Expected a string");
+        assert!(out.contains("--sort_keys"), "Unexpected args: {}", out);
+
+        if !kcl_installed() {
+            return;
+        }
+        let file = write_temp_kcl("schema App:\n    name: str\n    image: str\n\napp = App {name = \"web\", image = \"nginx\"}\n")
+            .expect("Failed to write temp KCL file");
+        let keys = |sort_keys| -> Vec<String> {
+            let docs = run_kcl(
+                RunOptions::new(file.path().to_string_lossy())
+                    .bin("kcl")
+                    .format("json")
+                    .sort_keys(sort_keys),
+            )
+            .expect("run_kcl returned Err")
+            .documents()
+            .expect("Failed to parse documents");
+            docs[0]["app"]
+                .as_object()
+                .expect("Expected an object")
+                .keys()
+                .cloned()
+                .collect()
+        };
+        assert_eq!(keys(false), ["name", "image"]);
+        assert_eq!(keys(true), ["image", "name"]);
+    }
+
+    /// Test that setting the interrupt flag stops validation and kills the running KCL processes.
+    #[cfg(unix)]
+    #[test]
+    fn test_validate_kcl_project_interrupt() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "sleep 2");
+        let project = dir.path().join("project");
+        std::fs::create_dir(&project).expect("Failed to create project dir");
+        for i in 0..10 {
+            std::fs::write(project.join(format!("file{:02}.k", i)), "a = 1")
+                .expect("Failed to write KCL file");
+        }
+        let project = project.to_string_lossy().into_owned();
+
+        let interrupt = AtomicBool::new(true);
+        let results = validate_kcl_project(&bin, &project, None, None, 2, Some(&interrupt))
+            .expect("validate_kcl_project returned Err unexpectedly");
+        assert!(results.is_empty());
+
+        let interrupt = AtomicBool::new(false);
+        let start = std::time::Instant::now();
+        let results = std::thread::scope(|scope| {
+            scope.spawn(|| {
+                std::thread::sleep(std::time::Duration::from_millis(300));
+                interrupt.store(true, Ordering::Relaxed);
+            });
+            validate_kcl_project(&bin, &project, None, None, 2, Some(&interrupt))
+                .expect("validate_kcl_project returned Err unexpectedly")
+        });
+        assert!(
+            results.is_empty(),
+            "Killed checks were reported: {:?}",
+            results
+        );
+        assert!(start.elapsed() < std::time::Duration::from_secs(2));
+    }
+
+    /// Test that an interrupt midway through a run keeps the results of the files checked
+    /// before it, and only drops the check it killed.
+    #[cfg(unix)]
+    #[test]
+    fn test_validate_kcl_project_interrupt_partial() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        let dir = tempdir().expect("Failed to create temp dir");
+        // The first two files pass at once, the third one hangs until it is killed.
+        let bin = fake_kcl(
+            dir.path(),
+            "case \"$2\" in *file00.k|*file01.k) ;; *) exec sleep 5;; esac",
+        );
+        let project = dir.path().join("project");
+        std::fs::create_dir(&project).expect("Failed to create project dir");
+        for i in 0..5 {
+            kcl_file(&project, &format!("file{:02}.k", i));
+        }
+        let project = project.to_string_lossy().into_owned();
+
+        let interrupt = AtomicBool::new(false);
+        let start = std::time::Instant::now();
+        let results = std::thread::scope(|scope| {
+            scope.spawn(|| {
+                std::thread::sleep(std::time::Duration::from_millis(500));
+                interrupt.store(true, Ordering::Relaxed);
+            });
+            validate_kcl_project(&bin, &project, None, None, 1, Some(&interrupt))
+                .expect("validate_kcl_project returned Err unexpectedly")
+        });
+        let files: Vec<&str> = results
+            .iter()
+            .map(|result| result.file.rsplit('/').next().unwrap_or_default())
+            .collect();
+        assert_eq!(files, ["file00.k", "file01.k"]);
+        assert!(results.iter().all(|result| !result.is_invalid()));
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    /// Test that `--disable_none` drops unset optional attributes, which are null otherwise.
+    #[test]
+    fn test_run_kcl_disable_none() {
+        assert!(
+            RunOptions::new("main.k")
+                .disable_none(true)
+                .args()
+                .contains(&"--disable_none".to_string())
+        );
+        assert!(
+            !RunOptions::new("main.k")
+                .args()
+                .contains(&"--disable_none".to_string())
+        );
+        if !kcl_installed() {
+            return;
+        }
+        let file = write_temp_kcl(
+            "schema App:\n    name: str\n    image?: str\n\napp = App {name = \"web\"}\n",
+        )
+        .expect("Failed to write temp KCL file");
+        let app = |disable_none| {
+            let docs = run_kcl(
+                RunOptions::new(file.path().to_string_lossy())
+                    .bin("kcl")
+                    .format("json")
+                    .disable_none(disable_none),
+            )
+            .expect("run_kcl returned Err")
+            .documents()
+            .expect("Failed to parse documents");
+            docs[0]["app"].clone()
+        };
+        assert_eq!(app(false).get("image"), Some(&serde_json::Value::Null));
+        assert_eq!(app(true).get("image"), None);
+    }
+
+    /// Test that `select_files` honors include and exclude globs, with excludes winning.
+    #[test]
+    fn test_select_files_patterns() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        for file in [
+            "prod-app.k",
+            "dev-app.k",
+            "envs/prod-db.k",
+            "tests/prod-fixture.k",
+            "tests/helpers.k",
+        ] {
+            let path = dir.path().join(file);
+            std::fs::create_dir_all(path.parent().expect("File has a parent"))
+                .expect("Failed to create dir");
+            std::fs::write(&path, "a = 1").expect("Failed to write KCL file");
+        }
+        let root = dir.path().to_string_lossy().into_owned();
+        let select = |include: Option<&str>, exclude: &[&str]| -> Vec<String> {
+            let include: Vec<String> = include.iter().map(|i| i.to_string()).collect();
+            let exclude: Vec<String> = exclude.iter().map(|e| e.to_string()).collect();
+            select_files(&root, None, &include, &exclude, None)
+                .expect("select_files returned Err")
+                .iter()
+                .map(|file| {
+                    std::path::Path::new(file)
+                        .strip_prefix(&root)
+                        .expect("File is outside the directory")
+                        .to_string_lossy()
+                        .into_owned()
+                })
+                .collect()
+        };
+
+        assert_eq!(
+            select(Some("**/prod-*.k"), &[]),
+            ["envs/prod-db.k", "prod-app.k", "tests/prod-fixture.k"]
+        );
+        assert_eq!(
+            select(None, &["**/tests/**"]),
+            ["dev-app.k", "envs/prod-db.k", "prod-app.k"]
+        );
+        assert_eq!(
+            select(Some("**/prod-*.k"), &["**/tests/**", "envs/*"]),
+            ["prod-app.k"]
+        );
+        assert_eq!(
+            select(None, &["prod-*.k"]),
+            ["dev-app.k", "tests/helpers.k"]
+        );
+        assert!(select_files(&root, None, &["[unclosed".to_string()], &[], None).is_err());
+    }
+
+    /// Test that a record piped into `kcl-run` becomes defines, with nested values JSON-encoded.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_run_pipeline_record_defines() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "echo \"$@\"");
+        let out = run_plugin(
+            &bin,
+            &format!(
+                "{{foo: bar, n: 3, tags: [a b], db: {{port: 5432}}}} | kcl-run '{}' -D n=4",
+                kcl_file(dir.path(), "template.k").display()
+            ),
+        )
+        .expect("kcl-run returned Err");
+        let out = out.as_str().expect("Expected a string");
+        assert!(
+            out.contains(r#"-D foo=bar -D n=3 -D tags=["a","b"] -D db={"port":5432} -D n=4"#),
+            "Unexpected args: {}",
+            out
+        );
+    }
+
+    /// Test that JSON output converts to TOML and parses back, without its null values.
+    #[test]
+    fn test_json_to_toml() {
+        let toml = json_to_toml(r#"{"name": "web", "replicas": 3, "image": null, "ports": [80, 443], "db": {"host": "pg"}}"#)
+            .expect("json_to_toml returned Err");
+        let docs = parse_output(&toml, "toml").expect("TOML output does not parse");
+        assert_eq!(
+            docs,
+            [
+                serde_json::json!({"name": "web", "replicas": 3, "ports": [80, 443], "db": {"host": "pg"}})
+            ]
+        );
+        assert!(json_to_toml("[1, 2]").is_err());
+        assert_eq!(
+            RunOptions::new("main.k").format("toml").args()[2..4],
+            ["--format", "json"]
+        );
+    }
+
+    /// Test that `kcl-run -f toml` asks KCL for JSON and returns (or writes) TOML that parses back.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_run_toml_format() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(
+            dir.path(),
+            r#"out=""
+case "$*" in *"--format json"*) ;; *) echo "expected json from KCL" >&2; exit 1;; esac
+while [ $# -gt 0 ]; do [ "$1" = "-o" ] && out="$2"; shift; done
+json='{"app": {"name": "web", "replicas": 3}}'
+if [ -n "$out" ]; then echo "$json" > "$out"; else echo "$json"; fi"#,
+        );
+        let file = kcl_file(dir.path(), "main.k");
+        let expected = [serde_json::json!({"app": {"name": "web", "replicas": 3}})];
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+
+        let out = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-run '{}' -f toml", file.display()),
+        )
+        .expect("kcl-run returned Err");
+        let out = out.as_str().expect("Expected a string");
+        let docs = parse_output(out.trim_start_matches("✅ "), "toml").expect("Output is not TOML");
+        assert_eq!(docs, expected);
+
+        let output = dir.path().join("app.toml");
+        eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!(
+                "kcl-run '{}' -f toml -o '{}' --validate-output",
+                file.display(),
+                output.display()
+            ),
+        )
+        .expect("kcl-run returned Err");
+        let written = std::fs::read_to_string(&output).expect("Output file was not written");
+        let docs = parse_output(&written, "toml").expect("Output file is not TOML");
+        assert_eq!(docs, expected);
+    }
+
+    /// Test that unknown output formats are rejected before KCL is invoked.
+    #[test]
+    fn test_run_rejects_unknown_format() {
+        let err = run_kcl_command(
+            &RunOptions::new("main.k")
+                .bin("/nonexistent/bin/kcl")
+                .format("jsonn"),
+        )
+        .expect_err("Expected an unsupported format error");
+        assert!(
+            err.to_string().contains("Unsupported format 'jsonn'"),
+            "Unexpected error: {}",
+            err
+        );
+
+        let file = write_temp_kcl("a = 1").expect("Failed to write temp KCL file");
+        let msg = run_plugin(
+            "/nonexistent/bin/kcl",
+            &format!("kcl-run '{}' -f jsonn", file.path().display()),
+        )
+        .expect_err("Expected kcl-run to reject the format");
+        assert!(
+            msg.contains("'jsonn' is not one of: yaml, json, toml, ndjson"),
+            "Unexpected error: {}",
+            msg
+        );
+    }
+
+    /// Test that `kcl-run` rejects missing paths and files without a `.k` extension up front.
+    #[test]
+    fn test_kcl_run_checks_file_argument() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let data = dir.path().join("data.json");
+        std::fs::write(&data, "{}").expect("Failed to write data file");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+        let mut error = |file: &std::path::Path| {
+            eval_plugin(
+                &mut plugin_test,
+                "/nonexistent/bin/kcl",
+                &format!("kcl-run '{}'", file.display()),
+            )
+            .expect_err("Expected kcl-run to reject the file")
+        };
+
+        let msg = error(&dir.path().join("missing.k"));
+        assert!(
+            msg.contains("KCL file not found"),
+            "Unexpected error: {}",
+            msg
+        );
+        assert!(
+            msg.contains("missing.k' does not exist"),
+            "Unexpected error: {}",
+            msg
+        );
+
+        let msg = error(&data);
+        assert!(msg.contains("Not a KCL file"), "Unexpected error: {}", msg);
+        assert!(
+            msg.contains("does not have a .k extension"),
+            "Unexpected error: {}",
+            msg
+        );
+    }
+
+    /// Test that `--working-dir` runs KCL from that directory and resolves relative files against it.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_run_working_dir() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "echo \"$(pwd) $*\"");
+        let project = dir.path().join("project");
+        std::fs::create_dir(&project).expect("Failed to create project dir");
+        kcl_file(&project, "main.k");
+        let project = project
+            .canonicalize()
+            .expect("Failed to canonicalize project dir");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+        let out = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-run 'main.k' -C '{}'", project.display()),
+        )
+        .expect("kcl-run returned Err");
+        let out = out.as_str().expect("Expected a string");
+        assert!(
+            out.contains(&format!("{} run main.k", project.display())),
+            "Unexpected output: {}",
+            out
+        );
+
+        let err = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!(
+                "kcl-run 'main.k' -C '{}'",
+                project.join("missing").display()
+            ),
+        )
+        .expect_err("Expected kcl-run to reject a missing working directory");
+        assert!(err.contains("is not a directory"));
+
+        if !kcl_installed() {
+            return;
+        }
+        std::fs::write(project.join("main.k"), "import base\n\nname = base.name\n")
+            .expect("Failed to write main KCL file");
+        std::fs::write(project.join("base.k"), "name = \"web\"\n").expect("Failed to write module");
+        std::fs::write(project.join("kcl.mod"), "[package]\nname = \"project\"\n")
+            .expect("Failed to write kcl.mod");
+        let out = run_kcl_command(
+            &RunOptions::new("main.k")
+                .bin("kcl")
+                .cwd(project.to_string_lossy()),
+        )
+        .expect("run_kcl_command returned Err");
+        assert!(
+            out.text.contains("name: web"),
+            "Unexpected output: {}",
+            out.text
+        );
+    }
+
+    /// Test that `ValidateOptions::progress` is called once per checked file, up to the total,
+    /// and that `kcl-validate` prints it on stderr unless `--quiet`.
+    #[cfg(unix)]
+    #[test]
+    fn test_validate_kcl_progress() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "");
+        let project = dir.path().join("project");
+        std::fs::create_dir(&project).expect("Failed to create project dir");
+        for name in ["a.k", "b.k", "c.k"] {
+            kcl_file(&project, name);
+        }
+        let calls = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let results = validate_kcl(
+            ValidateOptions::new(project.to_string_lossy())
+                .bin(&bin)
+                .jobs(2)
+                .progress({
+                    let calls = calls.clone();
+                    move |checked, total| calls.lock().unwrap().push((checked, total))
+                }),
+        )
+        .expect("validate_kcl returned Err");
+        assert_eq!(results.len(), 3);
+        let mut calls = calls.lock().unwrap().clone();
+        calls.sort();
+        assert_eq!(calls, [(1, 3), (2, 3), (3, 3)]);
+
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+        let printed = spy_print(&mut plugin_test);
+        let out = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-validate '{}' --summary", project.display()),
+        )
+        .expect("kcl-validate returned Err");
+        let out = out.as_str().expect("Expected a string");
+        assert!(out.starts_with("✅ All 3 files are valid"), "{}", out);
+        let mut lines = printed.lock().unwrap().clone();
+        lines.sort();
+        assert_eq!(
+            lines,
+            [
+                "stderr: Validated 1/3 files",
+                "stderr: Validated 2/3 files",
+                "stderr: Validated 3/3 files"
+            ]
+        );
+
+        printed.lock().unwrap().clear();
+        eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-validate '{}' --quiet", project.display()),
+        )
+        .expect("kcl-validate --quiet returned Err");
+        assert!(printed.lock().unwrap().is_empty());
+    }
+
+    /// Test that `ValidateOptions::cwd` resolves the directory and runs KCL from it, with the
+    /// files reported relative to it.
+    #[cfg(unix)]
+    #[test]
+    fn test_validate_kcl_cwd() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let root = dir
+            .path()
+            .canonicalize()
+            .expect("Failed to canonicalize temp dir");
+        let bin = fake_kcl(&root, "test -f \"$2\" && pwd >&2; exit 1");
+        let project = root.join("project");
+        std::fs::create_dir(&project).expect("Failed to create project dir");
+        kcl_file(&project, "main.k");
+        let results = validate_kcl(
+            ValidateOptions::new("project")
+                .bin(bin)
+                .cwd(root.to_string_lossy()),
+        )
+        .expect("validate_kcl returned Err");
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].file, "project/main.k");
+        assert_eq!(
+            results[0].error.as_deref().map(str::trim),
+            Some(root.to_string_lossy().as_ref())
+        );
+    }
+
+    /// Test that `--timeout` takes a duration and kills a hanging KCL with a timeout error.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_timeout_flag() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "exec sleep 5");
+        let project = dir.path().join("project");
+        std::fs::create_dir(&project).expect("Failed to create project dir");
+        let file = kcl_file(&project, "main.k");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+
+        let start = std::time::Instant::now();
+        let msg = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-run '{}' --timeout 200ms", file.display()),
+        )
+        .expect_err("Expected kcl-run to time out");
+        assert!(start.elapsed() < std::time::Duration::from_secs(4));
+        assert!(msg.contains("KCL timed out"), "Unexpected error: {}", msg);
+        assert!(msg.contains("after 200ms"), "Unexpected error: {}", msg);
+
+        let out = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-validate '{}' -t 200ms", project.display()),
+        )
+        .expect("kcl-validate returned Err");
+        let rows = out.as_list().expect("Expected a list of records");
+        let row = rows[0].as_record().expect("Expected a record");
+        assert_eq!(
+            row.get("error"),
+            Some(&Value::test_string("Timed out after 200ms"))
+        );
+
+        let err = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-run '{}' --timeout 0sec", file.display()),
+        )
+        .expect_err("Expected kcl-run to reject a zero timeout");
+        assert!(err.contains("Invalid timeout"));
+    }
+
+    /// Test that `kcl-format --check` succeeds on formatted files and fails on the others, without writing.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_format_check_flag() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "echo 'a = 1'");
+        let good = dir.path().join("good.k");
+        std::fs::write(&good, "a = 1\n").expect("Failed to write formatted file");
+        let bad = dir.path().join("bad.k");
+        std::fs::write(&bad, "a=1\n").expect("Failed to write unformatted file");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+
+        let out = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-format '{}' --check", good.display()),
+        )
+        .expect("kcl-format --check returned Err");
+        assert!(
+            out.as_str()
+                .is_ok_and(|s| s.starts_with("✅ Already formatted"))
+        );
+
+        let msg = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-format '{}' --check", bad.display()),
+        )
+        .expect_err("Expected kcl-format --check to fail");
+        assert!(
+            msg.contains("KCL files need formatting"),
+            "Unexpected error: {}",
+            msg
+        );
+        assert!(
+            msg.contains("1 file needs formatting"),
+            "Unexpected error: {}",
+            msg
+        );
+        assert!(msg.contains("bad.k"), "Unexpected error: {}", msg);
+        assert_eq!(
+            std::fs::read_to_string(&bad).expect("Failed to read unformatted file"),
+            "a=1\n"
+        );
+    }
+
+    /// Test that `kcl-run --stream` returns the output as a list of lines.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_run_stream_lines() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "printf 'a: 1\\nb: 2\\n'");
+        let out = run_plugin(
+            &bin,
+            &format!(
+                "kcl-run '{}' --stream",
+                kcl_file(dir.path(), "main.k").display()
+            ),
+        )
+        .expect("kcl-run returned Err");
+        assert_eq!(
+            out,
+            Value::test_list(vec![Value::test_string("a: 1"), Value::test_string("b: 2")])
+        );
+    }
+
+    /// Test that `stream_kcl` yields lines while KCL is still running, not once it exits.
+    #[cfg(unix)]
+    #[test]
+    fn test_stream_kcl_incremental() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let go = dir.path().join("go");
+        // The second line is only printed once the test saw the first one (or after 10s).
+        let bin = fake_kcl(
+            dir.path(),
+            &format!(
+                "echo 'a: 1'; i=0; while [ ! -f '{}' ] && [ $i -lt 100 ]; do sleep 0.1; i=$((i+1)); done; echo 'b: 2'; echo 'c: 3'",
+                go.display()
+            ),
+        );
+        let started = std::time::Instant::now();
+        let mut lines =
+            stream_kcl(&RunOptions::new("main.k").bin(bin)).expect("stream_kcl returned Err");
+
+        let first = lines.next().expect("Expected a first line");
+        assert_eq!(first.expect("Expected a line"), "a: 1");
+        assert!(
+            started.elapsed() < std::time::Duration::from_secs(5),
+            "The first line only arrived after KCL exited"
+        );
+        std::fs::write(&go, "").expect("Failed to write marker file");
+        let rest: Vec<String> = lines.map(|line| line.expect("Expected a line")).collect();
+        assert_eq!(rest, ["b: 2", "c: 3"]);
+    }
+
+    /// Test that a failing KCL run ends its stream with KCL's error.
+    #[cfg(unix)]
+    #[test]
+    fn test_stream_kcl_failure() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(
+            dir.path(),
+            "echo 'a: 1'; echo 'EvaluationError' >&2; exit 1",
+        );
+        let lines: Vec<_> = stream_kcl(&RunOptions::new("main.k").bin(bin))
+            .expect("stream_kcl returned Err")
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].as_ref().expect("Expected a line"), "a: 1");
+        let err = lines[1].as_ref().expect_err("Expected KCL's error");
+        assert!(
+            err.to_string().contains("EvaluationError"),
+            "Unexpected error: {}",
+            err
+        );
+
+        let err = stream_kcl(&RunOptions::new("main.k").bin("kcl").format("toml"))
+            .err()
+            .expect("Expected the toml format to be rejected");
+        assert!(err.to_string().contains("cannot be streamed"));
+    }
+
+    /// Test that `--stream` is rejected along with flags that need the whole output.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_run_stream_conflicts() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "echo 'a: 1'");
+        let msg = run_plugin(
+            &bin,
+            &format!(
+                "kcl-run '{}' --stream -o '{}'",
+                kcl_file(dir.path(), "main.k").display(),
+                dir.path().join("out.yaml").display()
+            ),
+        )
+        .expect_err("Expected --stream and --output to conflict");
+        assert!(
+            msg.contains("--stream cannot be used with --output"),
+            "Unexpected error: {}",
+            msg
+        );
+    }
+
+    /// Test that piped KCL source is formatted and returned, and that `--stdout` returns a
+    /// formatted file without writing it.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_format_source() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "sed -i 's/ *= */ = /' \"$2\"");
+        let file = dir.path().join("main.k");
+        std::fs::write(&file, "a=1\nb  =  2\n").expect("Failed to write KCL file");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+
+        let out = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            "\"a=1\\nname='✅'\\n\" | kcl-format --plain",
+        )
+        .expect("kcl-format returned Err");
+        assert_eq!(out, Value::test_string("a = 1\nname = '✅'\n"));
+
+        let out = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-format '{}' --stdout", file.display()),
+        )
+        .expect("kcl-format returned Err");
+        assert_eq!(out, Value::test_string("a = 1\nb = 2\n"));
+        assert_eq!(
+            std::fs::read_to_string(&file).expect("Failed to read KCL file"),
+            "a=1\nb  =  2\n"
+        );
+
+        let err = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-format '{}' --stdout", dir.path().display()),
+        )
+        .expect_err("Expected --stdout to reject a directory");
+        assert!(err.contains("--stdout can only be used with a file"));
+    }
+
+    /// Test that formatting a directory rewrites only its unformatted files and reports each one.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_format_directory() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(
+            dir.path(),
+            "if [ \"$3\" = --std_output ]; then echo 'a = 1'; else echo 'a = 1' > \"$2\"; fi",
+        );
+        let project = dir.path().join("project");
+        std::fs::create_dir_all(project.join("lib")).expect("Failed to create project dirs");
+        std::fs::write(project.join("a.k"), "a = 1\n").expect("Failed to write formatted file");
+        std::fs::write(project.join("b.k"), "a=1\n").expect("Failed to write unformatted file");
+        std::fs::write(project.join("lib/c.k"), "a=1\n").expect("Failed to write nested file");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+
+        let out = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-format '{}'", project.display()),
+        )
+        .expect("kcl-format returned Err");
+        let row = |name: &str, changed: bool| {
+            Value::test_record(record! {
+                "file" => Value::test_string(project.join(name).to_string_lossy()),
+                "changed" => Value::test_bool(changed),
+                "status" => Value::test_string(if changed { "formatted" } else { "clean" }),
+            })
+        };
+        assert_eq!(
+            out,
+            Value::test_list(vec![row("a.k", false), row("b.k", true)])
+        );
+        assert_eq!(
+            std::fs::read_to_string(project.join("b.k")).expect("Failed to read b.k"),
+            "a = 1\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(project.join("lib/c.k")).expect("Failed to read lib/c.k"),
+            "a=1\n"
+        );
+
+        let out = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-format '{}' --recursive", project.display()),
+        )
+        .expect("kcl-format --recursive returned Err");
+        assert_eq!(out.as_list().map(|files| files.len()).ok(), Some(3));
+    }
+
+    /// Test that `kcl-import --content` returns the generated KCL and leaves no file behind,
+    /// and is rejected along with `--output`.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_import_content() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(
+            dir.path(),
+            "[ \"$4\" = --output ] && printf 'name = \"web\"\\nreplicas = 2\\n' > \"$5/data.k\"",
+        );
+        let data = dir.path().join("data.json");
+        std::fs::write(&data, r#"{"name": "web", "replicas": 2}"#)
+            .expect("Failed to write JSON file");
+        let out = run_plugin(
+            &bin,
+            &format!("kcl-import '{}' --mode json --content", data.display()),
+        )
+        .expect("kcl-import returned Err");
+        assert_eq!(out, Value::test_string("name = \"web\"\nreplicas = 2\n"));
+        assert!(!dir.path().join("data.k").exists());
+
+        let err = run_plugin(
+            &bin,
+            &format!(
+                "kcl-import '{}' --mode json --content --output '{}'",
+                data.display(),
+                dir.path().display()
+            ),
+        )
+        .expect_err("Expected --content and --output to conflict");
+        assert!(
+            err.contains("--content cannot be used with --output"),
+            "Unexpected error: {}",
+            err
+        );
+    }
+
+    /// Test that `import_kcl_source` turns JSON data into KCL source.
+    #[test]
+    fn test_import_kcl_source_json() {
+        if !kcl_installed() {
+            return;
+        }
+        let dir = tempdir().expect("Failed to create temp dir");
+        let data = dir.path().join("data.json");
+        std::fs::write(&data, r#"{"name": "web", "replicas": 2}"#)
+            .expect("Failed to write JSON file");
+        let source = import_kcl_source("kcl", &data.to_string_lossy(), "json")
+            .expect("import_kcl_source returned Err");
+        assert!(
+            source.contains("name = \"web\""),
+            "Unexpected source: {}",
+            source
+        );
+    }
+
+    /// Test that `kcl-format --diff` returns the formatting changes and leaves the file untouched.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_format_diff() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "echo 'a = 1' > \"$2\"");
+        let file = dir.path().join("main.k");
+        std::fs::write(&file, "a=1\n").expect("Failed to write unformatted file");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+
+        let out = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-format '{}' --diff", file.display()),
+        )
+        .expect("kcl-format --diff returned Err");
+        let diff = out.as_str().expect("Expected a string");
+        assert!(diff.contains("-a=1\n+a = 1\n"), "Unexpected diff: {}", diff);
+        assert_eq!(
+            std::fs::read_to_string(&file).expect("Failed to read main.k"),
+            "a=1\n"
+        );
+
+        std::fs::write(&file, "a = 1\n").expect("Failed to write formatted file");
+        let diff = format_kcl_diff(&bin, &file.to_string_lossy(), false)
+            .expect("format_kcl_diff returned Err");
+        assert!(diff.is_empty(), "Unexpected diff: {}", diff);
+    }
+
+    /// Test that ANSI escape sequences are stripped from KCL output unless colors are kept.
+    #[cfg(unix)]
+    #[test]
+    fn test_strip_ansi() {
+        assert_eq!(
+            strip_ansi("\x1b[1;31merror\x1b[0m: \x1b]8;;file:///a.k\x07a.k\x1b]8;;\x07"),
+            "error: a.k"
+        );
+        assert_eq!(strip_ansi("a: 1\n"), "a: 1\n");
+
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "printf '\\033[32ma\\033[0m: 1\\n'");
+        let plain = run_kcl_command(&RunOptions::new("main.k").bin(&bin))
+            .expect("run_kcl_command returned Err");
+        assert_eq!(plain.text, "✅ a: 1\n");
+        let colored = run_kcl_command(&RunOptions::new("main.k").bin(&bin).color(true))
+            .expect("run_kcl_command returned Err");
+        assert_eq!(colored.text, "✅ \x1b[32ma\x1b[0m: 1\n");
+    }
+
+    /// Test that `kcl-run --dry-run` returns the exact command line without running KCL.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_run_dry_run() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let marker = dir.path().join("ran");
+        let bin = fake_kcl(dir.path(), &format!("touch '{}'", marker.display()));
+        let file = kcl_file(dir.path(), "main.k");
+        let output = dir.path().join("out.yaml");
+        let out = run_plugin(&bin, &format!("kcl-run '{}' -D env=prod -D replicas=3 -o '{}' --dry-run --pre-run-hook 'touch {}'", file.display(),
+                output.display(),
+                marker.display()))
+            .expect("kcl-run --dry-run returned Err");
+        let file = file.to_string_lossy();
+        let output = output.to_string_lossy();
+        let expected = [
+            bin.as_str(),
+            "run",
+            &file,
+            "--format",
+            "yaml",
+            "-D",
+            "env=prod",
+            "-D",
+            "replicas=3",
+            "-o",
+            &output,
+        ];
+        assert_eq!(
+            out,
+            Value::test_list(expected.into_iter().map(Value::test_string).collect())
+        );
+        assert!(!marker.exists(), "kcl or the pre-run hook was executed");
+    }
+
+    /// Test that errors about a path argument point at the argument, not the command name.
+    #[cfg(unix)]
+    #[test]
+    fn test_error_span_points_at_argument() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "echo 'KCL format failed' >&2; exit 1");
+        let missing = format!("'{}'", dir.path().join("missing.k").display());
+        let existing = format!("'{}'", kcl_file(dir.path(), "main.k").display());
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+
+        for (source, arg, msg) in [
+            (
+                format!("kcl-format {}", missing),
+                &missing,
+                "KCL file not found",
+            ),
+            (
+                format!("$env.KCL_BIN = '{}'; kcl-format {}", bin, existing),
+                &existing,
+                "Error formatting KCL",
+            ),
+        ] {
+            let err = plugin_test
+                .eval(&source)
+                .expect_err("Expected kcl-format to fail");
+            let nu_protocol::ShellError::LabeledError(err) = err else {
+                panic!("Unexpected error: {:?}", err);
+            };
+            assert_eq!(err.msg, msg);
+            let span = err.labels[0].span;
+            assert_eq!(
+                span.end - span.start,
+                arg.len(),
+                "Label does not cover the argument"
+            );
+        }
+    }
+
+    /// Test that `kcl-run --meta` reports the exit code of successful and failing runs.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_run_meta() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(
+            dir.path(),
+            "case \"$2\" in *bad.k) echo 'EvaluationError' >&2; exit 3;; esac\necho 'a: 1'",
+        );
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+        let mut meta = |name: &str| {
+            eval_plugin(
+                &mut plugin_test,
+                &bin,
+                &format!("kcl-run '{}' --meta", kcl_file(dir.path(), name).display()),
+            )
+            .expect("kcl-run --meta returned Err")
+        };
+
+        let good = meta("good.k");
+        let good = good.as_record().expect("Expected a record");
+        assert_eq!(good.get("exit_code"), Some(&Value::test_int(0)));
+        assert_eq!(good.get("success"), Some(&Value::test_bool(true)));
+        assert_eq!(good.get("stdout"), Some(&Value::test_string("a: 1\n")));
+
+        let bad = meta("bad.k");
+        let bad = bad.as_record().expect("Expected a record");
+        assert_eq!(bad.get("exit_code"), Some(&Value::test_int(3)));
+        assert_eq!(bad.get("success"), Some(&Value::test_bool(false)));
+        assert_eq!(
+            bad.get("stderr"),
+            Some(&Value::test_string("EvaluationError\n"))
+        );
+
+        let err =
+            run_kcl_command(&RunOptions::new(dir.path().join("bad.k").to_string_lossy()).bin(&bin))
+                .expect_err("Expected a failing run to be an error without --meta");
+        assert!(err.to_string().contains("EvaluationError"));
+    }
+
+    /// Test that `--overrides-file` entries are passed as `-O` (or `-D` with `--as define`)
+    /// before the explicit flags, skipping comments and blank lines.
+    #[test]
+    fn test_kcl_run_overrides_file() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let file = kcl_file(dir.path(), "main.k");
+        let overrides = dir.path().join("overrides.txt");
+        std::fs::write(
+            &overrides,
+            "# Production overrides\n\napp.replicas=3\n  app.image = \"nginx:1.27\"  \n# tag#1 stays in the value\napp.tag=v1#2\n",
+        )
+        .expect("Failed to write overrides file");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+        let mut args = |flags: &str| -> Vec<String> {
+            let out = plugin_test
+                .eval(&format!(
+                    "kcl-run '{}' --overrides-file '{}' {} --dry-run",
+                    file.display(),
+                    overrides.display(),
+                    flags
+                ))
+                .expect("kcl-run returned Err")
+                .into_value(Span::test_data())
+                .expect("Failed to collect kcl-run output");
+            out.as_list()
+                .expect("Expected a list")
+                .iter()
+                .skip(5)
+                .map(|arg| arg.as_str().expect("Expected a string").to_string())
+                .collect()
+        };
+
+        assert_eq!(
+            args("-O app.replicas=5"),
+            [
+                "-O",
+                "app.replicas=3",
+                "-O",
+                "app.image = \"nginx:1.27\"",
+                "-O",
+                "app.tag=v1#2",
+                "-O",
+                "app.replicas=5"
+            ]
+        );
+        assert_eq!(
+            args("--as define -D env=prod"),
+            [
+                "-D",
+                "app.replicas=3",
+                "-D",
+                "app.image = \"nginx:1.27\"",
+                "-D",
+                "app.tag=v1#2",
+                "-D",
+                "env=prod"
+            ]
+        );
+
+        std::fs::write(&overrides, "# ok\napp.replicas=3\n\nreplicas 3\n")
+            .expect("Failed to write overrides file");
+        let err = read_overrides_file(&overrides.to_string_lossy())
+            .expect_err("Expected a line without '=' to fail");
+        assert!(
+            err.to_string()
+                .contains(":4: expected key=value, got 'replicas 3'"),
+            "{}",
+            err
+        );
+    }
+
+    /// Test that `--merge` passes every file to a single `kcl run` and returns its merged output.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_run_merge() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        // Prints `name` from base.k and `replicas` from prod.k, only if both are compiled together.
+        let bin = fake_kcl(
+            dir.path(),
+            "case \"$*\" in *base.k*prod.k*) printf 'app:\\n  name: web\\n  replicas: 3\\n';; *) echo 'incomplete config' >&2; exit 1;; esac",
+        );
+        let base = kcl_file(dir.path(), "base.k");
+        let prod = kcl_file(dir.path(), "prod.k");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+
+        let out = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-run '{}' '{}' --merge", base.display(), prod.display()),
+        )
+        .expect("kcl-run returned Err");
+        assert_eq!(
+            out,
+            Value::test_string("✅ app:\n  name: web\n  replicas: 3\n")
+        );
+
+        let out = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!(
+                "kcl-run '{}' '{}' --merge --split-docs",
+                base.display(),
+                prod.display()
+            ),
+        )
+        .expect("kcl-run returned Err");
+        assert_eq!(
+            out,
+            Value::test_list(vec![Value::test_record(record! {
+                "app" => Value::test_record(record! {
+                    "name" => Value::test_string("web"),
+                    "replicas" => Value::test_int(3),
+                }),
+            })])
+        );
+
+        let err = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-run '{}' '{}'", base.display(), prod.display()),
+        )
+        .expect_err("Expected the files to fail when run separately");
+        assert!(err.contains("2 of 2 files failed"));
+    }
+
+    /// Test that `--env` sets environment variables for the KCL process and rejects
+    /// malformed entries.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_run_env() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "echo \"$KCL_PLUGIN_TOKEN $KCL_PLUGIN_REGION\"");
+        let file = kcl_file(dir.path(), "main.k");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+
+        let out = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!(
+                "kcl-run '{}' --env KCL_PLUGIN_TOKEN=a=b --env [KCL_PLUGIN_REGION=eu]",
+                file.display()
+            ),
+        )
+        .expect("kcl-run returned Err");
+        assert_eq!(out, Value::test_string("✅ a=b eu\n"));
+
+        let err = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-run '{}' --env KCL_PLUGIN_TOKEN", file.display()),
+        )
+        .expect_err("Expected an entry without '=' to fail");
+        assert!(err.contains("Invalid environment variable"));
+
+        assert_eq!(
+            parse_env_vars(&["A=1".to_string(), " B =x=y".to_string()]).expect("Valid entries"),
+            [
+                ("A".to_string(), "1".to_string()),
+                ("B".to_string(), "x=y".to_string())
+            ]
+        );
+        assert!(parse_env_vars(&["=1".to_string()]).is_err());
+    }
+
+    /// Test that hooks run in the directory and environment of KCL, so a relative file a
+    /// pre-run hook writes is the one KCL reads.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_run_hooks_cwd() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "cat input.yaml");
+        let work = dir.path().join("work");
+        std::fs::create_dir_all(&work).expect("Failed to create working dir");
+        let file = kcl_file(&work, "main.k");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+
+        let out = eval_plugin(&mut plugin_test, &bin, &format!("kcl-run '{}' -C '{}' --env KCL_HOOK_VALUE=prod --pre-run-hook 'echo \"env: $KCL_HOOK_VALUE\" > input.yaml' --post-run-hook 'rm input.yaml'", file.display(),
+                work.display()))
+            .expect("kcl-run returned Err");
+        assert_eq!(out, Value::test_string("✅ env: prod\n"));
+        assert!(!work.join("input.yaml").exists());
+    }
+
+    /// Test that kcl-run `--disable-none` forwards `--disable_none`, dropping null keys.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_run_disable_none() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(
+            dir.path(),
+            "case \"$*\" in\n  *--disable_none*) printf 'app:\\n  name: web\\n';;\n  *) printf 'app:\\n  name: web\\n  image: null\\n';;\nesac",
+        );
+        let file = kcl_file(dir.path(), "main.k");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+
+        let mut app = |flags: &str| {
+            eval_plugin(
+                &mut plugin_test,
+                &bin,
+                &format!(
+                    "(kcl-run '{}' --split-docs {}).0.app",
+                    file.display(),
+                    flags
+                ),
+            )
+            .expect("kcl-run returned Err")
+        };
+        assert_eq!(
+            app(""),
+            Value::test_record(record! {
+                "name" => Value::test_string("web"),
+                "image" => Value::test_nothing(),
+            })
+        );
+        assert_eq!(
+            app("--disable-none"),
+            Value::test_record(record! { "name" => Value::test_string("web") })
+        );
+    }
+
+    /// Test that kcl-run `--package` runs the directory holding `kcl.mod` instead of the file.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_run_package() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "echo \"$2\"");
+        let package = dir.path().join("app");
+        std::fs::create_dir_all(&package).expect("Failed to create package dir");
+        std::fs::write(package.join("kcl.mod"), "[package]\nname = \"app\"\n")
+            .expect("Failed to write kcl.mod");
+        let main = kcl_file(&package, "main.k");
+        let loose = kcl_file(dir.path(), "loose.k");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+
+        let mut run =
+            |args: &str| eval_plugin(&mut plugin_test, &bin, &format!("kcl-run {}", args));
+        let expected = Value::test_string(format!("✅ {}\n", package.display()));
+        assert_eq!(
+            run(&format!("'{}' --package", main.display())).expect("kcl-run returned Err"),
+            expected
+        );
+        assert_eq!(
+            run(&format!("'{}' --package", package.display())).expect("kcl-run returned Err"),
+            expected
+        );
+        assert_eq!(
+            run(&format!("--package -C '{}'", package.display())).expect("kcl-run returned Err"),
+            expected
+        );
+        assert_eq!(
+            run(&format!("'{}'", main.display())).expect("kcl-run returned Err"),
+            Value::test_string(format!("✅ {}\n", main.display()))
+        );
+
+        let err = run(&format!("'{}' --package", loose.display()))
+            .expect_err("Expected a file outside a package to fail");
+        assert!(err.contains("Not a KCL package"));
+        let err = run(&format!(
+            "'{}' '{}' --package",
+            main.display(),
+            loose.display()
+        ))
+        .expect_err("Expected several files to fail");
+        assert!(err.contains("Conflicting arguments"));
+        assert_eq!(kcl_package_dir(&package), Some(package.clone()));
+        assert_eq!(kcl_package_dir(dir.path()), None);
+    }
+
+    /// Test that kcl-run runs a nested file from its package root, where sibling modules resolve.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_run_package_root() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(
+            dir.path(),
+            "[ -f lib/lib.k ] || { echo \"module 'lib' not found\" >&2; exit 1; }\necho \"$(pwd) $2\"",
+        );
+        let package = dir.path().join("pkg");
+        let nested = package.join("deep/nested");
+        std::fs::create_dir_all(&nested).expect("Failed to create nested dir");
+        std::fs::create_dir_all(package.join("lib")).expect("Failed to create lib dir");
+        std::fs::write(package.join("kcl.mod"), "[package]\nname = \"pkg\"\n")
+            .expect("Failed to write kcl.mod");
+        std::fs::write(package.join("lib/lib.k"), "name = \"lib\"\n")
+            .expect("Failed to write lib.k");
+        let foo = nested.join("foo.k");
+        std::fs::write(&foo, "import lib\n\nvalue = lib.name\n").expect("Failed to write foo.k");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+
+        let out = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-run '{}'", foo.display()),
+        )
+        .expect("kcl-run returned Err");
+        assert_eq!(
+            out,
+            Value::test_string(format!("✅ {} {}\n", package.display(), foo.display()))
+        );
+
+        let err = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!(
+                "kcl-run '{}' --no-package-root -C '{}'",
+                foo.display(),
+                nested.display()
+            ),
+        )
+        .expect_err("Expected the import to fail outside the package root");
+        assert!(err.contains("module 'lib' not found"));
+
+        // Relative paths keep pointing at the same files from the package root.
+        let opts = RunOptions::new("pkg/deep/nested/foo.k")
+            .settings(["ci.yaml"])
+            .cwd(dir.path().to_string_lossy())
+            .package_root(true)
+            .in_package_root();
+        assert_eq!(opts.cwd, Some(package.to_string_lossy().into_owned()));
+        assert_eq!(opts.files, [foo.to_string_lossy()]);
+        assert_eq!(
+            opts.settings,
+            [dir.path().join("ci.yaml").to_string_lossy()]
+        );
+        assert_eq!(find_package_root(&foo), Some(package.clone()));
+        assert_eq!(find_package_root(dir.path()), None);
+        let outside = RunOptions::new("main.k")
+            .cwd(dir.path().to_string_lossy())
+            .package_root(true);
+        assert_eq!(outside.in_package_root().files, ["main.k"]);
+    }
+
+    /// Test that `--debug` records the full invocation of a successful run.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_run_debug() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "echo 'a: 1'\necho 'warning: deprecated' >&2");
+        let file = kcl_file(dir.path(), "main.k");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+
+        let out = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!(
+                "kcl-run '{}' --debug -D env=prod -C '{}'",
+                file.display(),
+                dir.path().display()
+            ),
+        )
+        .expect("kcl-run returned Err");
+        let record = out.as_record().expect("Expected a record");
+        for field in [
+            "bin",
+            "args",
+            "cwd",
+            "stdout",
+            "stderr",
+            "exit_code",
+            "duration",
+        ] {
+            let value = record
+                .get(field)
+                .unwrap_or_else(|| panic!("Missing {}", field));
+            assert!(!value.is_nothing(), "{} is empty", field);
+        }
+        assert_eq!(record.get("bin"), Some(&Value::test_string(&bin)));
+        let args: Vec<String> = record
+            .get("args")
+            .and_then(|args| args.as_list().ok())
+            .expect("Expected a list of args")
+            .iter()
+            .map(|arg| arg.as_str().expect("Expected a string").to_string())
+            .collect();
+        assert_eq!(
+            args,
+            [
+                "run",
+                &file.display().to_string(),
+                "--format",
+                "yaml",
+                "-D",
+                "env=prod"
+            ]
+        );
+        assert_eq!(
+            record.get("cwd"),
+            Some(&Value::test_string(dir.path().to_string_lossy()))
+        );
+        assert_eq!(record.get("stdout"), Some(&Value::test_string("a: 1\n")));
+        assert_eq!(
+            record.get("stderr"),
+            Some(&Value::test_string("warning: deprecated\n"))
+        );
+        assert_eq!(record.get("exit_code"), Some(&Value::test_int(0)));
+        assert!(
+            record
+                .get("duration")
+                .is_some_and(|d| d.as_duration().is_ok_and(|d| d > 0))
+        );
+
+        let err = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-run '{}' --debug --meta", file.display()),
+        )
+        .expect_err("Expected --debug and --meta to conflict");
+        assert!(err.contains("Conflicting arguments"));
+    }
+
+    /// Test that `--capture`, an alias of `--meta`, returns the converted output and the status
+    /// of KCL for passing and failing runs.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_run_capture() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(
+            dir.path(),
+            "case \"$2\" in *bad.k) echo 'partial'; echo 'EvaluationError' >&2; exit 3;; esac\necho '{\"a\": 1}'; echo 'deprecated' >&2",
+        );
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+        let mut capture = |name: &str| {
+            eval_plugin(
+                &mut plugin_test,
+                &bin,
+                &format!(
+                    "kcl-run '{}' -f toml --capture",
+                    kcl_file(dir.path(), name).display()
+                ),
+            )
+            .expect("kcl-run --capture returned Err")
+        };
+
+        let good = capture("good.k");
+        assert_eq!(
+            good,
+            Value::test_record(record! {
+                "stdout" => Value::test_string("a = 1\n"),
+                "stderr" => Value::test_string("deprecated\n"),
+                "exit_code" => Value::test_int(0),
+                "success" => Value::test_bool(true),
+            })
+        );
+
+        let bad = capture("bad.k");
+        assert_eq!(
+            bad,
+            Value::test_record(record! {
+                "stdout" => Value::test_string("partial\n"),
+                "stderr" => Value::test_string("EvaluationError\n"),
+                "exit_code" => Value::test_int(3),
+                "success" => Value::test_bool(false),
+            })
+        );
+    }
+
+    /// Test that relative paths resolve against the shell's current directory, not the plugin's.
+    #[cfg(unix)]
+    #[test]
+    fn test_relative_paths_use_shell_cwd() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "pwd");
+        let shell_dir = dir.path().join("shell");
+        std::fs::create_dir(&shell_dir).expect("Failed to create shell dir");
+        kcl_file(&shell_dir, "main.k");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+        plugin_test.engine_state_mut().add_env_var(
+            "PWD".to_string(),
+            Value::test_string(shell_dir.to_string_lossy()),
+        );
+
+        let out =
+            eval_plugin(&mut plugin_test, &bin, "kcl-run main.k").expect("kcl-run returned Err");
+        let shell_dir = shell_dir
+            .canonicalize()
+            .expect("Failed to resolve shell dir");
+        assert_eq!(
+            out,
+            Value::test_string(format!("✅ {}\n", shell_dir.display()))
+        );
+
+        let err = eval_plugin(&mut plugin_test, &bin, "kcl-run other.k")
+            .expect_err("Expected a missing file in the shell's directory to be reported");
+        assert!(err.contains("KCL file not found"));
+    }
+
+    /// Test that `kcl-format --check --diff` fails showing the proposed changes, without writing.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_format_check_diff() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(
+            dir.path(),
+            "printf 'schema App:\\n    name: str\\n    replicas: int\\n' > \"$2\"",
+        );
+        let file = dir.path().join("app.k");
+        let misaligned = "schema App:\n  name: str\n    replicas: int\n";
+        std::fs::write(&file, misaligned).expect("Failed to write misaligned file");
+        // The diff is checked in the help of the error, not its debug representation.
+        let err = PluginTest::new("kcl", KclWrapperPlugin.into())
+            .expect("Failed to create plugin test")
+            .eval(&format!(
+                "$env.KCL_BIN = '{}'; kcl-format '{}' --check --diff",
+                bin,
+                file.display()
+            ))
+            .expect_err("Expected kcl-format --check --diff to fail");
+        let nu_protocol::ShellError::LabeledError(err) = err else {
+            panic!("Unexpected error: {:?}", err);
+        };
+        assert_eq!(err.msg, "KCL files need formatting");
+        let help = err.help.unwrap_or_default();
+        assert!(
+            help.contains("-  name: str\n+    name: str\n"),
+            "Unexpected diff: {}",
+            help
+        );
+        assert!(!help.contains("-    replicas"), "Unexpected diff: {}", help);
+        assert_eq!(
+            std::fs::read_to_string(&file).expect("Failed to read app.k"),
+            misaligned
+        );
+    }
+
+    /// Test that `~` and environment variables are expanded in paths, and undefined ones are reported.
+    #[test]
+    fn test_expand_path() {
+        let lookup = |name: &str| match name {
+            "HOME" | "USERPROFILE" => Some("/home/kcl".to_string()),
+            "PROJECT" => Some("/work/app".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            expand_path("~", lookup).expect("~ not expanded"),
+            "/home/kcl"
+        );
+        assert_eq!(
+            expand_path("~/sub/main.k", lookup).expect("~/sub not expanded"),
+            "/home/kcl/sub/main.k"
+        );
+        assert_eq!(
+            expand_path("$PROJECT/main.k", lookup).expect("$VAR not expanded"),
+            "/work/app/main.k"
+        );
+        assert_eq!(
+            expand_path("${PROJECT}_v2/main.k", lookup).expect("${VAR} not expanded"),
+            "/work/app_v2/main.k"
+        );
+        assert_eq!(
+            expand_path("~user/main.k", lookup).expect("~user changed"),
+            "~user/main.k"
+        );
+
+        let err =
+            expand_path("$MISSING/main.k", lookup).expect_err("Expected an undefined variable");
+        assert!(
+            err.to_string().contains("$MISSING is not defined"),
+            "Unexpected error: {}",
+            err
+        );
+        let err = expand_path("~/main.k", |_| None).expect_err("Expected an undefined home");
+        assert!(
+            err.to_string().contains("is not defined"),
+            "Unexpected error: {}",
+            err
+        );
+    }
+
+    /// Test that `kcl-run` expands shell variables in file arguments.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_run_expands_variables() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "echo \"$2\"");
+        let file = kcl_file(dir.path(), "main.k");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+
+        let out = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!(
+                "$env.KCL_PROJECT = '{}'; kcl-run '$KCL_PROJECT/main.k'",
+                dir.path().display()
+            ),
+        )
+        .expect("kcl-run returned Err");
+        assert_eq!(out, Value::test_string(format!("✅ {}\n", file.display())));
+
+        let msg = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            "kcl-run '$KCL_UNDEFINED_DIR/main.k'",
+        )
+        .expect_err("Expected an undefined variable to be reported");
+        assert!(msg.contains("Invalid path"), "Unexpected error: {}", msg);
+        assert!(
+            msg.contains("$KCL_UNDEFINED_DIR is not defined"),
+            "Unexpected error: {}",
+            msg
+        );
+    }
+
+    /// Test that `kcl-validate` checks just the given file, or every file of a directory.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_validate_file_or_directory() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(
+            dir.path(),
+            "case \"$2\" in *bad.k) echo 'CompileError' >&2; exit 1;; esac",
+        );
+        let project = dir.path().join("project");
+        std::fs::create_dir(&project).expect("Failed to create project dir");
+        let good = kcl_file(&project, "good.k");
+        kcl_file(&project, "bad.k");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+
+        let out = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-validate '{}' --summary", good.display()),
+        )
+        .expect("kcl-validate returned Err");
+        assert_eq!(
+            out,
+            Value::test_string(format!("✅ 1 file is valid\n\n✅ {}", good.display()))
+        );
+
+        let out = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-validate '{}' --stats", project.display()),
+        )
+        .expect("kcl-validate returned Err");
+        let stats = out.as_record().expect("Expected a record");
+        assert_eq!(stats.get("total"), Some(&Value::test_int(2)));
+        assert_eq!(stats.get("invalid"), Some(&Value::test_int(1)));
+    }
+
+    /// Test that `kcl-validate --stats` counts the files of a mixed directory and times the run.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_validate_stats() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(
+            dir.path(),
+            "sleep 0.1\ncase \"$2\" in *bad*.k) echo 'CompileError' >&2; exit 1;; esac",
+        );
+        let project = dir.path().join("project");
+        std::fs::create_dir(&project).expect("Failed to create project dir");
+        for name in ["a.k", "b.k", "c.k", "bad1.k", "bad2.k"] {
+            kcl_file(&project, name);
+        }
+        let out = run_plugin(
+            &bin,
+            &format!("kcl-validate '{}' --stats -j 1", project.display()),
+        )
+        .expect("kcl-validate returned Err");
+        let stats = out.as_record().expect("Expected a record");
+        assert_eq!(stats.get("total"), Some(&Value::test_int(5)));
+        assert_eq!(stats.get("valid"), Some(&Value::test_int(3)));
+        assert_eq!(stats.get("invalid"), Some(&Value::test_int(2)));
+        assert_eq!(stats.get("ignored"), Some(&Value::test_int(0)));
+        assert_eq!(stats.get("all_valid"), Some(&Value::test_bool(false)));
+        let duration_ms = stats
+            .get("duration_ms")
+            .and_then(|value| value.as_int().ok())
+            .expect("Expected an int duration_ms");
+        assert!(duration_ms >= 500, "duration_ms is {}", duration_ms);
+    }
+
+    /// Test that `kcl.mod` is read into its package and dependency tables.
+    #[test]
+    fn test_read_kcl_mod() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        std::fs::write(
+            dir.path().join("kcl.mod"),
+            "[package]\nname = \"app\"\nedition = \"v0.10.0\"\nversion = \"0.0.1\"\n\n[dependencies]\nk8s = \"1.28\"\nhelpers = { git = \"https://github.com/kcl-lang/helpers\", tag = \"v0.1.0\" }\n",
+        )
+        .expect("Failed to write kcl.mod");
+        let manifest =
+            read_kcl_mod(&dir.path().to_string_lossy()).expect("read_kcl_mod returned Err");
+        assert_eq!(manifest["package"]["name"], "app");
+        assert_eq!(manifest["dependencies"]["k8s"], "1.28");
+        assert_eq!(manifest["dependencies"]["helpers"]["tag"], "v0.1.0");
+
+        std::fs::write(dir.path().join("kcl.mod"), "[package]\nname = \"empty\"\n")
+            .expect("Failed to write kcl.mod");
+        let manifest =
+            read_kcl_mod(&dir.path().to_string_lossy()).expect("read_kcl_mod returned Err");
+        assert_eq!(manifest["dependencies"], serde_json::json!({}));
+    }
+
+    /// Test that `kcl-mod` runs `kcl mod metadata` in the package directory and returns its dependencies.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_mod_metadata() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let log = dir.path().join("args");
+        let bin = fake_kcl(
+            dir.path(),
+            &format!("echo \"$(pwd) $@\" > '{}'", log.display()),
+        );
+        let package = dir.path().join("app");
+        std::fs::create_dir(&package).expect("Failed to create package dir");
+        std::fs::write(
+            package.join("kcl.mod"),
+            "[package]\nname = \"app\"\n\n[dependencies]\nk8s = \"1.28\"\n",
+        )
+        .expect("Failed to write kcl.mod");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+
+        let out = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-mod -C '{}'", package.display()),
+        )
+        .expect("kcl-mod returned Err");
+        let dependencies = out
+            .as_record()
+            .ok()
+            .and_then(|record| record.get("dependencies"))
+            .expect("Expected a dependencies field");
+        assert_eq!(
+            dependencies,
+            &Value::test_record(record! { "k8s" => Value::test_string("1.28") })
+        );
+        let args = std::fs::read_to_string(&log).expect("kcl was not run");
+        let package = package
+            .canonicalize()
+            .expect("Failed to resolve package dir");
+        assert_eq!(args.trim(), format!("{} mod metadata", package.display()));
+
+        let err = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-mod --action add -C '{}'", dir.path().display()),
+        )
+        .expect_err("Expected add without a package to fail");
+        assert!(err.contains("needs a package"));
+    }
+
+    /// Test that a relative `kcl-validate --schema` resolves against the shell's directory,
+    /// not `--working-dir`.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_validate_schema_path() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let log = dir.path().join("args");
+        let bin = fake_kcl(dir.path(), &format!("echo \"$@\" > '{}'", log.display()));
+        let data = dir.path().join("data");
+        std::fs::create_dir(&data).expect("Failed to create data dir");
+        std::fs::write(data.join("app.json"), "{}").expect("Failed to write data file");
+        let schemas = dir.path().join("schemas");
+        std::fs::create_dir(&schemas).expect("Failed to create schemas dir");
+        let schema = kcl_file(&schemas, "app.k");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+        plugin_test.engine_state_mut().add_env_var(
+            "PWD".to_string(),
+            Value::test_string(dir.path().to_string_lossy()),
+        );
+
+        eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!(
+                "kcl-validate '{}' --schema schemas/app.k -C '{}'",
+                data.display(),
+                data.display()
+            ),
+        )
+        .expect("kcl-validate returned Err");
+        let args = std::fs::read_to_string(&log).expect("kcl was not run");
+        assert!(
+            args.contains(&schema.display().to_string()),
+            "Unexpected arguments: {}",
+            args
+        );
+    }
+
+    /// Test that `kcl-validate` only compiles files by default, and runs them with `--compile-only false`,
+    /// reporting an invalid file as failing either way.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_validate_compile_only() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let log = dir.path().join("commands");
+        let bin = fake_kcl(
+            dir.path(),
+            &format!(
+                "echo \"$1\" >> '{}'\ncase \"$2\" in *bad.k) echo 'CompileError' >&2; exit 1;; esac\necho 'a: 1'",
+                log.display()
+            ),
+        );
+        let project = dir.path().join("project");
+        std::fs::create_dir(&project).expect("Failed to create project dir");
+        kcl_file(&project, "good.k");
+        kcl_file(&project, "bad.k");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+
+        for (flag, command) in [("", "lint"), ("--compile-only false", "run")] {
+            let out = eval_plugin(
+                &mut plugin_test,
+                &bin,
+                &format!("kcl-validate '{}' --stats {}", project.display(), flag),
+            )
+            .expect("kcl-validate returned Err");
+            let stats = out.as_record().expect("Expected a record");
+            assert_eq!(stats.get("valid"), Some(&Value::test_int(1)));
+            assert_eq!(stats.get("invalid"), Some(&Value::test_int(1)));
+            let commands = std::fs::read_to_string(&log).expect("kcl was not run");
+            assert_eq!(commands, format!("{}\n{}\n", command, command));
+            std::fs::remove_file(&log).expect("Failed to remove log");
+        }
+    }
+
+    /// Test that a glob argument or `--include` globs restrict `kcl-validate` to the matching files,
+    /// reported under the path given, relative to the working directory.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_validate_glob() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "");
+        let config = dir.path().join("config");
+        std::fs::create_dir_all(config.join("envs")).expect("Failed to create config dir");
+        kcl_file(&config, "app.k");
+        kcl_file(&config.join("envs"), "prod.k");
+        kcl_file(dir.path(), "other.k");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+
+        // A relative directory argument is expanded against the shell's current directory.
+        plugin_test.engine_state_mut().add_env_var(
+            "PWD".to_string(),
+            Value::test_string(dir.path().to_string_lossy()),
+        );
+        let root = dir.path().display();
+        for (args, prefix) in [
+            ("'config/**/*.k'".to_string(), "config".to_string()),
+            (
+                "'./config' --include ['*.k' 'envs/*.k']".to_string(),
+                "./config".to_string(),
+            ),
+            (
+                format!("'{}' --include [config/*.k config/envs/*.k]", root),
+                config.display().to_string(),
+            ),
+        ] {
+            let out = eval_plugin(
+                &mut plugin_test,
+                &bin,
+                &format!("kcl-validate -C '{}' --summary {}", root, args),
+            )
+            .expect("kcl-validate returned Err");
+            let expected = format!(
+                "✅ All 2 files are valid\n\n✅ {}/app.k\n✅ {}/envs/prod.k",
+                prefix, prefix
+            );
+            assert_eq!(out, Value::test_string(expected), "{}", args);
+        }
+    }
+
+    /// Test that `kcl-validate --fail-fast` stops at the first invalid file.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_validate_fail_fast() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(
+            dir.path(),
+            "case \"$2\" in *c_bad.k) sleep 0.3;; esac\ncase \"$2\" in *bad.k) echo 'CompileError' >&2; exit 1;; esac",
+        );
+        let project = dir.path().join("project");
+        std::fs::create_dir(&project).expect("Failed to create project dir");
+        let first = kcl_file(&project, "a_bad.k");
+        kcl_file(&project, "b_good.k");
+        kcl_file(&project, "c_bad.k");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+
+        let out = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-validate '{}' --fail-fast -j 1", project.display()),
+        )
+        .expect("kcl-validate returned Err");
+        assert_eq!(
+            out,
+            Value::test_list(vec![Value::test_record(record! {
+                "file" => Value::test_string(first.display().to_string()),
+                "valid" => Value::test_bool(false),
+                "status" => Value::test_string("invalid"),
+                "error" => Value::test_string("CompileError\n"),
+            })])
+        );
+
+        let out = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-validate '{}' --stats", project.display()),
+        )
+        .expect("kcl-validate returned Err");
+        let stats = out.as_record().expect("Expected a record");
+        assert_eq!(stats.get("invalid"), Some(&Value::test_int(2)));
+
+        // In parallel, the first failure kills the checks still running and is the only
+        // one reported.
+        let out = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!(
+                "kcl-validate '{}' --fail-fast -j 3 --stats",
+                project.display()
+            ),
+        )
+        .expect("kcl-validate returned Err");
+        let stats = out.as_record().expect("Expected a record");
+        assert_eq!(stats.get("total"), Some(&Value::test_int(1)));
+        assert_eq!(stats.get("invalid"), Some(&Value::test_int(1)));
+    }
+
+    /// Test that `kcl-validate --exclude '*_test.k'` skips test files at any depth.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_validate_exclude_tests() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "");
+        let project = dir.path().join("project");
+        std::fs::create_dir_all(project.join("lib")).expect("Failed to create project dir");
+        let main = kcl_file(&project, "main.k");
+        kcl_file(&project, "main_test.k");
+        let lib = kcl_file(&project.join("lib"), "lib.k");
+        kcl_file(&project.join("lib"), "lib_test.k");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+
+        let out = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!(
+                "kcl-validate '{}' --exclude '*_test.k' --summary",
+                project.display()
+            ),
+        )
+        .expect("kcl-validate returned Err");
+        assert_eq!(
+            out,
+            Value::test_string(format!(
+                "✅ All 2 files are valid\n\n✅ {}\n✅ {}",
+                lib.display(),
+                main.display()
+            ))
+        );
+    }
+
+    /// Test that `kcl-export` returns the OpenAPI spec KCL generates for a schema file's package.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_export_openapi() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let log = dir.path().join("args");
+        // Write the spec into the directory following --target, as `kcl doc generate` does.
+        let bin = fake_kcl(
+            dir.path(),
+            &format!(
+                "echo \"$@\" > '{}'\nwhile [ \"$1\" != --target ]; do shift; done\nmkdir -p \"$2/docs\"\necho '{{\"openapi\": \"3.0.0\", \"components\": {{\"schemas\": {{\"App\": {{\"type\": \"object\"}}}}}}}}' > \"$2/docs/api.json\"",
+                log.display()
+            ),
+        );
+        let models = dir.path().join("models");
+        std::fs::create_dir(&models).expect("Failed to create models dir");
+        let schema = models.join("app.k");
+        std::fs::write(&schema, "schema App:\n    name: str\n").expect("Failed to write schema");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+
+        let out = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-export '{}' --target openapi", schema.display()),
+        )
+        .expect("kcl-export returned Err");
+        let spec = out.as_record().expect("Expected a record");
+        assert_eq!(spec.get("openapi"), Some(&Value::test_string("3.0.0")));
+        assert!(spec.get("components").is_some());
+        let args = std::fs::read_to_string(&log).expect("kcl was not run");
+        assert!(
+            args.starts_with(&format!(
+                "doc generate --format openapi --file-path {} --target",
+                models.display()
+            )),
+            "{}",
+            args
+        );
+
+        let output = dir.path().join("openapi.json");
+        eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!(
+                "kcl-export '{}' -o '{}'",
+                models.display(),
+                output.display()
+            ),
+        )
+        .expect("kcl-export returned Err");
+        let written = std::fs::read_to_string(&output).expect("Spec was not written");
+        assert!(written.contains("\"components\""));
+
+        let err = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-export '{}' --target markdown", models.display()),
+        )
+        .expect_err("Expected an unknown target to fail");
+        assert!(err.contains("Unknown export target"));
+    }
+
+    /// Test that `kcl-validate --respect-gitignore` skips the files of an ignored folder.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_validate_respect_gitignore() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "");
+        let project = dir.path().join("project");
+        std::fs::create_dir_all(project.join("build")).expect("Failed to create project dir");
+        std::fs::write(project.join(".gitignore"), "build/\n").expect("Failed to write .gitignore");
+        let main = kcl_file(&project, "main.k");
+        let generated = kcl_file(&project.join("build"), "generated.k");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+
+        let summary = |plugin_test: &mut PluginTest, flag: &str| {
+            eval_plugin(
+                plugin_test,
+                &bin,
+                &format!("kcl-validate '{}' --summary {}", project.display(), flag),
+            )
+            .expect("kcl-validate returned Err")
+        };
+        assert_eq!(
+            summary(&mut plugin_test, "--respect-gitignore"),
+            Value::test_string(format!("✅ 1 file is valid\n\n✅ {}", main.display()))
+        );
+        assert_eq!(
+            summary(&mut plugin_test, ""),
+            Value::test_string(format!(
+                "✅ All 2 files are valid\n\n✅ {}\n✅ {}",
+                generated.display(),
+                main.display()
+            ))
+        );
+    }
+
+    /// Test that hidden and vendored directories are skipped unless `--include-hidden` is given.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_validate_include_hidden() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "");
+        let project = dir.path().join("project");
+        for sub in [".hidden", ".kclvm", "target"] {
+            std::fs::create_dir_all(project.join(sub)).expect("Failed to create project dir");
+        }
+        let main = kcl_file(&project, "main.k");
+        let hidden = kcl_file(&project.join(".hidden"), "x.k");
+        let vendored = kcl_file(&project.join(".kclvm"), "dep.k");
+        kcl_file(&project.join("target"), "built.k");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+
+        let summary = |plugin_test: &mut PluginTest, flags: &str| {
+            eval_plugin(
+                plugin_test,
+                &bin,
+                &format!("kcl-validate '{}' --summary {}", project.display(), flags),
+            )
+            .expect("kcl-validate returned Err")
+        };
+        assert_eq!(
+            summary(&mut plugin_test, ""),
+            Value::test_string(format!("✅ 1 file is valid\n\n✅ {}", main.display()))
+        );
+        assert_eq!(
+            summary(&mut plugin_test, "--include '**/*.k'"),
+            Value::test_string(format!("✅ 1 file is valid\n\n✅ {}", main.display()))
+        );
+        assert_eq!(
+            summary(&mut plugin_test, "--include '.hidden/*.k'"),
+            Value::test_string(format!("✅ 1 file is valid\n\n✅ {}", hidden.display()))
+        );
+        let all = summary(&mut plugin_test, "--include-hidden");
+        let all = all.as_str().expect("Expected a string");
+        assert!(all.starts_with("✅ All 4 files are valid"), "{}", all);
+        assert!(all.contains(&hidden.display().to_string()), "{}", all);
+        assert!(all.contains(&vendored.display().to_string()), "{}", all);
+
+        let skip_dirs = ["target".to_string()];
+        assert!(is_skipped_dir(".git", &skip_dirs));
+        assert!(is_skipped_dir("target", &skip_dirs));
+        assert!(!is_skipped_dir("src", &skip_dirs));
+        assert!(!is_skipped_dir(".", &skip_dirs));
+    }
+
+    /// Test that kcl-validate checks exactly the files piped into it, unless a path is given.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_validate_piped_files() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "");
+        let a = kcl_file(dir.path(), "a.k");
+        let b = kcl_file(dir.path(), "b.k");
+        let c = kcl_file(dir.path(), "c.k");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+
+        let mut validate = |pipeline: &str| {
+            eval_plugin(&mut plugin_test, &bin, pipeline).expect("kcl-validate returned Err")
+        };
+        assert_eq!(
+            validate(&format!(
+                "['{}' '{}'] | kcl-validate --summary",
+                a.display(),
+                c.display()
+            )),
+            Value::test_string(format!(
+                "✅ All 2 files are valid\n\n✅ {}\n✅ {}",
+                a.display(),
+                c.display()
+            ))
+        );
+        assert_eq!(
+            validate(&format!(
+                "[{{name: '{}', type: file}}] | kcl-validate --summary",
+                b.display()
+            )),
+            Value::test_string(format!("✅ 1 file is valid\n\n✅ {}", b.display()))
+        );
+        assert_eq!(
+            validate("([] | kcl-validate --stats).total"),
+            Value::test_int(0)
+        );
+        assert_eq!(
+            validate(&format!(
+                "(['{}'] | kcl-validate '{}' --stats).total",
+                a.display(),
+                dir.path().display()
+            )),
+            Value::test_int(3)
+        );
+    }
+
+    /// Test that `plain_text` replaces the emoji decorations with words.
+    #[test]
+    fn test_plain_text() {
+        assert_eq!(
+            plain_text("✅ 1 file is valid, ⚠ 1 ignored\n❌: error"),
+            "OK 1 file is valid, WARN 1 ignored\nFAIL: error"
+        );
+    }
+
+    /// Test that `--plain` and `NO_EMOJI` leave no emoji in the output of the commands.
+    #[cfg(unix)]
+    #[test]
+    fn test_plain_output_has_no_emoji() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(
+            dir.path(),
+            "case \"$2\" in *bad.k) echo '❌ CompileError' >&2; exit 1;; esac",
+        );
+        let project = dir.path().join("project");
+        std::fs::create_dir(&project).expect("Failed to create project dir");
+        let good = kcl_file(&project, "good.k");
+        kcl_file(&project, "bad.k");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+        let has_emoji = |text: &str| text.contains(['✅', '❌', '⚠']);
+
+        for command in [
+            format!("kcl-validate '{}' --summary --plain", project.display()),
+            format!("kcl-format '{}' --plain", good.display()),
+            format!(
+                "$env.NO_EMOJI = '1'; kcl-validate '{}' --summary",
+                project.display()
+            ),
+        ] {
+            let out = eval_plugin(&mut plugin_test, &bin, &command).expect("Command returned Err");
+            let text = out.coerce_str().expect("Expected a string").into_owned();
+            assert!(!has_emoji(&text), "{}: {}", command, text);
+            assert!(text.contains("OK"), "{}: {}", command, text);
+        }
+
+        let err = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!(
+                "kcl-validate '{}' --fail-on-invalid --plain",
+                project.display()
+            ),
+        )
+        .expect_err("Expected the invalid file to fail");
+        assert!(!has_emoji(&err), "{}", err);
+        assert!(err.contains("FAIL"), "{}", err);
+    }
+
+    /// Test that kcl-run `--plain` only replaces its own decoration, never the output KCL rendered.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_run_plain_keeps_output() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "echo 'msg: ✅ done'");
+        let file = kcl_file(dir.path(), "main.k");
+        let other = kcl_file(dir.path(), "other.k");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+
+        let mut run = |flags: &str| {
+            eval_plugin(
+                &mut plugin_test,
+                &bin,
+                &format!("kcl-run '{}' --plain {}", file.display(), flags),
+            )
+            .expect("kcl-run returned Err")
+        };
+        assert_eq!(run(""), Value::test_string("OK msg: ✅ done\n"));
+        assert_eq!(
+            run("--split-docs"),
+            Value::test_list(vec![Value::test_record(record! {
+                "msg" => Value::test_string("✅ done"),
+            })])
+        );
+        assert_eq!(
+            run(&format!("'{}'", other.display())),
+            Value::test_record(record! {
+                file.display().to_string() => Value::test_string("OK msg: ✅ done\n"),
+                other.display().to_string() => Value::test_string("OK msg: ✅ done\n"),
+            })
+        );
+        let meta = run("--meta");
+        assert_eq!(
+            meta.as_record().expect("Expected a record").get("stdout"),
+            Some(&Value::test_string("msg: ✅ done\n"))
+        );
+    }
+
+    /// Test that output that is not valid UTF-8 fails, or comes back byte for byte with `--binary`.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_run_non_utf8_output() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(dir.path(), "printf 'a: \\377\\376'");
+        let file = kcl_file(dir.path(), "main.k");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+
+        let err = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-run '{}'", file.display()),
+        )
+        .expect_err("Expected non UTF-8 output to fail");
+        assert!(
+            err.contains("not valid UTF-8 (invalid byte at offset 3 of 5)"),
+            "{}",
+            err
+        );
+
+        let out = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-run '{}' --binary", file.display()),
+        )
+        .expect("kcl-run returned Err");
+        assert_eq!(out, Value::test_binary(b"a: \xff\xfe".to_vec()));
+    }
+
+    /// Test that `--split-docs` returns each YAML document of the output as a record.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_run_split_docs() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(
+            dir.path(),
+            "printf 'kind: Deployment\\nreplicas: 2\\n---\\nkind: Service\\nport: 80\\n'",
+        );
+        let file = kcl_file(dir.path(), "manifests.k");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+
+        let out = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-run '{}' --split-docs", file.display()),
+        )
+        .expect("kcl-run returned Err");
+        assert_eq!(
+            out,
+            Value::test_list(vec![
+                Value::test_record(record! {
+                    "kind" => Value::test_string("Deployment"),
+                    "replicas" => Value::test_int(2),
+                }),
+                Value::test_record(record! {
+                    "kind" => Value::test_string("Service"),
+                    "port" => Value::test_int(80),
+                }),
+            ])
+        );
+
+        let err = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!(
+                "kcl-run '{}' --split-docs -o '{}'",
+                file.display(),
+                dir.path().join("out.yaml").display()
+            ),
+        )
+        .expect_err("Expected --split-docs with --output to fail");
+        assert!(err.contains("--split-docs can only be used"), "{:?}", err);
+    }
+
+    /// Test that `.exe` is appended to the binary name on Windows only, unless it has an extension.
+    #[test]
+    fn test_exe_name() {
+        assert_eq!(exe_name("kcl", true), "kcl.exe");
+        assert_eq!(exe_name(r"C:\kcl\bin\kcl", true), r"C:\kcl\bin\kcl.exe");
+        assert_eq!(exe_name("kcl.exe", true), "kcl.exe");
+        assert_eq!(exe_name("kcl", false), "kcl");
+    }
+
+    /// Test that a bare binary name resolves to its file in PATH, and a path is kept as is.
+    #[cfg(unix)]
+    #[test]
+    fn test_resolve_kcl_binary() {
+        let sh = resolve_kcl_binary("sh");
+        assert!(sh.is_absolute() && sh.is_file(), "{}", sh.display());
+        assert_eq!(resolve_kcl_binary("sh"), sh);
+        assert_eq!(
+            resolve_kcl_binary("/opt/kcl/bin/kcl"),
+            std::path::PathBuf::from("/opt/kcl/bin/kcl")
+        );
+        assert_eq!(
+            resolve_kcl_binary("no-such-kcl-binary"),
+            std::path::PathBuf::from("no-such-kcl-binary")
+        );
+    }
+
+    /// Test that a bare `kcl` resolves to `kcl.exe` on Windows, found in PATH or not.
+    #[cfg(windows)]
+    #[test]
+    fn test_resolve_kcl_binary_windows() {
+        let resolved = resolve_kcl_binary("kcl");
+        assert_eq!(
+            resolved.file_name().and_then(|name| name.to_str()),
+            Some("kcl.exe")
+        );
+        assert_eq!(
+            resolve_kcl_binary(r"C:\kcl\bin\kcl"),
+            std::path::PathBuf::from(r"C:\kcl\bin\kcl.exe")
+        );
+    }
+
+    /// Test that files are discovered without external tools, and a missing directory has none.
+    #[test]
+    fn test_select_files_walks_directories() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        std::fs::create_dir_all(dir.path().join("nested").join("deep"))
+            .expect("Failed to create dirs");
+        for file in ["main.k", "nested/deep/lib.k", "nested/data.json"] {
+            std::fs::write(dir.path().join(file), "").expect("Failed to write file");
+        }
+        let root = dir.path().to_string_lossy().into_owned();
+        let files = select_files(&root, None, &[], &[], None).expect("select_files returned Err");
+        let expected: Vec<String> = [
+            dir.path().join("main.k"),
+            dir.path().join("nested").join("deep").join("lib.k"),
+        ]
+        .iter()
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect();
+        assert_eq!(files, expected);
+
+        let missing = dir.path().join("missing").to_string_lossy().into_owned();
+        assert!(
+            select_files(&missing, None, &[], &[], None)
+                .expect("select_files returned Err")
+                .is_empty()
+        );
+    }
+
+    /// Test that `-f ndjson` writes each item of the JSON list KCL renders on its own line.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_run_ndjson_format() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(
+            dir.path(),
+            "case \"$*\" in *scalar.k*) echo '3';; *) echo '[{\"event\": \"start\", \"n\": 1}, {\"event\": \"stop\", \"tags\": [\"a\"]}]';; esac",
+        );
+        let file = kcl_file(dir.path(), "events.k");
+        let scalar = kcl_file(dir.path(), "scalar.k");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+
+        let out = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-run '{}' -f ndjson --validate-output", file.display()),
+        )
+        .expect("kcl-run returned Err");
+        let out = out.as_str().expect("Expected a string");
+        let lines: Vec<&str> = out.trim_start_matches("✅ ").lines().collect();
+        assert_eq!(
+            lines,
+            [
+                r#"{"event":"start","n":1}"#,
+                r#"{"event":"stop","tags":["a"]}"#
+            ]
+        );
+        for line in lines {
+            serde_json::from_str::<serde_json::Value>(line).expect("Line is not valid JSON");
+        }
+
+        let err = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-run '{}' -f ndjson", scalar.display()),
+        )
+        .expect_err("Expected a scalar output to fail");
+        assert!(
+            err.contains("ndjson output needs a list at the top level, got a number"),
+            "{:?}",
+            err
+        );
+        assert_eq!(
+            RunOptions::new("main.k").format("ndjson").args()[2..4],
+            ["--format", "json"]
+        );
+    }
+
+    /// Test that `json_diff` reports nested, list, added and removed keys at their own paths.
+    #[test]
+    fn test_json_diff() {
+        let old = serde_json::json!({
+            "name": "app",
+            "spec": {"replicas": 1, "ports": [80, 443]},
+            "debug": true,
+        });
+        let new = serde_json::json!({
+            "name": "app",
+            "spec": {"replicas": 3, "ports": [80]},
+            "owner": "ops",
+        });
+        let changes: Vec<(
+            String,
+            &str,
+            Option<serde_json::Value>,
+            Option<serde_json::Value>,
+        )> = json_diff(&old, &new)
+            .into_iter()
+            .map(|c| (c.path, c.change, c.old, c.new))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                (
+                    "spec.replicas".to_string(),
+                    "changed",
+                    Some(serde_json::json!(1)),
+                    Some(serde_json::json!(3))
+                ),
+                (
+                    "spec.ports.1".to_string(),
+                    "removed",
+                    Some(serde_json::json!(443)),
+                    None
+                ),
+                (
+                    "debug".to_string(),
+                    "removed",
+                    Some(serde_json::json!(true)),
+                    None
+                ),
+                (
+                    "owner".to_string(),
+                    "added",
+                    None,
+                    Some(serde_json::json!("ops"))
+                ),
+            ]
+        );
+        assert!(json_diff(&old, &old).is_empty());
+    }
+
+    /// Test that `kcl-diff` pinpoints the one field that differs between two files, and
+    /// between two define sets of the same file.
+    #[cfg(unix)]
+    #[test]
+    fn test_kcl_diff() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let bin = fake_kcl(
+            dir.path(),
+            "case \"$*\" in *new.k*|*env=prod*) echo '{\"name\": \"app\", \"replicas\": 3}';; *) echo '{\"name\": \"app\", \"replicas\": 1}';; esac",
+        );
+        let old = kcl_file(dir.path(), "old.k");
+        let new = kcl_file(dir.path(), "new.k");
+        let mut plugin_test =
+            PluginTest::new("kcl", KclWrapperPlugin.into()).expect("Failed to create plugin test");
+        let expected = Value::test_list(vec![Value::test_record(record! {
+            "path" => Value::test_string("replicas"),
+            "change" => Value::test_string("changed"),
+            "old" => Value::test_int(1),
+            "new" => Value::test_int(3),
+        })]);
+
+        let out = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-diff '{}' '{}'", old.display(), new.display()),
+        )
+        .expect("kcl-diff returned Err");
+        assert_eq!(out, expected);
+
+        let out = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!(
+                "kcl-diff '{}' --old-define [env=dev] --new-define [env=prod]",
+                old.display()
+            ),
+        )
+        .expect("kcl-diff returned Err");
+        assert_eq!(out, expected);
+
+        let err = eval_plugin(
+            &mut plugin_test,
+            &bin,
+            &format!("kcl-diff '{}'", old.display()),
+        )
+        .expect_err("Expected a single file without defines to fail");
+        assert!(err.contains("Nothing to compare"));
+    }
 }